@@ -0,0 +1,147 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+
+use crate::utils::format_rfc3339;
+
+/// Lines waiting to be written, more are dropped.
+const LINE_BUFFER: usize = 4096;
+
+static LINES: OnceCell<SyncSender<String>> = OnceCell::new();
+
+/// When the access log is moved aside for a new one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Rotation {
+    pub(crate) max_size: Option<u64>,
+    pub(crate) max_age: Option<Duration>,
+    /// Rotated files kept as `<path>.1` to `<path>.<keep>`, newest first.
+    pub(crate) keep: usize,
+}
+
+/// A relay as written to the access log once it ended.
+pub(crate) struct Entry<'a> {
+    pub(crate) client: SocketAddr,
+    pub(crate) listener: &'a str,
+    pub(crate) target: &'a str,
+    pub(crate) duration: Duration,
+    pub(crate) upload: u64,
+    pub(crate) download: u64,
+    /// finished, error, idle or lifetime.
+    pub(crate) reason: &'a str,
+    /// client, target, forwarder or none, for failures before either did.
+    pub(crate) closed_by: &'a str,
+}
+
+/// Write the access log to `path` from a thread of its own, so slow disks
+/// never hold up relays.
+pub(crate) fn spawn(path: PathBuf, rotation: Rotation) -> anyhow::Result<()> {
+    let file = AccessLog::open(path, rotation)?;
+    let (tx, rx) = mpsc::sync_channel::<String>(LINE_BUFFER);
+    if LINES.set(tx).is_err() {
+        return Ok(());
+    }
+    std::thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || {
+            let mut file = file;
+            for line in rx {
+                if let Err(e) = file.write_line(&line) {
+                    tracing::error!("Writing access log in failure: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Log a relay, nothing is written without `--access-log`.
+pub(crate) fn record(entry: &Entry<'_>) {
+    let lines = match LINES.get() {
+        Some(lines) => lines,
+        None => return,
+    };
+    let line = format!(
+        "{} client={} listener={} target={} duration={:.3} upload={} download={} reason={} closed_by={}\n",
+        format_rfc3339(SystemTime::now()),
+        entry.client,
+        entry.listener,
+        entry.target,
+        entry.duration.as_secs_f64(),
+        entry.upload,
+        entry.download,
+        entry.reason,
+        entry.closed_by
+    );
+    let _ = lines.try_send(line);
+}
+
+struct AccessLog {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+impl AccessLog {
+    fn open(path: PathBuf, rotation: Rotation) -> anyhow::Result<Self> {
+        let file = open_append(&path)
+            .with_context(|| format!("unable to open access log {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let oversized = self.rotation.max_size.map_or(false, |max| {
+            self.size > 0 && self.size + line.len() as u64 > max
+        });
+        let expired = self
+            .rotation
+            .max_age
+            .map_or(false, |max| self.opened.elapsed() >= max);
+        if oversized || expired {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let keep = self.rotation.keep;
+        if keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(&self.path, keep));
+            for n in (1..keep).rev() {
+                let _ = fs::rename(rotated(&self.path, n), rotated(&self.path, n + 1));
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
@@ -0,0 +1,179 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use maxminddb::{geoip2, Reader};
+
+use crate::utils::unmap_addr;
+
+/// A network like `10.0.0.0/8` or `fd00::/8`, a bare address is a network of
+/// its own.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn parse(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid network {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .with_context(|| format!("invalid prefix length in network {}", s))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    /// Whether `ip` is in the network, ipv4 clients of dual stack sockets
+    /// being in the ipv4 ones.
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmap_addr(SocketAddr::new(ip, 0)).ip()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u128::from(u32::from(net)) << 96,
+                u128::from(u32::from(ip)) << 96,
+                self.prefix,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix: u8) -> bool {
+    if prefix == 0 {
+        return true;
+    }
+    let mask = u128::MAX << (128 - u32::from(prefix));
+    net & mask == ip & mask
+}
+
+/// Country lookups in a MaxMind GeoLite2 or GeoIP2 database.
+pub(crate) struct GeoIp(Reader<Vec<u8>>);
+
+impl GeoIp {
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let reader = Reader::open_readfile(path)
+            .with_context(|| format!("unable to open geoip database {}", path.display()))?;
+        Ok(Self(reader))
+    }
+
+    /// ISO code of the country `ip` is located in, if the database knows.
+    pub(crate) fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.0.lookup(ip).ok()?;
+        record
+            .country
+            .and_then(|country| country.iso_code)
+            .map(str::to_owned)
+    }
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("database", &self.0.metadata.database_type)
+            .finish()
+    }
+}
+
+/// Which clients may connect, denied networks and countries take precedence
+/// over allowed ones and nothing allowed lets everyone not denied in.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Acl {
+    pub(crate) allow: Vec<Cidr>,
+    pub(crate) deny: Vec<Cidr>,
+    pub(crate) geoip: Option<Arc<GeoIp>>,
+    /// Upper case ISO country codes.
+    pub(crate) allow_countries: Vec<String>,
+    pub(crate) deny_countries: Vec<String>,
+}
+
+impl Acl {
+    /// Country of the client, for checking and logging it.
+    pub(crate) fn country(&self, ip: IpAddr) -> Option<String> {
+        self.geoip.as_ref()?.country(ip)
+    }
+
+    pub(crate) fn allows(&self, ip: IpAddr, country: Option<&str>) -> bool {
+        let denied_country = country.map_or(false, |country| {
+            self.deny_countries.iter().any(|denied| denied == country)
+        });
+        if denied_country || self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        if self.allow.is_empty() && self.allow_countries.is_empty() {
+            return true;
+        }
+        self.allow.iter().any(|cidr| cidr.contains(ip))
+            || country.map_or(false, |country| {
+                self.allow_countries
+                    .iter()
+                    .any(|allowed| allowed == country)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, ip: &str) -> bool {
+        Cidr::parse(network).unwrap().contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn contains_by_prefix() {
+        assert!(contains("10.0.0.0/8", "10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("192.168.1.0/31", "192.168.1.1"));
+        assert!(!contains("192.168.1.0/31", "192.168.1.2"));
+        assert!(contains("fd00::/8", "fd12::1"));
+        assert!(!contains("fd00::/8", "fe80::1"));
+        assert!(contains("0.0.0.0/0", "203.0.113.7"));
+        assert!(contains("::/0", "2001:db8::1"));
+    }
+
+    #[test]
+    fn bare_address_is_a_network_of_its_own() {
+        assert!(contains("203.0.113.7", "203.0.113.7"));
+        assert!(!contains("203.0.113.7", "203.0.113.8"));
+        assert!(contains("2001:db8::1", "2001:db8::1"));
+        assert!(!contains("2001:db8::1", "2001:db8::2"));
+    }
+
+    #[test]
+    fn families_do_not_mix() {
+        assert!(!contains("::/0", "10.0.0.1"));
+        assert!(!contains("0.0.0.0/0", "2001:db8::1"));
+    }
+
+    #[test]
+    fn ipv4_mapped_is_in_ipv4_networks() {
+        assert!(contains("10.0.0.0/8", "::ffff:10.1.2.3"));
+        assert!(!contains("10.0.0.0/8", "::ffff:11.0.0.1"));
+        assert!(contains("203.0.113.7", "::ffff:203.0.113.7"));
+    }
+
+    #[test]
+    fn invalid_networks() {
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("fd00::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("example.com").is_err());
+    }
+}
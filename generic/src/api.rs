@@ -0,0 +1,96 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+
+use crate::http::read_request_header;
+
+/// Time a client is given to send its request and read the response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Requests served at once, more wait to be accepted.
+const MAX_REQUESTS: usize = 32;
+
+/// Answer to a request of a tiny HTTP endpoint.
+pub(crate) struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    pub(crate) fn ok(content_type: &'static str, body: String) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    pub(crate) fn status(status: &'static str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: String::new(),
+        }
+    }
+
+    pub(crate) fn not_found() -> Self {
+        Self::status("404 Not Found")
+    }
+}
+
+type Handler = Arc<dyn Fn(&str, &str) -> Response + Send + Sync>;
+
+/// Serve HTTP/1.1 at `addr`, answering every request by its method and path
+/// with `handler`. Request bodies are ignored and connections are closed
+/// after one response, or when the exchange takes too long.
+pub(crate) async fn serve<F>(addr: SocketAddr, name: &str, handler: F) -> anyhow::Result<()>
+where
+    F: Fn(&str, &str) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Serving {} at http://{}", name, addr);
+    let handler: Handler = Arc::new(handler);
+    let requests = Arc::new(Semaphore::new(MAX_REQUESTS));
+    loop {
+        let permit = requests.clone().acquire_owned().await?;
+        let (stream, _) = listener.accept().await?;
+        let handler = handler.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            let res = tokio::time::timeout(REQUEST_TIMEOUT, serve_request(stream, handler))
+                .await
+                .context("request not served in time")
+                .and_then(|res| res);
+            if let Err(e) = res {
+                tracing::warn!("Serving {} in failure: {}", name, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+async fn serve_request(mut stream: TcpStream, handler: Handler) -> anyhow::Result<()> {
+    let (header, end) = read_request_header(&mut stream).await?;
+    let header = String::from_utf8_lossy(&header[..end]);
+    let request_line = header.split("\r\n").next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => handler(method, path),
+        _ => Response::status("400 Bad Request"),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config::{ProxyConfig, Rule};
+use crate::connections;
+use crate::proxy::{
+    self, encode_address, resolve_target, socks5_auth, with_handshake_timeout, SOCKS5_ATYP_DOMAIN,
+    SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6, SOCKS5_VERSION,
+};
+use crate::proxy_protocol::ProxiedAddrs;
+use crate::relay::{quota_usage, transfer};
+use crate::resolver;
+use crate::socks_server::{
+    reply, reply_bound, SOCKS5_CMD_BIND, SOCKS5_REPLY_FAILURE, SOCKS5_REPLY_SUCCEEDED,
+};
+use crate::utils::unmap_addr;
+
+/// Time the peer is given to connect back to the bound address.
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Options of BIND requests of socks5 clients.
+#[derive(Debug, Clone)]
+pub(crate) struct SocksBind {
+    /// Reported to clients instead of the ip really bound, like the public
+    /// one of a proxy behind nat.
+    pub(crate) advertise: Option<IpAddr>,
+}
+
+/// Serve a BIND request of a socks5 client: a port is bound on the proxy, or
+/// here without one, the client learns its address with the first reply and
+/// the address of `target` connecting back with the second, then both are
+/// relayed.
+pub(crate) async fn serve<S>(
+    mut inbound: S,
+    addrs: ProxiedAddrs,
+    rule: &Arc<Rule>,
+    bind: &SocksBind,
+    target: &str,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    quota_usage(addrs, rule)?;
+    connections::set_target(target);
+    // the proxy counts as busy with the relay until it is done
+    let mut lease = None;
+    let res = match rule.proxy_for(addrs.source.ip()) {
+        Some(pool) => {
+            let picked = pool
+                .pick()
+                .context("no proxy available, paused by circuit breaker or none discovered")?;
+            let res = with_handshake_timeout(&picked, bind_proxy(&picked, target)).await;
+            picked.connected(res.is_ok());
+            lease = Some(picked);
+            res.map(|(control, bound)| Bound::Proxy(control, bound))
+        }
+        None => bind_direct(rule, addrs)
+            .await
+            .map(|(listener, bound)| Bound::Direct(listener, bound)),
+    };
+    let bound = match res {
+        Ok(bound) => bound,
+        Err(e) => {
+            let _ = reply(&mut inbound, SOCKS5_REPLY_FAILURE).await;
+            return Err(e);
+        }
+    };
+    let advertised = match bind.advertise {
+        Some(ip) => SocketAddr::new(ip, bound.addr().port()),
+        None => bound.addr(),
+    };
+    tracing::info!(
+        "Bound {} for {}, advertised as {}",
+        bound.addr(),
+        target,
+        advertised
+    );
+    reply_bound(&mut inbound, SOCKS5_REPLY_SUCCEEDED, advertised).await?;
+
+    let res = tokio::time::timeout(BIND_ACCEPT_TIMEOUT, bound.accept(target))
+        .await
+        .map_err(|_| anyhow::anyhow!("no connection from {} to the bound address", target))
+        .and_then(|res| res);
+    let (outbound, peer) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = reply(&mut inbound, SOCKS5_REPLY_FAILURE).await;
+            return Err(e);
+        }
+    };
+    tracing::info!("Relay {} to {} connected back", addrs.source, peer);
+    reply_bound(&mut inbound, SOCKS5_REPLY_SUCCEEDED, peer).await?;
+    let res = transfer(inbound, Box::new(outbound), addrs, rule, target).await;
+    drop(lease);
+    res
+}
+
+/// The port bound for the client, with its address.
+enum Bound {
+    /// The control connection the proxy answers on once connected back.
+    Proxy(TcpStream, SocketAddr),
+    Direct(TcpListener, SocketAddr),
+}
+
+impl Bound {
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Bound::Proxy(_, addr) | Bound::Direct(_, addr) => *addr,
+        }
+    }
+
+    /// Wait for `target` to connect, returns the connection and the address
+    /// it came from.
+    async fn accept(self, target: &str) -> anyhow::Result<(TcpStream, SocketAddr)> {
+        match self {
+            Bound::Proxy(mut control, _) => {
+                let peer = read_reply(&mut control).await?;
+                Ok((control, peer))
+            }
+            Bound::Direct(listener, _) => {
+                // only the expected peer may connect, unless it is unspecified
+                let expected: Vec<IpAddr> = resolver::lookup(target)
+                    .await?
+                    .into_iter()
+                    .map(|addr| unmap_addr(addr).ip())
+                    .filter(|ip| !ip.is_unspecified())
+                    .collect();
+                loop {
+                    let (stream, peer) = listener.accept().await?;
+                    let peer = unmap_addr(peer);
+                    if expected.is_empty() || expected.contains(&peer.ip()) {
+                        return Ok((stream, peer));
+                    }
+                    tracing::warn!("Connection from {} to the bound address refused", peer);
+                }
+            }
+        }
+    }
+}
+
+/// Run the BIND command on the proxy, returns the control connection and
+/// the address bound there.
+async fn bind_proxy(proxy: &ProxyConfig, target: &str) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    // a plain socks5 proxy, whose connection tells the bound address
+    let mut control = proxy::connect(proxy).await?;
+    socks5_auth(&mut control, proxy).await?;
+
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_BIND, 0x00];
+    request.extend_from_slice(&encode_address(&resolve_target(proxy, target).await?)?);
+    control.write_all(&request).await?;
+    let bound = read_reply(&mut control).await?;
+
+    // some proxies reply 0.0.0.0 meaning the address we are talking to
+    let bound = if bound.ip().is_unspecified() {
+        SocketAddr::new(control.peer_addr()?.ip(), bound.port())
+    } else {
+        bound
+    };
+    Ok((control, bound))
+}
+
+/// Bind on the outbound address, or on every address of the family the
+/// client reached us on with the peer connecting from wherever it is.
+async fn bind_direct(
+    rule: &Rule,
+    addrs: ProxiedAddrs,
+) -> anyhow::Result<(TcpListener, SocketAddr)> {
+    if let Some(ip) = rule.outbound.bind {
+        let listener = TcpListener::bind((ip, 0)).await?;
+        let addr = listener.local_addr()?;
+        return Ok((listener, addr));
+    }
+    let local = unmap_addr(addrs.destination).ip();
+    let unspecified: IpAddr = match local {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let listener = TcpListener::bind((unspecified, 0)).await?;
+    let port = listener.local_addr()?.port();
+    Ok((listener, SocketAddr::new(local, port)))
+}
+
+/// A reply of the proxy to BIND, returns the address it carries.
+async fn read_reply(control: &mut TcpStream) -> anyhow::Result<SocketAddr> {
+    let mut head = [0u8; 4];
+    control.read_exact(&mut head).await?;
+    if head[0] != SOCKS5_VERSION {
+        anyhow::bail!("unsupported socks version {} in bind reply", head[0]);
+    }
+    if head[1] != 0x00 {
+        anyhow::bail!("bind rejected by proxy, reply code {}", head[1]);
+    }
+    let ip: IpAddr = match head[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).into()
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).into()
+        }
+        SOCKS5_ATYP_DOMAIN => anyhow::bail!("proxy replied a domain as bound address"),
+        atyp => anyhow::bail!("unsupported bound address type {}", atyp),
+    };
+    let port = control.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
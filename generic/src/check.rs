@@ -0,0 +1,281 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::Context;
+use clap::Args;
+use tokio::net::lookup_host;
+
+use crate::config::{self, ProxyConfig, ProxyProtocol, Rule, RuleOpts, Target};
+use crate::listener::{self, SYSTEMD_PREFIX, VSOCK_PREFIX};
+use crate::proxy;
+use crate::utils::UNIX_PREFIX;
+
+/// Options of the `check` subcommand.
+#[derive(Args)]
+pub(crate) struct CheckOpts {
+    #[clap(short, long, help = "config file(toml) with the rules to check")]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "target to connect to for rules without a fixed one, like example.com:443"
+    )]
+    check_target: Option<String>,
+    #[clap(flatten)]
+    rule: RuleOpts,
+}
+
+/// Options of the `validate` subcommand.
+#[derive(Args)]
+pub(crate) struct ValidateOpts {
+    #[clap(short, long, help = "config file(toml) with the rules to validate")]
+    config: Option<PathBuf>,
+    #[clap(flatten)]
+    rule: RuleOpts,
+}
+
+/// Check every proxy and target of the rules, printing a report. Returns
+/// whether everything passed.
+pub(crate) async fn run(opts: CheckOpts) -> anyhow::Result<bool> {
+    let rules = config::load(opts.rule, opts.config).await?;
+    let mut report = Report::default();
+    let mut checked: Vec<(Option<String>, String)> = Vec::new();
+    for rule in rules.iter() {
+        let target = rule
+            .target
+            .fixed()
+            .map(str::to_string)
+            .or_else(|| opts.check_target.clone());
+        let proxies: Vec<ProxyConfig> = match rule.proxy.as_ref() {
+            Some(pool) => pool.proxies(),
+            None => Vec::new(),
+        };
+        for proxy in proxies.iter() {
+            // listeners of a rule share its proxies and often its target
+            let key = (target.clone(), proxy.address.clone());
+            if checked.contains(&key) {
+                continue;
+            }
+            checked.push(key);
+            check_proxy(&mut report, proxy, target.as_deref()).await;
+        }
+        if proxies.is_empty() {
+            let key = (target.clone(), String::new());
+            if checked.contains(&key) {
+                continue;
+            }
+            checked.push(key);
+            check_direct(&mut report, rule, target.as_deref()).await;
+        }
+    }
+    report.print();
+    Ok(report.failed == 0)
+}
+
+async fn check_proxy(report: &mut Report, proxy: &ProxyConfig, target: Option<&str>) {
+    let address = &proxy.address;
+    let mut stream = match report
+        .step(format!("connect to proxy {}", address), proxy::open(proxy))
+        .await
+    {
+        Some(stream) => stream,
+        None => return,
+    };
+    if proxy.protocol == ProxyProtocol::Socks5 {
+        let auth = proxy::socks5_auth(&mut stream, proxy);
+        if report
+            .step(format!("authenticate with proxy {}", address), auth)
+            .await
+            .is_none()
+        {
+            return;
+        }
+    }
+    let target = match target {
+        Some(target) => target,
+        None => {
+            report.skip(format!(
+                "connect through proxy {}, no fixed target or --check-target",
+                address
+            ));
+            return;
+        }
+    };
+    // the handshake runs on a fresh connection as the probing one is used up
+    let connect = async {
+        let stream = proxy::open(proxy).await?;
+        proxy::connect_through(stream, proxy, target).await
+    };
+    report
+        .step(
+            format!("connect to {} through proxy {}", target, address),
+            connect,
+        )
+        .await;
+}
+
+async fn check_direct(report: &mut Report, rule: &Rule, target: Option<&str>) {
+    match target {
+        Some(target) => {
+            report
+                .step(
+                    format!("connect to {} directly", target),
+                    rule.outbound.connect(target),
+                )
+                .await;
+        }
+        None => report.skip(format!(
+            "connect for {}, no fixed target or --check-target",
+            rule.listen
+        )),
+    }
+}
+
+/// Load the rules and resolve every address in them without binding or
+/// connecting anything, printing a report. Returns whether everything passed.
+pub(crate) async fn validate(opts: ValidateOpts) -> bool {
+    let mut report = Report::default();
+    // certificates and keys are loaded along with the rules
+    let load = config::load(opts.rule, opts.config);
+    let rules = match report.step("load rules".to_string(), load).await {
+        Some(rules) => rules,
+        None => {
+            report.print();
+            return false;
+        }
+    };
+
+    let mut listens: Vec<&str> = Vec::new();
+    for rule in rules.iter() {
+        println!("rule {} -> {}", rule.listen, describe(rule));
+        let name = format!("listen address {} is unique", rule.listen);
+        if listens.contains(&rule.listen.as_str()) {
+            report.fail(name, "listened by more than one rule");
+        } else {
+            report.pass(name);
+        }
+        listens.push(&rule.listen);
+
+        if let Some(path) = rule.listen.strip_prefix(UNIX_PREFIX) {
+            let name = format!("directory of unix socket {} exists", path);
+            match Path::new(path).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => {
+                    report.fail(name, "no such directory")
+                }
+                _ => report.pass(name),
+            }
+        } else if !rule.listen.starts_with(SYSTEMD_PREFIX) && !rule.listen.starts_with(VSOCK_PREFIX)
+        {
+            report
+                .step(
+                    format!("resolve listen address {}", rule.listen),
+                    listener::resolve_listen(rule),
+                )
+                .await;
+        }
+
+        if let Some(target) = rule.target.fixed() {
+            if !target.starts_with(UNIX_PREFIX) {
+                report
+                    .step(format!("resolve target {}", target), resolve(target))
+                    .await;
+            }
+        }
+        if let Some(pool) = rule.proxy.as_ref() {
+            for proxy in pool.proxies().iter() {
+                for hop in std::iter::once(proxy).chain(proxy.chain.iter()) {
+                    report
+                        .step(
+                            format!("resolve proxy {}", hop.address),
+                            resolve(&hop.address),
+                        )
+                        .await;
+                }
+            }
+        }
+    }
+    report.print();
+    report.failed == 0
+}
+
+fn describe(rule: &Rule) -> String {
+    let target = match &rule.target {
+        Target::Fixed(target) => target.clone(),
+        Target::PreservePort(host) => format!("{} with the listener port", host),
+        Target::Original => "original destination".to_string(),
+        Target::Local => "local address".to_string(),
+        Target::Sni(_) => "sni host".to_string(),
+        Target::Host(_) => "http host".to_string(),
+        Target::Socks5 => "socks5 request".to_string(),
+        Target::HttpConnect => "http connect request".to_string(),
+    };
+    match rule.proxy.as_ref() {
+        Some(pool) => {
+            let proxies: Vec<String> = pool.proxies().into_iter().map(|p| p.address).collect();
+            format!("{} through {}", target, proxies.join(", "))
+        }
+        None => format!("{} directly", target),
+    }
+}
+
+async fn resolve(addr: &str) -> anyhow::Result<()> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .with_context(|| format!("unable to resolve {}", addr))?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct Report {
+    passed: usize,
+    failed: usize,
+    skipped: usize,
+}
+
+impl Report {
+    /// Run a step, printing whether it passed and how long it took.
+    async fn step<F, T, E>(&mut self, name: String, step: F) -> Option<T>
+    where
+        F: Future<Output = Result<T, E>>,
+        E: std::fmt::Display,
+    {
+        let start = Instant::now();
+        let res = step.await;
+        let elapsed = start.elapsed().as_millis();
+        match res {
+            Ok(value) => {
+                self.passed += 1;
+                println!("PASS {} ({}ms)", name, elapsed);
+                Some(value)
+            }
+            Err(e) => {
+                self.failed += 1;
+                println!("FAIL {} ({}ms): {:#}", name, elapsed, e);
+                None
+            }
+        }
+    }
+
+    fn pass(&mut self, name: String) {
+        self.passed += 1;
+        println!("PASS {}", name);
+    }
+
+    fn fail(&mut self, name: String, reason: &str) {
+        self.failed += 1;
+        println!("FAIL {}: {}", name, reason);
+    }
+
+    fn skip(&mut self, name: String) {
+        self.skipped += 1;
+        println!("SKIP {}", name);
+    }
+
+    fn print(&self) {
+        println!(
+            "{} passed, {} failed, {} skipped",
+            self.passed, self.failed, self.skipped
+        );
+    }
+}
@@ -0,0 +1,586 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+
+use crate::config::{self, Rule, RuleOpts};
+use crate::forwarder::{Config, Forwarder};
+use crate::limit::{AcceptRates, Limits, QuotaPeriod};
+use crate::logging::{self, Facility, LogFormat};
+use crate::privileges::Privileges;
+#[cfg(windows)]
+use crate::service;
+use crate::{
+    access_log, check, connections, health, metrics, pool, relay, statsd, telemetry, utils,
+};
+#[cfg(unix)]
+use crate::{daemon, handoff, sandbox};
+
+#[derive(Parser)]
+#[clap(version, author, about)]
+pub(crate) struct Opts {
+    #[clap(
+        short,
+        long,
+        help = "config file(toml) with forwarding rules, overrides the rule given by command line"
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "relay stdin/stdout to the target for one session and exit, for ssh ProxyCommand or inetd"
+    )]
+    stdio: bool,
+    #[clap(
+        long,
+        help = "connections relayed at once by all listeners, more wait in the listen backlog"
+    )]
+    max_connections: Option<usize>,
+    #[clap(
+        long,
+        help = "connections relayed at once for one client ip, more from it are rejected"
+    )]
+    max_connections_per_ip: Option<usize>,
+    #[clap(
+        long,
+        help = "new connections accepted per second by all listeners, bursting up to one second worth, more wait in the listen backlog"
+    )]
+    accept_rate: Option<u32>,
+    #[clap(
+        long,
+        help = "new connections per second from one client ip, held back when faster and rejected when far beyond"
+    )]
+    accept_rate_per_ip: Option<u32>,
+    #[clap(
+        long,
+        help = "bytes per second of all relays together in each direction, shared by the relays"
+    )]
+    bandwidth: Option<u64>,
+    #[clap(
+        long,
+        help = "bytes one client ip may relay in a quota period, its new connections are rejected beyond"
+    )]
+    quota: Option<u64>,
+    #[clap(
+        long,
+        default_value = "day",
+        help = "period of quotas, hour or day, counted from the first relay of the client in it"
+    )]
+    quota_period: QuotaPeriod,
+    #[clap(
+        long,
+        help = "serve prometheus metrics at http://<address>/metrics, like 127.0.0.1:9100"
+    )]
+    metrics_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        help = "serve the admin api listing and killing connections at this address, keep it private"
+    )]
+    admin_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        help = "serve /healthz and /readyz at this address, ready while listening with a usable proxy"
+    )]
+    health_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        help = "push statsd metrics with dogstatsd tags to this udp address"
+    )]
+    statsd_addr: Option<SocketAddr>,
+    #[clap(
+        long,
+        default_value = "10",
+        help = "seconds between pushes of statsd metrics"
+    )]
+    statsd_interval: u64,
+    #[clap(
+        long,
+        help = "export spans of relays over otlp/grpc to this endpoint, like http://127.0.0.1:4317"
+    )]
+    otlp_endpoint: Option<String>,
+    #[clap(
+        long,
+        default_value = "text",
+        help = "format of logs, text or json with the fields of connections for log collectors"
+    )]
+    log_format: LogFormat,
+    #[clap(long, help = "log to the local syslog daemon instead(unix only)")]
+    syslog: bool,
+    #[clap(
+        long,
+        default_value = "daemon",
+        help = "syslog facility like daemon, user or local0-7"
+    )]
+    syslog_facility: Facility,
+    #[clap(
+        long,
+        help = "append a line for every finished relay to this file, apart from the log"
+    )]
+    access_log: Option<PathBuf>,
+    #[clap(long, help = "bytes of the access log before it is rotated")]
+    access_log_max_size: Option<u64>,
+    #[clap(long, help = "seconds before the access log is rotated")]
+    access_log_max_age: Option<u64>,
+    #[clap(
+        long,
+        default_value = "5",
+        help = "rotated access logs kept as <file>.1 to <file>.<n>, newest first"
+    )]
+    access_log_keep: usize,
+    #[clap(
+        long,
+        help = "wait up to this many seconds at startup for a proxy of every rule to be reachable and accept the credentials, exit if none does"
+    )]
+    wait_for_proxy: Option<u64>,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "seconds relays in flight are given to finish on SIGTERM or SIGINT before being closed"
+    )]
+    shutdown_grace: u64,
+    #[clap(
+        long,
+        help = "take over the listening sockets of the instance running with the same unix socket path, which then drains and exits, for upgrades without refusing connections(unix only)"
+    )]
+    upgrade_socket: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "detach from the terminal and run in the background, logs only go to syslog or the otlp endpoint then(unix only)"
+    )]
+    daemon: bool,
+    #[clap(
+        long,
+        help = "write the pid to this file, refusing to start if the pid in it is still running"
+    )]
+    pid_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "switch to this user, by name or uid, once the listening sockets are bound(unix only)"
+    )]
+    user: Option<String>,
+    #[clap(
+        long,
+        help = "switch to this group, by name or gid, once the listening sockets are bound, the primary group of --user by default(unix only)"
+    )]
+    group: Option<String>,
+    #[clap(
+        long,
+        help = "restrict filesystem access with landlock to the config file and its directory, certificates given on the command line, the access log and pid file directories and system files for the resolver(unix only)"
+    )]
+    sandbox: bool,
+    #[clap(
+        long,
+        help = "also allow reading this path in the sandbox, repeat for more"
+    )]
+    sandbox_read: Vec<PathBuf>,
+    #[clap(
+        long,
+        help = "also allow writing this path in the sandbox, repeat for more"
+    )]
+    sandbox_write: Vec<PathBuf>,
+    #[clap(
+        long,
+        help = "chroot to this directory where landlock is not available, implies --sandbox"
+    )]
+    chroot: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "only allow the syscalls needed for relaying once listening, others fail with EPERM(linux x86_64 and aarch64 only)"
+    )]
+    seccomp: bool,
+    #[cfg(feature = "hickory-dns")]
+    #[clap(
+        long,
+        help = "resolve proxies and direct targets with hickory-dns instead of the system resolver"
+    )]
+    hickory_dns: bool,
+    #[clap(
+        long,
+        help = "read the proxy password from the terminal without echo, or from stdin when it is not one, instead of --proxy-pass"
+    )]
+    ask_pass: bool,
+    #[clap(flatten)]
+    rule: RuleOpts,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Connect to every proxy, authenticate and connect through it to the
+    /// target, then print a pass/fail report.
+    Check(check::CheckOpts),
+    /// Load the config and resolve every address in it without binding or
+    /// connecting anything, then print a pass/fail report.
+    Validate(check::ValidateOpts),
+    /// Install, uninstall, start or stop the Windows service.
+    #[cfg(windows)]
+    #[clap(subcommand)]
+    Service(service::ServiceCommand),
+}
+
+/// Entry of the socks5-forwarder binary.
+pub fn main() {
+    let mut opt = Opts::parse();
+    // the service control manager runs the service on its own thread
+    #[cfg(windows)]
+    if let Some(Command::Service(command)) = opt.command.as_ref() {
+        service::manage(command).expect("service management failed");
+        return;
+    }
+    // while stdin is still the terminal, the daemon detaches from it
+    if opt.ask_pass {
+        if opt.rule.proxy_pass.is_some() || opt.rule.proxy_pass_file.is_some() || opt.stdio {
+            panic!("invalid configuration: ask pass conflicts with proxy pass and stdio");
+        }
+        // the password would be dropped, asked for nothing
+        if opt.config.is_some() || opt.rule.credentials_file.is_some() {
+            panic!("invalid configuration: ask pass only applies to the command line proxy user");
+        }
+        if opt.rule.proxy_user.is_none() && opt.rule.proxy_user_file.is_none() {
+            panic!("invalid configuration: ask pass needs a proxy user");
+        }
+        opt.rule.proxy_pass = Some(ask_pass().expect("unable to read the proxy password"));
+    }
+    // forking is only safe before the runtime starts its threads
+    if opt.daemon {
+        #[cfg(unix)]
+        daemon::daemonize().expect("unable to daemonize");
+        #[cfg(not(unix))]
+        panic!("invalid configuration: daemon mode is only supported on unix");
+    }
+    #[cfg(unix)]
+    let _pid_file = opt.pid_file.as_deref().map(|path| {
+        daemon::PidFile::create(path, opt.upgrade_socket.is_some()).expect("invalid configuration")
+    });
+    #[cfg(not(unix))]
+    if opt.pid_file.is_some() {
+        panic!("invalid configuration: pid file is only supported on unix");
+    }
+    // threads only inherit the sandbox when started after
+    let sandboxed = if opt.sandbox || opt.chroot.is_some() {
+        Some(restrict_fs(&opt))
+    } else {
+        None
+    };
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("unable to start the runtime")
+        .block_on(run(opt, sandboxed))
+        .expect("forwarder failed");
+}
+
+/// Prompt for the proxy password on stderr, the line read is taken as is
+/// without its newline.
+fn ask_pass() -> anyhow::Result<String> {
+    use std::io::{BufRead, Write};
+
+    #[cfg(unix)]
+    let terminal = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    #[cfg(not(unix))]
+    let terminal = false;
+    #[cfg(unix)]
+    let _echo = if terminal {
+        Some(EchoOff::new()?)
+    } else {
+        None
+    };
+    if terminal {
+        eprint!("Proxy password: ");
+        std::io::stderr().flush()?;
+    }
+    let mut line = String::new();
+    let read = std::io::stdin().lock().read_line(&mut line)?;
+    if terminal {
+        // the newline typed was not echoed
+        eprintln!();
+    }
+    if read == 0 {
+        anyhow::bail!("no password before end of input");
+    }
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Terminal echo turned off until dropped.
+#[cfg(unix)]
+struct EchoOff(libc::termios);
+
+#[cfg(unix)]
+impl EchoOff {
+    fn new() -> std::io::Result<Self> {
+        let mut termios = std::mem::MaybeUninit::<libc::termios>::uninit();
+        // filled by tcgetattr before being read
+        let saved = unsafe {
+            if libc::tcgetattr(libc::STDIN_FILENO, termios.as_mut_ptr()) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            termios.assume_init()
+        };
+        let mut quiet = saved;
+        // still line by line, only the echo goes
+        quiet.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self(saved))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0) };
+    }
+}
+
+/// How the filesystem was restricted before the runtime started.
+pub(crate) enum Sandboxed {
+    Landlock,
+    /// Landlock failed for the reason given, the chroot is left until the
+    /// listeners are bound.
+    Chroot(anyhow::Error),
+}
+
+#[cfg(unix)]
+fn restrict_fs(opt: &Opts) -> Sandboxed {
+    let sandbox = sandbox_of(opt);
+    match sandbox.restrict() {
+        Ok(()) => Sandboxed::Landlock,
+        // logged once logging is set up
+        Err(e) if sandbox.chroot.is_some() => Sandboxed::Chroot(e),
+        Err(e) => panic!("unable to sandbox: {:#}, give --chroot for a fallback", e),
+    }
+}
+
+#[cfg(not(unix))]
+fn restrict_fs(_opt: &Opts) -> Sandboxed {
+    panic!("invalid configuration: sandbox is only supported on unix")
+}
+
+/// Paths the forwarder is known to need, more are given by the user.
+#[cfg(unix)]
+fn sandbox_of(opt: &Opts) -> sandbox::Sandbox {
+    fn parent(path: &std::path::Path) -> PathBuf {
+        match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+
+    let mut read: Vec<PathBuf> = sandbox::SYSTEM_PATHS.iter().map(PathBuf::from).collect();
+    let mut rules = vec![opt.rule.clone()];
+    if let Some(config) = opt.config.as_deref() {
+        read.push(parent(config));
+        // the files named in the config are read when it is loaded later
+        rules = config::load_opts(config).expect("invalid configuration");
+    }
+    for rule in rules.iter() {
+        read.extend(rule.files().into_iter().map(PathBuf::from));
+    }
+    read.extend(opt.sandbox_read.iter().cloned());
+
+    let mut write: Vec<PathBuf> = [&opt.access_log, &opt.pid_file, &opt.upgrade_socket]
+        .iter()
+        .filter_map(|path| path.as_deref().map(parent))
+        .collect();
+    // binding a unix socket creates it in its directory
+    for rule in rules.iter() {
+        write.extend(rule.unix_listeners().map(parent));
+    }
+    write.extend(opt.sandbox_write.iter().cloned());
+    sandbox::Sandbox {
+        read,
+        write,
+        chroot: opt.chroot.clone(),
+    }
+}
+
+pub(crate) async fn run(opt: Opts, sandboxed: Option<Sandboxed>) -> anyhow::Result<()> {
+    let syslog = opt.syslog.then(|| opt.syslog_facility);
+    metrics::start();
+    logging::init(
+        opt.log_format,
+        opt.stdio,
+        syslog,
+        opt.otlp_endpoint.as_deref(),
+    )
+    .context("invalid configuration")?;
+    let _flush = telemetry::Flush;
+    match sandboxed.as_ref() {
+        Some(Sandboxed::Landlock) => tracing::info!("Filesystem access restricted with landlock"),
+        Some(Sandboxed::Chroot(e)) => {
+            tracing::warn!("Landlock unavailable, falling back to chroot: {:#}", e)
+        }
+        None => {}
+    }
+    #[cfg(unix)]
+    check_fd_limit(opt.max_connections);
+
+    if let Some(command) = opt.command {
+        let passed = match command {
+            Command::Check(opts) => Some(check::run(opts).await.context("invalid configuration")?),
+            Command::Validate(opts) => Some(check::validate(opts).await),
+            // running as the service, go on serving
+            #[cfg(windows)]
+            Command::Service(_) => None,
+        };
+        if let Some(passed) = passed {
+            // exiting skips the drop of the guard
+            telemetry::shutdown();
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+    }
+
+    let rules = config::load(opt.rule, opt.config.clone())
+        .await
+        .context("invalid configuration")?;
+
+    if let Some(timeout) = opt.wait_for_proxy {
+        wait_for_proxies(&rules, Duration::from_secs(timeout))
+            .await
+            .context("proxy unavailable")?;
+    }
+
+    if opt.stdio {
+        return serve_stdio(rules).await.context("relay failed");
+    }
+    let limits = Arc::new(
+        Limits::new(
+            opt.max_connections,
+            opt.max_connections_per_ip,
+            AcceptRates {
+                global: opt.accept_rate,
+                per_ip: opt.accept_rate_per_ip,
+            },
+            opt.bandwidth,
+            opt.quota.map(|bytes| (bytes, opt.quota_period)),
+        )
+        .context("invalid configuration")?,
+    );
+    if let Some(path) = opt.access_log {
+        let rotation = access_log::Rotation {
+            max_size: opt.access_log_max_size,
+            max_age: opt.access_log_max_age.map(Duration::from_secs),
+            keep: opt.access_log_keep,
+        };
+        access_log::spawn(path, rotation).context("invalid configuration")?;
+    }
+    #[cfg(unix)]
+    metrics::summarize_on_signal().context("unable to handle SIGUSR1")?;
+    if let Some(addr) = opt.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve_metrics(addr).await {
+                tracing::error!("Metrics endpoint failed: {}", e);
+            }
+        });
+    }
+    if let Some(addr) = opt.health_addr {
+        tokio::spawn(async move {
+            if let Err(e) = health::serve_health(addr).await {
+                tracing::error!("Health endpoint failed: {}", e);
+            }
+        });
+    }
+    if let Some(addr) = opt.admin_addr {
+        tokio::spawn(async move {
+            if let Err(e) = connections::serve_admin(addr).await {
+                tracing::error!("Admin api failed: {}", e);
+            }
+        });
+    }
+    if let Some(addr) = opt.statsd_addr {
+        if opt.statsd_interval == 0 {
+            anyhow::bail!("invalid configuration: statsd interval must be positive");
+        }
+        statsd::spawn(addr, Duration::from_secs(opt.statsd_interval));
+    }
+    #[cfg(unix)]
+    if let Some(path) = opt.upgrade_socket.as_ref() {
+        let taken = handoff::receive(path).context("unable to take over listening sockets")?;
+        if taken > 0 {
+            tracing::info!(
+                "Took over {} listening sockets from the running instance",
+                taken
+            );
+        }
+    }
+    let privileges = match (opt.user.as_deref(), opt.group.as_deref()) {
+        (None, None) => None,
+        (user, group) => Some(Privileges::resolve(user, group).context("invalid configuration")?),
+    };
+    let mut forwarder = Forwarder::new(Config::from_rules(rules, opt.config))
+        .shutdown_grace(Duration::from_secs(opt.shutdown_grace))
+        .handle_signals(true);
+    forwarder.limits = limits;
+    forwarder.upgrade_socket = opt.upgrade_socket;
+    forwarder.privileges = privileges;
+    // without landlock, the chroot waits for the listeners to be bound
+    #[cfg(unix)]
+    {
+        forwarder.chroot = opt
+            .chroot
+            .filter(|_| !matches!(sandboxed, Some(Sandboxed::Landlock)));
+    }
+    forwarder.seccomp = opt.seccomp;
+    #[cfg(feature = "hickory-dns")]
+    if opt.hickory_dns {
+        let resolver = crate::resolver::HickoryResolver::from_system_conf();
+        forwarder = forwarder.resolver(resolver.context("invalid configuration")?);
+    }
+    forwarder.run().await
+}
+
+/// Fds taken apart from relays, by listeners, the runtime, logs and
+/// endpoints.
+#[cfg(unix)]
+const RESERVED_FDS: u64 = 64;
+
+/// Every relay holds two fds, one for the client and one upstream.
+#[cfg(unix)]
+fn check_fd_limit(max_connections: Option<usize>) {
+    let limit = match utils::raise_fd_limit() {
+        Ok(limit) => limit,
+        Err(e) => {
+            tracing::warn!("Unable to raise the file descriptor limit: {}", e);
+            return;
+        }
+    };
+    tracing::info!("File descriptor limit is {}", limit);
+    let needed = max_connections.map(|max| max as u64 * 2 + RESERVED_FDS);
+    if let Some(needed) = needed.filter(|needed| *needed > limit) {
+        tracing::warn!(
+            "--max-connections needs about {} file descriptors, more than the limit of {}",
+            needed,
+            limit
+        );
+    }
+}
+
+/// Surface orchestration ordering problems at boot rather than as failures
+/// of every connection later.
+async fn wait_for_proxies(rules: &[Rule], timeout: Duration) -> anyhow::Result<()> {
+    for rule in rules {
+        // the pools of source routes as well, clients may be picked for any
+        for pool in rule.pools() {
+            tracing::info!("Waiting for a proxy of {} to be reachable", rule.listen);
+            // discovered proxies are only known once discovery runs
+            pool::spawn_background(pool);
+            pool::wait_reachable(pool, timeout).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn serve_stdio(rules: Vec<Rule>) -> anyhow::Result<()> {
+    if rules.len() != 1 {
+        anyhow::bail!("stdio mode needs exactly one rule, {} given", rules.len());
+    }
+    let rule = rules.into_iter().next().unwrap();
+    if rule.udp {
+        anyhow::bail!("udp is not supported in stdio mode");
+    }
+    relay::serve_stdio(rule).await
+}
@@ -0,0 +1,1865 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::Args;
+use rand::Rng;
+use serde::{Deserialize, Deserializer};
+
+use crate::acl::{Acl, Cidr, GeoIp};
+use crate::bind::SocksBind;
+use crate::discovery::{self, Discovery, ProxyBuilder, Source};
+use crate::dns;
+use crate::doh::Doh;
+use crate::http::HostRouting;
+use crate::limit::Quotas;
+use crate::listener::{SYSTEMD_PREFIX, VSOCK_PREFIX};
+use crate::mux::MuxSessions;
+use crate::outbound::Outbound;
+use crate::pool::{CircuitBreaker, HealthCheck, Prewarm, ProxyPool};
+use crate::resolver::CachedAddrs;
+use crate::sni::SniRouting;
+use crate::statsd::Tags;
+use crate::target_check::TargetCheck;
+use crate::throttle::Bandwidth;
+use crate::tls::{TlsClient, TlsServer};
+use crate::utils::{split_host_port, Keepalive, UNIX_PREFIX};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ProxyProtocol {
+    Socks4,
+    Socks4a,
+    Socks5,
+    /// HTTP proxy supporting the CONNECT method.
+    Http,
+}
+
+impl Default for ProxyProtocol {
+    fn default() -> Self {
+        ProxyProtocol::Socks5
+    }
+}
+
+/// Where the names of targets reached through a proxy are resolved.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Resolve {
+    /// Here, the proxy is sent an ip address.
+    Local,
+    /// By the proxy, sent the name so no dns query leaks from here.
+    Remote,
+}
+
+impl Default for Resolve {
+    fn default() -> Self {
+        Resolve::Remote
+    }
+}
+
+impl FromStr for Resolve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Resolve::Local),
+            "remote" => Ok(Resolve::Remote),
+            _ => Err(format!("unknown resolve {}, expect local or remote", s)),
+        }
+    }
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "socks4" => Ok(ProxyProtocol::Socks4),
+            "socks4a" => Ok(ProxyProtocol::Socks4a),
+            "socks5" => Ok(ProxyProtocol::Socks5),
+            "http" => Ok(ProxyProtocol::Http),
+            _ => Err(format!(
+                "unknown proxy protocol {}, expect socks4, socks4a, socks5 or http",
+                s
+            )),
+        }
+    }
+}
+
+/// How new connections are spread over the proxies.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Balance {
+    RoundRobin,
+    /// Proxy with the fewest active relays.
+    LeastConn,
+}
+
+impl Default for Balance {
+    fn default() -> Self {
+        Balance::RoundRobin
+    }
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Balance::RoundRobin),
+            "least-conn" => Ok(Balance::LeastConn),
+            _ => Err(format!(
+                "unknown balance strategy {}, expect round-robin or least-conn",
+                s
+            )),
+        }
+    }
+}
+
+/// PROXY protocol version of the header sent to the target.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ProxyHeaderVersion {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyHeaderVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(ProxyHeaderVersion::V1),
+            "v2" => Ok(ProxyHeaderVersion::V2),
+            _ => Err(format!(
+                "unknown proxy protocol version {}, expect v1 or v2",
+                s
+            )),
+        }
+    }
+}
+
+/// `key=target`, like a host or ALPN protocol mapped to the target for it.
+#[derive(Debug, Clone)]
+pub(crate) struct Route {
+    pub(crate) key: String,
+    pub(crate) target: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, target)) => Ok(Route {
+                key: key.to_string(),
+                target: target.to_string(),
+            }),
+            None => Err(format!("invalid route {}, expect key=target", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyConfig {
+    pub(crate) address: String,
+    pub(crate) protocol: ProxyProtocol,
+    /// Username and password; SOCKS4 only sends the username as user id,
+    /// HTTP sends them with basic authentication.
+    pub(crate) credential: Option<(String, String)>,
+    /// Talk to the proxy over TLS.
+    pub(crate) tls: Option<TlsClient>,
+    /// Socks5 proxies traversed in order after this one before reaching the target.
+    pub(crate) chain: Vec<ProxyConfig>,
+    pub(crate) outbound: Outbound,
+    /// Time limit of socks5 negotiation or the CONNECT exchange, hops included.
+    pub(crate) handshake_timeout: Option<Duration>,
+    /// Where the target is resolved, socks4 proxies always get an ip.
+    pub(crate) resolve: Resolve,
+    /// Resolves the target instead of the system resolver, when local.
+    pub(crate) doh: Option<Arc<Doh>>,
+    /// Addresses of the proxy re-resolved periodically, instead of on every
+    /// connection.
+    pub(crate) resolved: Option<Arc<CachedAddrs>>,
+    /// Authenticate every connection with random credentials of its own, so
+    /// Tor puts each on a separate circuit.
+    pub(crate) isolate_streams: bool,
+    /// Authenticate to the socks5 proxy with Kerberos as this host based
+    /// service, like `rcmd@proxy.example.com`.
+    pub(crate) gssapi: Option<String>,
+}
+
+impl ProxyConfig {
+    /// Credentials of a new connection to the proxy.
+    pub(crate) fn stream_credential(&self) -> Option<(String, String)> {
+        if self.isolate_streams {
+            let random = || format!("{:032x}", rand::random::<u128>());
+            return Some((random(), random()));
+        }
+        self.credential.clone()
+    }
+
+    /// Parse a chained socks5 hop, like `user:pass@10.0.0.2:1080`.
+    fn hop(s: &str) -> anyhow::Result<Self> {
+        let (credential, address) = split_credential(s)?;
+        Ok(Self {
+            address: address.to_string(),
+            protocol: ProxyProtocol::Socks5,
+            credential,
+            tls: None,
+            chain: Vec::new(),
+            // only the first proxy is connected to
+            outbound: Outbound::default(),
+            handshake_timeout: None,
+            // the target is only sent to the last hop, as resolved by the first
+            resolve: Resolve::Remote,
+            doh: None,
+            resolved: None,
+            isolate_streams: false,
+            gssapi: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Target {
+    Fixed(String),
+    /// Host given, the port is the one connected to on the listener.
+    PreservePort(String),
+    /// Destination before being redirected by iptables, read by SO_ORIGINAL_DST.
+    Original,
+    /// Local address of the accepted connection, which is the original
+    /// destination when intercepted by TPROXY.
+    Local,
+    /// Host named in the TLS ClientHello.
+    Sni(SniRouting),
+    /// Derived from the Host header of the first HTTP request.
+    Host(HostRouting),
+    /// Requested by the client speaking socks5 to the listener.
+    Socks5,
+    /// Requested by the client with HTTP CONNECT.
+    HttpConnect,
+}
+
+impl Target {
+    pub(crate) fn fixed(&self) -> Option<&str> {
+        match self {
+            Target::Fixed(target) => Some(target.as_str()),
+            _ => None,
+        }
+    }
+}
+
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Retries of connecting through proxy, with exponential backoff.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Retry {
+    pub(crate) retries: u32,
+    pub(crate) backoff: Duration,
+}
+
+impl Retry {
+    /// Delay before the retry following `attempt` failures, jittered
+    /// between half and full of the exponential backoff.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_RETRY_BACKOFF);
+        let millis = delay.as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+    }
+}
+
+/// A forwarding rule: connections accepted on `listen` are relayed to `target`,
+/// through one of the proxies if there are any.
+#[derive(Debug)]
+pub(crate) struct Rule {
+    pub(crate) listen: String,
+    pub(crate) target: Target,
+    /// Tried in order when connecting to the fixed target failed.
+    pub(crate) target_failover: Vec<String>,
+    /// Health of the fixed target and failover ones, down ones are skipped.
+    pub(crate) target_check: Option<TargetCheck>,
+    pub(crate) proxy: Option<Arc<ProxyPool>>,
+    /// Proxies of clients by network, checked in order before `proxy`.
+    pub(crate) source_routes: Vec<SourceRoute>,
+    /// Connect to the target directly when the proxy is unavailable.
+    pub(crate) fallback_direct: bool,
+    pub(crate) retry: Retry,
+    /// Relay over yamux sessions to a forwarder running with `--demux`.
+    pub(crate) mux: Option<MuxSessions>,
+    /// Accept yamux sessions, relaying every stream in them.
+    pub(crate) demux: bool,
+    /// Expect a PROXY protocol header on accepted connections.
+    pub(crate) accept_proxy_protocol: bool,
+    /// Terminate TLS on accepted connections.
+    pub(crate) tls: Option<TlsServer>,
+    /// Talk to the target over TLS.
+    pub(crate) target_tls: Option<TlsClient>,
+    /// Announce the client to the target with a PROXY protocol header.
+    pub(crate) send_proxy_protocol: Option<ProxyHeaderVersion>,
+    /// Bind the udp socket of the listen address, forwarding datagrams or
+    /// dns queries.
+    pub(crate) udp: bool,
+    /// Relay dns queries of the udp socket to the target over tcp, the tcp
+    /// listener relaying dns over tcp as is.
+    pub(crate) dns: bool,
+    /// Accept BIND requests when serving socks5.
+    pub(crate) socks5_bind: Option<SocksBind>,
+    /// Only accept IPv6 clients on an IPv6 listen address.
+    pub(crate) v6only: bool,
+    /// Only accept traffic arriving on this network interface.
+    pub(crate) bind_device: Option<String>,
+    pub(crate) outbound: Outbound,
+    /// DSCP class of packets sent to clients.
+    pub(crate) inbound_dscp: Option<u8>,
+    /// Keepalive of accepted connections, the outbound ones have their own.
+    pub(crate) keepalive: Option<Keepalive>,
+    /// Disable Nagle's algorithm on accepted connections.
+    pub(crate) nodelay: bool,
+    /// Listening sockets sharing the address with SO_REUSEPORT, each with its
+    /// own accept loop.
+    pub(crate) acceptors: usize,
+    /// Pending connections queued by the kernel before being accepted.
+    pub(crate) backlog: i32,
+    /// Close relays nothing went through for this long.
+    pub(crate) idle_timeout: Option<Duration>,
+    /// Close relays living longer than this, whatever they are doing.
+    pub(crate) max_lifetime: Option<Duration>,
+    /// Time limit of reading what comes before relaying from clients.
+    pub(crate) accept_timeout: Option<Duration>,
+    /// Bytes per second of every relay, in each direction.
+    pub(crate) rate_limit: Option<u64>,
+    /// Bandwidth shared with relays of every rule, set by the process.
+    pub(crate) bandwidth: Option<Bandwidth>,
+    /// Transfer quotas of client ips, set by the process.
+    pub(crate) quotas: Option<Arc<Quotas>>,
+    /// Networks of clients allowed to connect.
+    pub(crate) acl: Acl,
+    /// Tags of statsd metrics of the rule.
+    pub(crate) statsd_tags: Tags,
+}
+
+impl Rule {
+    /// Proxies for connections of `client`, none to connect directly.
+    pub(crate) fn proxy_for(&self, client: IpAddr) -> Option<&Arc<ProxyPool>> {
+        match self
+            .source_routes
+            .iter()
+            .find(|route| route.network.contains(client))
+        {
+            Some(route) => route.proxy.as_ref(),
+            None => self.proxy.as_ref(),
+        }
+    }
+
+    /// Every proxy pool of the rule, for starting their background tasks.
+    pub(crate) fn pools(&self) -> impl Iterator<Item = &Arc<ProxyPool>> {
+        self.proxy.iter().chain(
+            self.source_routes
+                .iter()
+                .filter_map(|route| route.proxy.as_ref()),
+        )
+    }
+}
+
+/// Target of a source route connecting without proxy.
+const DIRECT_ROUTE: &str = "direct";
+
+/// Clients of `network` go through `proxy`, or directly without one.
+#[derive(Debug, Clone)]
+pub(crate) struct SourceRoute {
+    network: Cidr,
+    proxy: Option<Arc<ProxyPool>>,
+}
+
+/// Options of a single rule, shared by the command line and the config file.
+#[derive(Args, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RuleOpts {
+    #[clap(
+        short,
+        long,
+        default_value = "127.0.0.1:8000",
+        help = "listen address, a port range like 0.0.0.0:6000-6100 listens on every port, unix:/path for unix socket, vsock:<cid or any>:<port> for vsock, systemd:<index or name> for socket passed by systemd, repeat along with --target for more forwards sharing the proxies"
+    )]
+    #[serde(default = "default_listen", deserialize_with = "one_or_many")]
+    pub(crate) listen: Vec<String>,
+    #[clap(
+        short,
+        long,
+        help = "target address like 1.1.1.1:443 or unix:/path without proxy, for the listen address in the same position"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) target: Vec<String>,
+    #[clap(
+        long,
+        help = "standby target tried when connecting to --target fails, repeat for more tried in order(single tcp target only)"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) target_failover: Vec<String>,
+    #[clap(
+        long,
+        help = "seconds between health checks of the target and its failover targets through the proxy, down ones are skipped(disabled by default)"
+    )]
+    #[serde(default)]
+    pub(crate) target_check_interval: Option<u64>,
+    #[clap(
+        long,
+        help = "take the target port from the port connected to on the listener, --target is then only the host"
+    )]
+    #[serde(default)]
+    pub(crate) preserve_port: bool,
+    #[clap(
+        long,
+        env = "SOCKS5_PROXY",
+        hide_env_values = true,
+        help = "socks5 proxy address, like 10.0.0.1:8080(leave blank for direct proxy), repeat for multiple proxies with optional weight like 10.0.0.1:8080,weight=3 and account of their own like user:pass@10.0.0.1:8080, or file:/path@10.0.0.1:8080 to read it from a file"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) proxy_addr: Vec<String>,
+    #[clap(
+        long,
+        help = "discover the proxies from this srv name, like _socks._tcp.example.com, with their priorities and weights, asked again once the answer expires"
+    )]
+    #[serde(default)]
+    pub(crate) proxy_srv: Option<String>,
+    #[clap(
+        long,
+        help = "discover the proxies from a registry, the passing instances of a consul service like consul://127.0.0.1:8500/socks or the keys under an etcd prefix like etcd://127.0.0.1:2379/socks/ valued host:port[,weight=N]"
+    )]
+    #[serde(default)]
+    pub(crate) proxy_discovery: Option<String>,
+    #[clap(
+        long,
+        help = "proxy of clients from a network like 10.1.0.0/16=10.0.0.1:1080, or 10.2.0.0/16=direct, the first matching route wins over the proxies of the rule"
+    )]
+    #[serde(default, deserialize_with = "routes")]
+    pub(crate) source_route: Vec<Route>,
+    #[clap(
+        long,
+        default_value = "round-robin",
+        help = "how to balance over multiple proxies, round-robin or least-conn"
+    )]
+    #[serde(default)]
+    pub(crate) balance: Balance,
+    #[clap(
+        long,
+        help = "seconds between proxy health checks, failing proxies leave the rotation(disabled by default)"
+    )]
+    pub(crate) health_check_interval: Option<u64>,
+    #[clap(
+        long,
+        default_value = "5",
+        help = "timeout of a proxy or target health check in seconds"
+    )]
+    #[serde(default = "default_health_check_timeout")]
+    pub(crate) health_check_timeout: u64,
+    #[clap(
+        long,
+        help = "pause a proxy after this many connect failures in a row(disabled by default)"
+    )]
+    pub(crate) circuit_breaker_failures: Option<u32>,
+    #[clap(
+        long,
+        default_value = "30",
+        help = "seconds a proxy is paused by the circuit breaker"
+    )]
+    #[serde(default = "default_circuit_breaker_cooldown")]
+    pub(crate) circuit_breaker_cooldown: u64,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "connections to keep opened to every proxy ahead of time"
+    )]
+    #[serde(default)]
+    pub(crate) prewarm: usize,
+    #[clap(
+        long,
+        help = "also finish the proxy handshake to the target ahead of time(needs a fixed target)"
+    )]
+    #[serde(default)]
+    pub(crate) prewarm_handshake: bool,
+    #[clap(
+        long,
+        default_value = "socks5",
+        help = "proxy protocol, socks4, socks4a, socks5 or http"
+    )]
+    #[serde(default)]
+    pub(crate) proxy_protocol: ProxyProtocol,
+    #[clap(
+        long,
+        help = "authenticate every connection to the socks5 proxy with a random username and password, which Tor isolates on a circuit of its own"
+    )]
+    #[serde(default)]
+    pub(crate) isolate_streams: bool,
+    #[clap(
+        long,
+        help = "authenticate to the socks5 proxy with gssapi, using the kerberos ticket of the credential cache(needs the gssapi feature)"
+    )]
+    #[serde(default)]
+    pub(crate) proxy_gssapi: bool,
+    #[clap(
+        long,
+        default_value = "rcmd",
+        help = "kerberos service of the proxy with gssapi, its principal being service/proxy-host"
+    )]
+    #[serde(default = "default_gssapi_service")]
+    pub(crate) proxy_gssapi_service: String,
+    #[clap(
+        long,
+        default_value = "remote",
+        help = "where target names are resolved, remote by the proxy so no dns query leaks, or local sending it an ip(socks4 is always local)"
+    )]
+    #[serde(default)]
+    pub(crate) resolve: Resolve,
+    #[clap(
+        long,
+        help = "resolve target names with this dns-over-https endpoint, like https://cloudflare-dns.com/dns-query(needs --resolve local)"
+    )]
+    #[serde(default)]
+    pub(crate) doh: Option<String>,
+    #[clap(
+        long,
+        help = "re-resolve proxy hostnames every this many seconds, instead of on every connection"
+    )]
+    #[serde(default)]
+    pub(crate) proxy_resolve_interval: Option<u64>,
+    #[clap(
+        long,
+        env = "SOCKS5_USER",
+        help = "proxy username, the user id of socks4 and basic authentication of http"
+    )]
+    pub(crate) proxy_user: Option<String>,
+    #[clap(
+        long,
+        env = "SOCKS5_PASS",
+        hide_env_values = true,
+        help = "proxy password, of socks5 and basic authentication of http"
+    )]
+    pub(crate) proxy_pass: Option<String>,
+    #[clap(
+        long,
+        env = "SOCKS5_USER_FILE",
+        help = "file holding the proxy username, like a docker or kubernetes secret mounted at /run/secrets/proxy_user"
+    )]
+    pub(crate) proxy_user_file: Option<PathBuf>,
+    #[clap(
+        long,
+        env = "SOCKS5_PASS_FILE",
+        help = "file holding the proxy password, like a docker or kubernetes secret mounted at /run/secrets/proxy_pass"
+    )]
+    pub(crate) proxy_pass_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "file with the proxy username and password as user:pass, readable by its owner only, instead of --proxy-user and --proxy-pass"
+    )]
+    pub(crate) credentials_file: Option<PathBuf>,
+    #[clap(long, help = "connect to the proxy over tls")]
+    #[serde(default)]
+    pub(crate) proxy_tls: bool,
+    #[clap(
+        long,
+        help = "ca certificates(pem) to verify the proxy, use the bundled roots by default"
+    )]
+    pub(crate) proxy_tls_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "server name to verify the proxy, use the host of proxy address by default"
+    )]
+    pub(crate) proxy_tls_sni: Option<String>,
+    #[clap(
+        long,
+        help = "socks5 proxies to traverse through the proxy in order, like user:pass@10.0.0.2:1080 or file:/path@10.0.0.2:1080"
+    )]
+    #[serde(default)]
+    pub(crate) proxy_chain: Vec<String>,
+    #[clap(
+        long,
+        help = "connect to the target directly when connecting through proxy fails(tcp only)"
+    )]
+    #[serde(default)]
+    pub(crate) fallback_direct: bool,
+    #[clap(
+        long,
+        default_value = "0",
+        help = "times to retry connecting through proxy before giving up(tcp only)"
+    )]
+    #[serde(default)]
+    pub(crate) connect_retries: u32,
+    #[clap(
+        long,
+        default_value = "100",
+        help = "initial retry backoff in milliseconds, doubled on every retry"
+    )]
+    #[serde(default = "default_retry_backoff")]
+    pub(crate) retry_backoff: u64,
+    #[clap(
+        long,
+        help = "expect a PROXY protocol(v1 or v2) header on accepted connections, like from haproxy with send-proxy"
+    )]
+    #[serde(default)]
+    pub(crate) accept_proxy_protocol: bool,
+    #[clap(
+        long,
+        help = "certificate chain(pem) to terminate tls on the listener, the target gets plaintext"
+    )]
+    pub(crate) tls_cert: Option<PathBuf>,
+    #[clap(long, help = "private key(pem) of the tls certificate")]
+    pub(crate) tls_key: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "ca certificates(pem) to verify clients with, clients without a valid certificate are rejected"
+    )]
+    pub(crate) tls_client_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "route by the alpn protocol negotiated with tls termination, like h2=10.0.0.1:443, others go to the target"
+    )]
+    #[serde(default, deserialize_with = "routes")]
+    pub(crate) alpn_route: Vec<Route>,
+    #[clap(
+        long,
+        help = "connect to the target over tls, for plaintext clients of tls only services"
+    )]
+    #[serde(default)]
+    pub(crate) target_tls: bool,
+    #[clap(
+        long,
+        help = "ca certificates(pem) to verify the target, use the bundled roots by default"
+    )]
+    pub(crate) target_tls_ca: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "server name to verify the target, use the host of target address by default"
+    )]
+    pub(crate) target_tls_sni: Option<String>,
+    #[clap(
+        long,
+        help = "send a PROXY protocol header of this version(v1 or v2) to the target before relaying, like to haproxy with accept-proxy"
+    )]
+    pub(crate) send_proxy_protocol: Option<ProxyHeaderVersion>,
+    #[clap(
+        long,
+        help = "multiplex relays over this many yamux sessions to the target, which must run with --demux"
+    )]
+    pub(crate) mux: Option<usize>,
+    #[clap(
+        long,
+        help = "accept yamux sessions from a forwarder running with --mux and relay every stream to the target"
+    )]
+    #[serde(default)]
+    pub(crate) demux: bool,
+    #[clap(
+        long,
+        help = "also forward udp on the listen address(through socks5 udp associate with proxy)"
+    )]
+    #[serde(default)]
+    pub(crate) udp: bool,
+    #[clap(
+        long,
+        help = "serve dns on the listen address over udp and tcp, relaying queries to the resolver of --target over tcp through the proxy so none leaks"
+    )]
+    #[serde(default)]
+    pub(crate) dns: bool,
+    #[clap(
+        long,
+        help = "only accept ipv6 clients on an ipv6 listen address, [::] takes ipv4 clients too without it"
+    )]
+    #[serde(default)]
+    pub(crate) v6only: bool,
+    #[clap(
+        long,
+        help = "only accept traffic arriving on this network interface, like eth0(linux only)"
+    )]
+    #[serde(default)]
+    pub(crate) bind_device: Option<String>,
+    #[clap(
+        long,
+        help = "local address to connect to proxies and targets from, on multi-homed hosts"
+    )]
+    #[serde(default)]
+    pub(crate) outbound_bind: Option<IpAddr>,
+    #[clap(
+        long,
+        help = "firewall mark(SO_MARK) set on connections to proxies and targets, for policy routing(linux only)"
+    )]
+    #[serde(default)]
+    pub(crate) fwmark: Option<u32>,
+    #[clap(
+        long,
+        help = "DSCP class(0-63) of packets sent to clients, for QoS(unix only)"
+    )]
+    #[serde(default)]
+    pub(crate) inbound_dscp: Option<u8>,
+    #[clap(
+        long,
+        help = "DSCP class(0-63) of packets sent to proxies and targets, for QoS(unix only)"
+    )]
+    #[serde(default)]
+    pub(crate) outbound_dscp: Option<u8>,
+    #[clap(
+        long,
+        help = "disable tcp keepalive on client, proxy and target connections"
+    )]
+    #[serde(default)]
+    pub(crate) no_keepalive: bool,
+    #[clap(
+        long,
+        default_value = "15",
+        help = "seconds idle before the first tcp keepalive probe"
+    )]
+    #[serde(default = "default_keepalive_idle")]
+    pub(crate) keepalive_idle: u64,
+    #[clap(
+        long,
+        help = "seconds between tcp keepalive probes, the idle time by default"
+    )]
+    #[serde(default)]
+    pub(crate) keepalive_interval: Option<u64>,
+    #[clap(
+        long,
+        help = "unanswered tcp keepalive probes before dropping the connection(linux only), the system default if unset"
+    )]
+    #[serde(default)]
+    pub(crate) keepalive_count: Option<u32>,
+    #[clap(
+        long,
+        help = "set TCP_NODELAY on both sides of relays, lowering latency of interactive protocols like ssh"
+    )]
+    #[serde(default)]
+    pub(crate) nodelay: bool,
+    #[clap(
+        long,
+        default_value = "10",
+        help = "seconds to wait connecting to a proxy or target, 0 leaves it to the system"
+    )]
+    #[serde(default = "default_connect_timeout")]
+    pub(crate) connect_timeout: u64,
+    #[clap(
+        long,
+        default_value = "10",
+        help = "seconds to wait for the handshake with a proxy, 0 waits forever"
+    )]
+    #[serde(default = "default_handshake_timeout")]
+    pub(crate) handshake_timeout: u64,
+    #[clap(
+        long,
+        default_value = "10",
+        help = "seconds clients are given to send what comes before relaying, like a PROXY header, tls handshake or requested host, 0 waits forever"
+    )]
+    #[serde(default = "default_handshake_timeout")]
+    pub(crate) accept_timeout: u64,
+    #[clap(
+        long,
+        help = "close relays with no bytes transferred in either direction for this many seconds"
+    )]
+    #[serde(default)]
+    pub(crate) idle_timeout: Option<u64>,
+    #[clap(
+        long,
+        help = "close relays after this many seconds even when busy, to rotate upstream sessions"
+    )]
+    #[serde(default)]
+    pub(crate) max_lifetime: Option<u64>,
+    #[clap(
+        long,
+        help = "bytes per second of every relay in each direction, bursting up to one second worth"
+    )]
+    #[serde(default)]
+    pub(crate) rate_limit: Option<u64>,
+    #[clap(
+        long,
+        help = "only accept clients from this network, like 192.168.0.0/16, repeat for more"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) allow: Vec<String>,
+    #[clap(
+        long,
+        help = "reject clients from this network, even when allowed, repeat for more"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) deny: Vec<String>,
+    #[clap(
+        long,
+        help = "MaxMind GeoLite2 or GeoIP2 country database, to check and log the country of clients"
+    )]
+    #[serde(default)]
+    pub(crate) geoip_db: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "only accept clients from this country code like DE, allowed networks are let in as well, repeat for more"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) allow_country: Vec<String>,
+    #[clap(
+        long,
+        help = "reject clients from this country code, even when allowed, repeat for more"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) deny_country: Vec<String>,
+    #[clap(
+        long,
+        help = "tag like env:prod on statsd metrics of the listener, along with listener:<address>, repeat for more"
+    )]
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub(crate) statsd_tag: Vec<String>,
+    #[clap(
+        long,
+        default_value = "1",
+        help = "listening sockets bound with SO_REUSEPORT, each accepting on its own, to scale accepting on many cores(unix only)"
+    )]
+    #[serde(default = "default_acceptors")]
+    pub(crate) acceptors: usize,
+    #[clap(
+        long,
+        default_value = "1024",
+        help = "pending connections queued by the kernel for tcp listeners, capped by net.core.somaxconn on linux"
+    )]
+    #[serde(default = "default_backlog")]
+    pub(crate) backlog: i32,
+    #[clap(
+        long,
+        help = "relay to the original destination of connections redirected by iptables(linux only)"
+    )]
+    #[serde(default)]
+    pub(crate) transparent: bool,
+    #[clap(
+        long,
+        help = "accept connections intercepted by iptables TPROXY and relay to their original destination(linux only)"
+    )]
+    #[serde(default)]
+    pub(crate) tproxy: bool,
+    #[clap(
+        long,
+        help = "relay to the host named by SNI in the tls client hello, like a sniproxy"
+    )]
+    #[serde(default)]
+    pub(crate) sni: bool,
+    #[clap(long, default_value = "443", help = "target port with --sni")]
+    #[serde(default = "default_sni_port")]
+    pub(crate) sni_port: u16,
+    #[clap(
+        long,
+        help = "hosts allowed with --sni, like example.com or *.example.com(allow all by default)"
+    )]
+    #[serde(default)]
+    pub(crate) sni_allow: Vec<String>,
+    #[clap(
+        long,
+        help = "relay plain http to the target routed by the Host header of the first request"
+    )]
+    #[serde(default)]
+    pub(crate) http_host: bool,
+    #[clap(
+        long,
+        help = "route of --http-host like example.com=10.0.0.1:80 or *.example.com=10.0.0.2:80, hosts are reached themselves without any route"
+    )]
+    #[serde(default, deserialize_with = "routes")]
+    pub(crate) host_route: Vec<Route>,
+    #[clap(
+        long,
+        default_value = "80",
+        help = "port of hosts without one with --http-host"
+    )]
+    #[serde(default = "default_http_port")]
+    pub(crate) http_port: u16,
+    #[clap(
+        long,
+        help = "serve socks5 on the listener and relay to the destination requested by clients"
+    )]
+    #[serde(default)]
+    pub(crate) socks5: bool,
+    #[clap(
+        long,
+        help = "accept BIND requests of socks5 clients, binding a port on the proxy, or here without one, for the peer to connect back like active ftp"
+    )]
+    #[serde(default)]
+    pub(crate) socks5_bind: bool,
+    #[clap(
+        long,
+        help = "ip reported to socks5 clients as bound for BIND instead of the real one, like the public ip of a proxy behind nat"
+    )]
+    pub(crate) socks5_bind_advertise: Option<IpAddr>,
+    #[clap(
+        long,
+        help = "serve http CONNECT on the listener and relay to the destination requested by clients"
+    )]
+    #[serde(default)]
+    pub(crate) http_connect: bool,
+}
+
+fn default_listen() -> Vec<String> {
+    vec!["127.0.0.1:8000".to_string()]
+}
+
+fn default_health_check_timeout() -> u64 {
+    5
+}
+
+fn default_circuit_breaker_cooldown() -> u64 {
+    30
+}
+
+fn default_gssapi_service() -> String {
+    "rcmd".to_string()
+}
+
+fn default_retry_backoff() -> u64 {
+    100
+}
+
+fn default_acceptors() -> usize {
+    1
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_backlog() -> i32 {
+    1024
+}
+
+fn default_keepalive_idle() -> u64 {
+    15
+}
+
+fn default_sni_port() -> u16 {
+    443
+}
+
+fn default_http_port() -> u16 {
+    80
+}
+
+/// Parse `host:port` or `host:start-end`, returns the host and the port range.
+fn port_range(addr: &str) -> anyhow::Result<(&str, u16, u16)> {
+    let (host, ports) = addr
+        .rsplit_once(':')
+        .with_context(|| format!("invalid address {}", addr))?;
+    let (start, end) = match ports.split_once('-') {
+        Some((start, end)) => (start, end),
+        None => (ports, ports),
+    };
+    let start: u16 = start
+        .parse()
+        .with_context(|| format!("invalid port in address {}", addr))?;
+    let end: u16 = end
+        .parse()
+        .with_context(|| format!("invalid port in address {}", addr))?;
+    if start > end {
+        anyhow::bail!("invalid port range in address {}", addr);
+    }
+    Ok((host, start, end))
+}
+
+/// A secret mounted as a file, without the trailing newline editors and
+/// `echo` leave.
+fn read_secret(path: &Path) -> anyhow::Result<String> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read secret file {}", path.display()))?;
+    Ok(content.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+/// Read `user:pass` from `path`, refusing a file others may read like ssh
+/// does for keys.
+fn read_credentials(path: &Path) -> anyhow::Result<(String, String)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)
+            .with_context(|| format!("unable to read credentials file {}", path.display()))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            anyhow::bail!(
+                "credentials file {} is accessible by others(mode {:o}), chmod 600 it",
+                path.display(),
+                mode & 0o777
+            );
+        }
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read credentials file {}", path.display()))?;
+    let line = content.lines().next().unwrap_or_default();
+    match line.split_once(':') {
+        Some((user, pass)) if !user.is_empty() => Ok((user.to_string(), pass.to_string())),
+        _ => anyhow::bail!(
+            "credentials file {} should hold user:pass on its first line",
+            path.display()
+        ),
+    }
+}
+
+/// Split `user:pass@address` into the credential, if any, and the address.
+/// `file:/path@address` reads the credential from a file like
+/// `--credentials-file`.
+fn split_credential(s: &str) -> anyhow::Result<(Option<(String, String)>, &str)> {
+    let (credential, address) = match s.rsplit_once('@') {
+        Some(split) => split,
+        None => return Ok((None, s)),
+    };
+    if let Some(path) = credential.strip_prefix("file:") {
+        return Ok((Some(read_credentials(Path::new(path))?), address));
+    }
+    let credential = match credential.split_once(':') {
+        Some((u, p)) => (u.to_string(), p.to_string()),
+        None => (credential.to_string(), String::default()),
+    };
+    Ok((Some(credential), address))
+}
+
+/// The file of a `file:/path@address` credential.
+fn credential_file(s: &str) -> Option<&Path> {
+    let (credential, _) = s.rsplit_once('@')?;
+    credential.strip_prefix("file:").map(Path::new)
+}
+
+/// Parse `address[,weight=N]`, weight defaults to 1.
+pub(crate) fn parse_weighted(s: &str) -> anyhow::Result<(String, u32)> {
+    let (address, weight) = match s.split_once(",weight=") {
+        Some((address, weight)) => {
+            let weight: u32 = weight
+                .parse()
+                .with_context(|| format!("invalid weight in {}", s))?;
+            (address, weight)
+        }
+        None => (s, 1),
+    };
+    if weight == 0 {
+        anyhow::bail!("weight must be positive in {}", s);
+    }
+    Ok((address.to_string(), weight))
+}
+
+/// Accept both `"a"` and `["a", "b"]` in config file.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(s) => vec![s],
+        OneOrMany::Many(v) => v,
+    })
+}
+
+/// Routes are a table of key to target in config file.
+fn routes<'de, D>(deserializer: D) -> Result<Vec<Route>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let routes = BTreeMap::<String, String>::deserialize(deserializer)?;
+    Ok(routes
+        .into_iter()
+        .map(|(key, target)| Route { key, target })
+        .collect())
+}
+
+impl RuleOpts {
+    /// Files read by the rules, like certificates and credentials.
+    pub(crate) fn files(&self) -> Vec<&Path> {
+        let proxies = self
+            .proxy_addr
+            .iter()
+            .chain(self.proxy_chain.iter())
+            .chain(self.source_route.iter().map(|route| &route.target));
+        let mut files: Vec<&Path> = proxies.filter_map(|s| credential_file(s)).collect();
+        files.extend(
+            [
+                &self.tls_cert,
+                &self.tls_key,
+                &self.tls_client_ca,
+                &self.proxy_tls_ca,
+                &self.credentials_file,
+                &self.proxy_user_file,
+                &self.proxy_pass_file,
+                &self.target_tls_ca,
+                &self.geoip_db,
+            ]
+            .iter()
+            .copied()
+            .filter_map(Option::as_deref),
+        );
+        files
+    }
+
+    /// Paths of the unix socket listeners, created when bound.
+    pub(crate) fn unix_listeners(&self) -> impl Iterator<Item = &Path> {
+        self.listen
+            .iter()
+            .filter_map(|listen| listen.strip_prefix("unix:"))
+            .map(Path::new)
+    }
+
+    /// Rules of every listen address, all sharing the same proxy pool.
+    pub(crate) fn into_rules(self) -> anyhow::Result<Vec<Rule>> {
+        let name = self.listen.join(", ");
+        if self.listen.is_empty() {
+            anyhow::bail!("no listen address specified");
+        }
+        let modes = [
+            !self.target.is_empty(),
+            self.transparent,
+            self.tproxy,
+            self.sni,
+            self.http_host,
+            self.socks5,
+            self.http_connect,
+        ];
+        match modes.iter().filter(|&&mode| mode).count() {
+            0 => anyhow::bail!("no target specified for listener {}", name),
+            1 => {}
+            _ => anyhow::bail!(
+                "only one of target, transparent, tproxy, sni, http_host, socks5 and http_connect can be set for listener {}",
+                name
+            ),
+        }
+        if !self.target.is_empty() && self.target.len() != self.listen.len() {
+            anyhow::bail!(
+                "every listen address needs its own target for listener {}",
+                name
+            );
+        }
+        // a port range listens on every port, relaying to the matching target port
+        let mut listens = Vec::new();
+        let mut fixed_targets = Vec::new();
+        for (i, listen) in self.listen.iter().enumerate() {
+            if listen.starts_with(UNIX_PREFIX)
+                || listen.starts_with(SYSTEMD_PREFIX)
+                || listen.starts_with(VSOCK_PREFIX)
+            {
+                listens.push(listen.clone());
+                fixed_targets.extend(self.target.get(i).cloned());
+                continue;
+            }
+            let (host, start, end) = port_range(listen)?;
+            listens.extend((start..=end).map(|port| format!("{}:{}", host, port)));
+            if let Some(target) = self.target.get(i) {
+                if self.preserve_port || target.starts_with(UNIX_PREFIX) {
+                    fixed_targets.extend((start..=end).map(|_| target.clone()));
+                    continue;
+                }
+                let (target_host, target_start, target_end) = port_range(target)?;
+                if target_end != target_start && target_end - target_start != end - start {
+                    anyhow::bail!(
+                        "port range of target {} does not match listen address {}",
+                        target,
+                        listen
+                    );
+                }
+                if target_start.checked_add(end - start).is_none() {
+                    anyhow::bail!("port range of target {} is out of bounds", target);
+                }
+                fixed_targets.extend(
+                    (0..=end - start)
+                        .map(|offset| format!("{}:{}", target_host, target_start + offset)),
+                );
+            }
+        }
+        if self.preserve_port && fixed_targets.is_empty() {
+            anyhow::bail!("preserve_port needs a target host for listener {}", name);
+        }
+        // only the fixed target may name a local socket, never a routed one
+        if self
+            .target_failover
+            .iter()
+            .chain(self.alpn_route.iter().map(|route| &route.target))
+            .any(|target| target.starts_with(UNIX_PREFIX))
+        {
+            anyhow::bail!(
+                "unix socket targets are only supported as the target of listener {}",
+                name
+            );
+        }
+        if !self.target_failover.is_empty()
+            && (fixed_targets.len() != 1 || self.preserve_port || self.udp || self.dns)
+        {
+            anyhow::bail!(
+                "target failover needs a single tcp target for listener {}",
+                name
+            );
+        }
+        if self.target_check_interval.is_some() && (fixed_targets.is_empty() || self.preserve_port)
+        {
+            anyhow::bail!(
+                "target health checks need a fixed target for listener {}",
+                name
+            );
+        }
+        if !self.source_route.is_empty() && self.mux.is_some() {
+            anyhow::bail!(
+                "source routes are not supported with mux for listener {}",
+                name
+            );
+        }
+        if self.health_check_interval == Some(0) {
+            anyhow::bail!(
+                "health check interval must be positive for listener {}",
+                name
+            );
+        }
+        if self.target_check_interval == Some(0) {
+            anyhow::bail!(
+                "target check interval must be positive for listener {}",
+                name
+            );
+        }
+        let targets: Vec<Target> = if self.preserve_port {
+            fixed_targets
+                .into_iter()
+                .map(Target::PreservePort)
+                .collect()
+        } else if !fixed_targets.is_empty() {
+            fixed_targets.into_iter().map(Target::Fixed).collect()
+        } else {
+            let target = if self.transparent {
+                Target::Original
+            } else if self.tproxy {
+                Target::Local
+            } else if self.sni {
+                Target::Sni(SniRouting {
+                    port: self.sni_port,
+                    allow: self.sni_allow,
+                })
+            } else if self.http_host {
+                Target::Host(HostRouting {
+                    routes: self.host_route,
+                    port: self.http_port,
+                })
+            } else if self.socks5 {
+                Target::Socks5
+            } else {
+                Target::HttpConnect
+            };
+            vec![target; listens.len()]
+        };
+        let fixed_target = targets.iter().all(|target| target.fixed().is_some());
+        let has_proxy = !self.proxy_addr.is_empty()
+            || self.proxy_srv.is_some()
+            || self.proxy_discovery.is_some()
+            || self
+                .source_route
+                .iter()
+                .any(|route| route.target != DIRECT_ROUTE);
+        if (self.udp || self.dns)
+            && listens
+                .iter()
+                .any(|listen| listen.starts_with(SYSTEMD_PREFIX))
+        {
+            anyhow::bail!(
+                "udp is not supported with systemd sockets for listener {}",
+                name
+            );
+        }
+        if listens
+            .iter()
+            .any(|listen| listen.starts_with(UNIX_PREFIX) || listen.starts_with(VSOCK_PREFIX))
+            && (self.udp
+                || self.dns
+                || targets.iter().any(|target| {
+                    matches!(
+                        target,
+                        Target::Original | Target::Local | Target::PreservePort(_)
+                    )
+                }))
+        {
+            anyhow::bail!(
+                "unix and vsock sockets have no udp, transparent proxy or port to preserve for listener {}",
+                name
+            );
+        }
+        if targets.iter().any(|target| {
+            matches!(target, Target::Fixed(target) | Target::PreservePort(target) if target.starts_with(UNIX_PREFIX))
+        }) && (!cfg!(unix) || self.udp || has_proxy || self.preserve_port)
+        {
+            anyhow::bail!(
+                "unix socket target is only reachable directly without udp for listener {}",
+                name
+            );
+        }
+        if self.rate_limit == Some(0) {
+            anyhow::bail!("rate limit must be positive for listener {}", name);
+        }
+        if self.idle_timeout == Some(0) || self.max_lifetime == Some(0) {
+            anyhow::bail!(
+                "idle timeout and maximum lifetime must be positive for listener {}",
+                name
+            );
+        }
+        if self.geoip_db.is_none()
+            && (!self.allow_country.is_empty() || !self.deny_country.is_empty())
+        {
+            anyhow::bail!(
+                "allow_country and deny_country need geoip_db for listener {}",
+                name
+            );
+        }
+        if self.backlog <= 0 {
+            anyhow::bail!("backlog must be positive for listener {}", name);
+        }
+        if self.acceptors == 0 {
+            anyhow::bail!("at least one acceptor is needed for listener {}", name);
+        }
+        if self.acceptors > 1
+            && (!cfg!(unix)
+                || listens.iter().any(|listen| {
+                    [UNIX_PREFIX, SYSTEMD_PREFIX, VSOCK_PREFIX]
+                        .iter()
+                        .any(|prefix| listen.starts_with(prefix))
+                }))
+        {
+            anyhow::bail!(
+                "multiple acceptors are only supported on tcp listeners on unix for listener {}",
+                name
+            );
+        }
+        if !cfg!(target_os = "linux") && self.fwmark.is_some() {
+            anyhow::bail!("fwmark is only supported on linux");
+        }
+        let proxy_sources = [
+            !self.proxy_addr.is_empty(),
+            self.proxy_srv.is_some(),
+            self.proxy_discovery.is_some(),
+        ];
+        if proxy_sources.iter().filter(|&&given| given).count() > 1 {
+            anyhow::bail!(
+                "give only one of proxy addresses, a proxy srv name or a proxy discovery for listener {}",
+                name
+            );
+        }
+        // accounts of their own would be replaced by the random ones
+        let own_credentials = self
+            .proxy_addr
+            .iter()
+            .chain(self.source_route.iter().map(|route| &route.target))
+            .any(|address| address.contains('@'));
+        if self.isolate_streams
+            && (self.proxy_protocol != ProxyProtocol::Socks5
+                || self.proxy_user.is_some()
+                || self.proxy_user_file.is_some()
+                || self.proxy_pass.is_some()
+                || self.proxy_pass_file.is_some()
+                || self.credentials_file.is_some()
+                || own_credentials)
+        {
+            anyhow::bail!(
+                "stream isolation needs a socks5 proxy without credentials for listener {}",
+                name
+            );
+        }
+        // streams multiplexed on one proxy connection share its circuit
+        if self.isolate_streams && self.mux.is_some() {
+            anyhow::bail!(
+                "stream isolation is not supported with mux for listener {}",
+                name
+            );
+        }
+        if self.proxy_gssapi {
+            if !cfg!(feature = "gssapi") {
+                anyhow::bail!("gssapi support is not built in, rebuild with the gssapi feature");
+            }
+            if self.proxy_protocol != ProxyProtocol::Socks5
+                || self.isolate_streams
+                || self.proxy_user.is_some()
+                || self.proxy_user_file.is_some()
+                || self.proxy_pass.is_some()
+                || self.proxy_pass_file.is_some()
+                || self.credentials_file.is_some()
+                || own_credentials
+            {
+                anyhow::bail!(
+                    "gssapi needs a socks5 proxy without other credentials for listener {}",
+                    name
+                );
+            }
+        }
+        if self.proxy_resolve_interval == Some(0) {
+            anyhow::bail!(
+                "proxy resolve interval must be positive for listener {}",
+                name
+            );
+        }
+        if self.doh.is_some() && self.resolve != Resolve::Local {
+            anyhow::bail!("doh needs local resolution for listener {}", name);
+        }
+        for dscp in self.inbound_dscp.iter().chain(self.outbound_dscp.iter()) {
+            if !cfg!(unix) {
+                anyhow::bail!("dscp is only supported on unix");
+            }
+            if *dscp > 63 {
+                anyhow::bail!("dscp {} is out of range 0-63", dscp);
+            }
+        }
+        if !cfg!(target_os = "linux")
+            && targets
+                .iter()
+                .any(|target| matches!(target, Target::Original | Target::Local))
+        {
+            anyhow::bail!("transparent proxy is only supported on linux");
+        }
+        if self.udp && !fixed_target {
+            anyhow::bail!("udp forwarding needs a fixed target for listener {}", name);
+        }
+        if self.dns && (self.udp || !fixed_target || self.demux) {
+            anyhow::bail!(
+                "dns needs the fixed target of a resolver, without udp forwarding or demux for listener {}",
+                name
+            );
+        }
+        if self.udp
+            && has_proxy
+            && (self.proxy_protocol != ProxyProtocol::Socks5
+                || self.proxy_tls
+                || self.proxy_gssapi
+                || !self.proxy_chain.is_empty())
+        {
+            anyhow::bail!(
+                "udp forwarding through proxy needs a single plain socks5 for listener {}",
+                name
+            );
+        }
+        if self.socks5_bind_advertise.is_some() && !self.socks5_bind {
+            anyhow::bail!(
+                "socks5 bind advertise needs socks5 bind for listener {}",
+                name
+            );
+        }
+        if self.socks5_bind {
+            if !targets
+                .iter()
+                .all(|target| matches!(target, Target::Socks5))
+            {
+                anyhow::bail!("socks5 bind needs a socks5 listener for listener {}", name);
+            }
+            if self.mux.is_some() {
+                anyhow::bail!(
+                    "socks5 bind is not supported with mux for listener {}",
+                    name
+                );
+            }
+            if has_proxy
+                && (self.proxy_protocol != ProxyProtocol::Socks5
+                    || self.proxy_tls
+                    || self.proxy_gssapi
+                    || !self.proxy_chain.is_empty())
+            {
+                anyhow::bail!(
+                    "socks5 bind through proxy needs a single plain socks5 for listener {}",
+                    name
+                );
+            }
+        }
+        if self.mux.is_some() && self.demux {
+            anyhow::bail!(
+                "mux and demux are exclusive, use them on separate forwarders for listener {}",
+                name
+            );
+        }
+        if (self.mux.is_some() || self.demux) && !fixed_target {
+            anyhow::bail!("mux and demux need a fixed target for listener {}", name);
+        }
+        if self.prewarm_handshake && (!fixed_target || targets.len() > 1) {
+            anyhow::bail!(
+                "prewarm handshake needs a single fixed target for listener {}",
+                name
+            );
+        }
+        if self.target_tls && self.target_tls_sni.is_none() && !fixed_target {
+            anyhow::bail!(
+                "target_tls_sni is needed without a fixed target for listener {}",
+                name
+            );
+        }
+        let tls = match (self.tls_cert.as_ref(), self.tls_key.as_ref()) {
+            (Some(cert), Some(key)) => Some(TlsServer::new(
+                cert,
+                key,
+                self.tls_client_ca.as_deref(),
+                self.alpn_route,
+            )?),
+            (None, None) if self.tls_client_ca.is_some() || !self.alpn_route.is_empty() => {
+                anyhow::bail!(
+                    "tls_client_ca and alpn_route need tls termination for listener {}",
+                    name
+                )
+            }
+            (None, None) => None,
+            _ => anyhow::bail!(
+                "tls_cert and tls_key must be set together for listener {}",
+                name
+            ),
+        };
+        let acl = Acl {
+            allow: self
+                .allow
+                .iter()
+                .map(|cidr| Cidr::parse(cidr))
+                .collect::<anyhow::Result<_>>()?,
+            deny: self
+                .deny
+                .iter()
+                .map(|cidr| Cidr::parse(cidr))
+                .collect::<anyhow::Result<_>>()?,
+            geoip: match self.geoip_db.as_ref() {
+                Some(path) => Some(Arc::new(GeoIp::open(path)?)),
+                None => None,
+            },
+            allow_countries: self
+                .allow_country
+                .iter()
+                .map(|code| code.to_ascii_uppercase())
+                .collect(),
+            deny_countries: self
+                .deny_country
+                .iter()
+                .map(|code| code.to_ascii_uppercase())
+                .collect(),
+        };
+        let keepalive = if self.no_keepalive {
+            None
+        } else if self.keepalive_idle == 0 || self.keepalive_interval == Some(0) {
+            anyhow::bail!("tcp keepalive times must be positive, use no_keepalive to disable it");
+        } else {
+            let time = Duration::from_secs(self.keepalive_idle);
+            Some(Keepalive {
+                time,
+                interval: self
+                    .keepalive_interval
+                    .map(Duration::from_secs)
+                    .unwrap_or(time),
+                retries: self.keepalive_count,
+            })
+        };
+        let outbound = Outbound {
+            bind: self.outbound_bind,
+            mark: self.fwmark,
+            dscp: self.outbound_dscp,
+            keepalive,
+            nodelay: self.nodelay,
+            connect_timeout: if self.connect_timeout == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(self.connect_timeout))
+            },
+        };
+        let handshake_timeout = if self.handshake_timeout == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.handshake_timeout))
+        };
+        if self.credentials_file.is_some()
+            && (self.proxy_user.is_some()
+                || self.proxy_pass.is_some()
+                || self.proxy_user_file.is_some()
+                || self.proxy_pass_file.is_some())
+        {
+            anyhow::bail!(
+                "give either a credentials file or proxy user and pass for listener {}",
+                name
+            );
+        }
+        let proxy_user = match self.proxy_user_file.as_deref() {
+            Some(_) if self.proxy_user.is_some() => {
+                anyhow::bail!("give either proxy user or its file for listener {}", name)
+            }
+            Some(path) => Some(read_secret(path)?),
+            None => self.proxy_user,
+        };
+        let proxy_pass = match self.proxy_pass_file.as_deref() {
+            Some(_) if self.proxy_pass.is_some() => {
+                anyhow::bail!("give either proxy pass or its file for listener {}", name)
+            }
+            Some(path) => Some(read_secret(path)?),
+            None => self.proxy_pass,
+        };
+        let credential = match (proxy_user, proxy_pass, &self.credentials_file) {
+            (_, _, Some(path)) => Some(read_credentials(path)?),
+            (Some(u), Some(p), None) => Some((u, p)),
+            (Some(u), None, None) => Some((u, String::default())),
+            _ => None,
+        };
+        let chain = self
+            .proxy_chain
+            .iter()
+            .map(|s| ProxyConfig::hop(s))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let doh = match self.doh.as_deref() {
+            Some(url) => Some(Arc::new(Doh::new(url, outbound.clone())?)),
+            None => None,
+        };
+        let build: ProxyBuilder = {
+            let (proxy_tls, proxy_tls_sni) = (self.proxy_tls, self.proxy_tls_sni.clone());
+            let proxy_tls_ca = self.proxy_tls_ca.clone();
+            let (protocol, resolve) = (self.proxy_protocol, self.resolve);
+            let resolve_interval = self.proxy_resolve_interval;
+            let isolate_streams = self.isolate_streams;
+            let gssapi_service = self.proxy_gssapi.then(|| self.proxy_gssapi_service.clone());
+            let outbound = outbound.clone();
+            Arc::new(move |address: &str| -> anyhow::Result<ProxyConfig> {
+                // an account of its own, or the one of the rule
+                let (own_credential, address) = split_credential(address)?;
+                let credential = own_credential.or_else(|| credential.clone());
+                // ip addresses have nothing to re-resolve
+                let resolved = match resolve_interval {
+                    Some(interval) if address.parse::<std::net::SocketAddr>().is_err() => Some(
+                        Arc::new(CachedAddrs::new(address, Duration::from_secs(interval))),
+                    ),
+                    _ => None,
+                };
+                let tls = if proxy_tls {
+                    let server_name = match proxy_tls_sni.as_ref() {
+                        Some(server_name) => server_name.clone(),
+                        None => split_host_port(address)?.0.to_string(),
+                    };
+                    Some(TlsClient::new(proxy_tls_ca.as_deref(), &server_name)?)
+                } else {
+                    None
+                };
+                let gssapi = match gssapi_service.as_ref() {
+                    Some(service) => Some(format!("{}@{}", service, split_host_port(address)?.0)),
+                    None => None,
+                };
+                Ok(ProxyConfig {
+                    address: address.to_string(),
+                    protocol,
+                    credential,
+                    tls,
+                    chain: chain.clone(),
+                    outbound: outbound.clone(),
+                    handshake_timeout,
+                    resolve,
+                    doh: doh.clone(),
+                    resolved,
+                    isolate_streams,
+                    gssapi,
+                })
+            })
+        };
+        let mut proxies = Vec::with_capacity(self.proxy_addr.len());
+        for addr in self.proxy_addr {
+            let (address, weight) = parse_weighted(&addr)?;
+            proxies.push((build(&address)?, weight, 0));
+        }
+        let timeout = Duration::from_secs(self.health_check_timeout);
+        let health_check = self.health_check_interval.map(|interval| HealthCheck {
+            interval: Duration::from_secs(interval),
+            timeout,
+        });
+        let cooldown = Duration::from_secs(self.circuit_breaker_cooldown);
+        let circuit_breaker = self
+            .circuit_breaker_failures
+            .map(|failures| CircuitBreaker {
+                failures: failures.max(1),
+                cooldown,
+            });
+        let mut source_routes = Vec::with_capacity(self.source_route.len());
+        for route in &self.source_route {
+            let network = Cidr::parse(&route.key)?;
+            let proxy = if route.target == DIRECT_ROUTE {
+                None
+            } else {
+                let proxy = build(&route.target)?;
+                Some(Arc::new(ProxyPool::new(
+                    vec![(proxy, 1, 0)],
+                    self.balance,
+                    health_check,
+                    circuit_breaker,
+                    None,
+                    None,
+                )))
+            };
+            source_routes.push(SourceRoute { network, proxy });
+        }
+        let discovery = match (self.proxy_srv, self.proxy_discovery) {
+            (Some(srv_name), _) => {
+                let records = dns::lookup_srv(&srv_name)?;
+                if records.is_empty() {
+                    anyhow::bail!("no proxy in srv {} for listener {}", srv_name, name);
+                }
+                let discovery = Discovery {
+                    source: Source::Srv(srv_name),
+                    build,
+                    wait: records.iter().map(|r| r.ttl).min().unwrap_or_default(),
+                };
+                proxies = discovery.proxies(&discovery::srv_instances(&records))?;
+                Some(discovery)
+            }
+            // registries are watched once serving, the pool starts empty
+            (None, Some(source)) => Some(Discovery {
+                source: source.parse().map_err(|e: String| anyhow::anyhow!(e))?,
+                build,
+                wait: Duration::ZERO,
+            }),
+            (None, None) => None,
+        };
+        let proxy = if proxies.is_empty() && discovery.is_none() {
+            None
+        } else {
+            let prewarm_target = if self.prewarm_handshake {
+                targets[0].fixed().map(str::to_string)
+            } else {
+                None
+            };
+            let prewarm = if self.prewarm > 0 {
+                Some(Prewarm {
+                    size: self.prewarm,
+                    target: prewarm_target,
+                })
+            } else {
+                None
+            };
+            Some(Arc::new(ProxyPool::new(
+                proxies,
+                self.balance,
+                health_check,
+                circuit_breaker,
+                prewarm,
+                discovery,
+            )))
+        };
+
+        let mut rules = Vec::with_capacity(targets.len());
+        for (listen, target) in listens.into_iter().zip(targets) {
+            let target_tls = if self.target_tls {
+                let server_name = match (self.target_tls_sni.as_ref(), target.fixed()) {
+                    (Some(server_name), _) => server_name.clone(),
+                    (None, target) => split_host_port(target.unwrap_or_default())?.0.to_string(),
+                };
+                Some(TlsClient::new(self.target_tls_ca.as_deref(), &server_name)?)
+            } else {
+                None
+            };
+            let target_check = self.target_check_interval.map(|interval| {
+                let targets = target.fixed().map(str::to_string).into_iter();
+                TargetCheck::new(
+                    targets
+                        .chain(self.target_failover.iter().cloned())
+                        .collect(),
+                    Duration::from_secs(interval),
+                    Duration::from_secs(self.health_check_timeout),
+                )
+            });
+            let mut tags = self.statsd_tag.clone();
+            tags.push(format!("listener:{}", listen));
+            rules.push(Rule {
+                statsd_tags: Tags::new(&tags),
+                listen,
+                target,
+                target_failover: self.target_failover.clone(),
+                target_check,
+                proxy: proxy.clone(),
+                source_routes: source_routes.clone(),
+                fallback_direct: self.fallback_direct,
+                retry: Retry {
+                    retries: self.connect_retries,
+                    backoff: Duration::from_millis(self.retry_backoff),
+                },
+                mux: self.mux.map(MuxSessions::new),
+                demux: self.demux,
+                accept_proxy_protocol: self.accept_proxy_protocol,
+                tls: tls.clone(),
+                target_tls,
+                send_proxy_protocol: self.send_proxy_protocol,
+                udp: self.udp || self.dns,
+                dns: self.dns,
+                socks5_bind: self.socks5_bind.then(|| SocksBind {
+                    advertise: self.socks5_bind_advertise,
+                }),
+                v6only: self.v6only,
+                bind_device: self.bind_device.clone(),
+                outbound: outbound.clone(),
+                inbound_dscp: self.inbound_dscp,
+                keepalive,
+                nodelay: self.nodelay,
+                acceptors: self.acceptors,
+                backlog: self.backlog,
+                idle_timeout: self.idle_timeout.map(Duration::from_secs),
+                max_lifetime: self.max_lifetime.map(Duration::from_secs),
+                accept_timeout: (self.accept_timeout > 0)
+                    .then(|| Duration::from_secs(self.accept_timeout)),
+                rate_limit: self.rate_limit,
+                bandwidth: None,
+                quotas: None,
+                acl: acl.clone(),
+            });
+        }
+        Ok(rules)
+    }
+}
+
+/// Config file layout:
+///
+/// ```toml
+/// [[rules]]
+/// listen = ["127.0.0.1:8000", "127.0.0.1:8001"]
+/// target = ["1.1.1.1:443", "1.0.0.1:443"]
+/// proxy_addr = "10.0.0.1:8080"
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(default)]
+    rules: Vec<RuleOpts>,
+}
+
+pub(crate) fn load_rules<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<Rule>> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read config file {}", path.display()))?;
+    parse_rules(&content).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+/// Rules of the config file at `path`, or else of `opts` from the command
+/// line, loaded apart from the runtime since SRV lookups block.
+pub(crate) async fn load(opts: RuleOpts, path: Option<PathBuf>) -> anyhow::Result<Vec<Rule>> {
+    tokio::task::spawn_blocking(move || match path {
+        Some(path) => load_rules(path),
+        None => opts.into_rules(),
+    })
+    .await?
+}
+
+/// Options of the rules of a config file, before they are turned into
+/// rules.
+pub(crate) fn load_opts<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<RuleOpts>> {
+    let path = path.as_ref();
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("unable to read config file {}", path.display()))?;
+    parse_opts(&content).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+/// Rules of a config file, given its content.
+pub(crate) fn parse_rules(content: &str) -> anyhow::Result<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for opts in parse_opts(content)? {
+        rules.extend(opts.into_rules()?);
+    }
+    Ok(rules)
+}
+
+fn parse_opts(content: &str) -> anyhow::Result<Vec<RuleOpts>> {
+    let config: Config = toml::from_str(content).context("unable to parse config")?;
+    if config.rules.is_empty() {
+        anyhow::bail!("no rules found in config");
+    }
+    Ok(config.rules)
+}
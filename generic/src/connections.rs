@@ -0,0 +1,229 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::api::{self, Response};
+use crate::hooks::{CloseEvent, ConnectedEvent, Hooks};
+use crate::utils::Activity;
+
+static CONNECTIONS: Lazy<Mutex<HashMap<u64, Arc<Connection>>>> = Lazy::new(Default::default);
+
+tokio::task_local! {
+    static CURRENT: Arc<Connection>;
+}
+
+/// A connection being relayed, as far as it got.
+pub(crate) struct Connection {
+    id: u64,
+    listener: String,
+    start: Instant,
+    client: Mutex<Option<SocketAddr>>,
+    target: Mutex<Option<String>>,
+    activity: Mutex<Option<Arc<Activity>>>,
+    kill: Notify,
+    hooks: Option<Arc<dyn Hooks>>,
+}
+
+/// A connection as listed by the admin API.
+#[derive(Serialize)]
+pub(crate) struct Snapshot {
+    id: u64,
+    listener: String,
+    client: Option<SocketAddr>,
+    target: Option<String>,
+    age_secs: f64,
+    upload: u64,
+    download: u64,
+}
+
+/// Relay `fut` as connection `id`, listed until it finishes or is killed.
+pub(crate) async fn track<F>(
+    id: u64,
+    listener: &str,
+    client: Option<SocketAddr>,
+    hooks: Option<Arc<dyn Hooks>>,
+    fut: F,
+) -> anyhow::Result<()>
+where
+    F: Future<Output = anyhow::Result<()>>,
+{
+    let connection = Arc::new(Connection {
+        id,
+        listener: listener.to_string(),
+        start: Instant::now(),
+        client: Mutex::new(client),
+        target: Mutex::new(None),
+        activity: Mutex::new(None),
+        kill: Notify::new(),
+        hooks,
+    });
+    CONNECTIONS.lock().unwrap().insert(id, connection.clone());
+    let res = CURRENT
+        .scope(connection.clone(), async {
+            tokio::select! {
+                res = fut => res,
+                _ = connection.kill.notified() => {
+                    Err(anyhow::anyhow!("connection killed"))
+                }
+            }
+        })
+        .await;
+    CONNECTIONS.lock().unwrap().remove(&id);
+    if let Some(hooks) = connection.hooks.as_ref() {
+        let (upload, download) = connection.bytes();
+        let target = connection.target.lock().unwrap().clone();
+        hooks.on_close(&CloseEvent {
+            id,
+            listener,
+            client: *connection.client.lock().unwrap(),
+            target: target.as_deref(),
+            upload,
+            download,
+            duration: connection.start.elapsed(),
+            error: res.as_ref().err(),
+        });
+    }
+    res
+}
+
+impl Connection {
+    fn bytes(&self) -> (u64, u64) {
+        self.activity
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or((0, 0), |activity| activity.bytes())
+    }
+}
+
+fn with_current(f: impl FnOnce(&Connection)) {
+    let _ = CURRENT.try_with(|connection| f(connection));
+}
+
+/// The real client of the current connection, known from its PROXY header.
+pub(crate) fn set_client(client: SocketAddr) {
+    with_current(|connection| *connection.client.lock().unwrap() = Some(client));
+}
+
+pub(crate) fn set_target(target: &str) {
+    with_current(|connection| *connection.target.lock().unwrap() = Some(target.to_string()));
+}
+
+/// The current connection reached its target, through `proxy` if any.
+pub(crate) fn set_connected(target: &str, proxy: Option<&str>) {
+    with_current(|connection| {
+        if let Some(hooks) = connection.hooks.as_ref() {
+            hooks.on_connected(&ConnectedEvent {
+                id: connection.id,
+                target,
+                proxy,
+            });
+        }
+    });
+}
+
+/// Bytes of the current connection are read from `activity` once relaying.
+pub(crate) fn set_activity(activity: Arc<Activity>) {
+    with_current(|connection| *connection.activity.lock().unwrap() = Some(activity));
+}
+
+/// Connections being relayed, oldest first.
+pub(crate) fn list() -> Vec<Snapshot> {
+    let connections: Vec<_> = CONNECTIONS.lock().unwrap().values().cloned().collect();
+    let mut snapshots: Vec<_> = connections
+        .iter()
+        .map(|connection| {
+            let (upload, download) = connection.bytes();
+            Snapshot {
+                id: connection.id,
+                listener: connection.listener.clone(),
+                client: *connection.client.lock().unwrap(),
+                target: connection.target.lock().unwrap().clone(),
+                age_secs: connection.start.elapsed().as_secs_f64(),
+                upload,
+                download,
+            }
+        })
+        .collect();
+    snapshots.sort_by_key(|snapshot| snapshot.id);
+    snapshots
+}
+
+/// Close connection `id`, returns whether it was there.
+pub(crate) fn kill(id: u64) -> bool {
+    match CONNECTIONS.lock().unwrap().get(&id) {
+        Some(connection) => {
+            // a permit is stored even when the relay is not waiting yet
+            connection.kill.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Connections of one forwarder, from being accepted until relayed, so
+/// several forwarders in a process drain apart.
+#[derive(Default)]
+pub(crate) struct Relays {
+    ids: Mutex<HashSet<u64>>,
+}
+
+impl Relays {
+    /// Count connection `id` until the returned guard is dropped.
+    pub(crate) fn start(self: &Arc<Self>, id: u64) -> RelayGuard {
+        self.ids.lock().unwrap().insert(id);
+        RelayGuard {
+            relays: self.clone(),
+            id,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.ids.lock().unwrap().len()
+    }
+
+    /// Close every connection, returns how many there were.
+    pub(crate) fn kill_all(&self) -> usize {
+        let ids = self.ids.lock().unwrap();
+        for id in ids.iter() {
+            kill(*id);
+        }
+        ids.len()
+    }
+}
+
+pub(crate) struct RelayGuard {
+    relays: Arc<Relays>,
+    id: u64,
+}
+
+impl Drop for RelayGuard {
+    fn drop(&mut self) {
+        self.relays.ids.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Serve the admin API: `GET /connections` lists the connections being
+/// relayed and `DELETE /connections/<id>` kills one.
+pub(crate) async fn serve_admin(addr: SocketAddr) -> anyhow::Result<()> {
+    api::serve(addr, "admin api", |method, path| {
+        match (method, path.strip_prefix("/connections")) {
+            ("GET", Some("")) | ("GET", Some("/")) => match serde_json::to_string(&list()) {
+                Ok(body) => Response::ok("application/json", body),
+                Err(_) => Response::status("500 Internal Server Error"),
+            },
+            ("DELETE", Some(id)) => match id.trim_start_matches('/').parse() {
+                Ok(id) if kill(id) => Response::status("204 No Content"),
+                _ => Response::not_found(),
+            },
+            _ => Response::not_found(),
+        }
+    })
+    .await
+}
@@ -0,0 +1,75 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// Detach from the terminal like a classic daemon: fork twice so no
+/// controlling terminal can be acquired again, and point the standard
+/// streams at /dev/null. Must run before any thread is started.
+pub(crate) fn daemonize() -> anyhow::Result<()> {
+    fork_and_exit_parent().context("unable to fork")?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error()).context("unable to create a new session");
+    }
+    fork_and_exit_parent().context("unable to fork")?;
+    // the working directory is kept, config, certificate and pid file
+    // paths given relative to it stay valid
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error()).context("unable to redirect stdio");
+        }
+    }
+    Ok(())
+}
+
+fn fork_and_exit_parent() -> io::Result<()> {
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        _ => std::process::exit(0),
+    }
+}
+
+/// PID file removed when dropped, on a clean exit.
+pub(crate) struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Write the pid of this process, refusing to if the one in an existing
+    /// file is still running, unless `upgrading` from it.
+    pub(crate) fn create(path: &Path, upgrading: bool) -> anyhow::Result<Self> {
+        if let Ok(content) = fs::read_to_string(path) {
+            if let Ok(pid) = content.trim().parse::<libc::pid_t>() {
+                if !upgrading && pid > 0 && unsafe { libc::kill(pid, 0) } == 0 {
+                    anyhow::bail!("already running with pid {}, see {}", pid, path.display());
+                }
+            }
+            // left behind by a process that is gone, or being upgraded
+            fs::remove_file(path)?;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(path)
+            .with_context(|| format!("unable to create pid file {}", path.display()))?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // the instance that upgraded this one owns the file now
+        let pid = std::process::id().to_string();
+        if fs::read_to_string(&self.0).map_or(false, |content| content.trim() == pid) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde_json::Value;
+use tokio::net::TcpStream;
+
+use crate::config::{parse_weighted, ProxyConfig};
+use crate::dns::{self, Srv};
+use crate::http_client;
+
+/// SRV answers are asked again no sooner, whatever their ttl.
+const SRV_MIN_REFRESH: Duration = Duration::from_secs(10);
+/// Time Consul holds a blocking query when nothing changes.
+const CONSUL_WAIT: Duration = Duration::from_secs(300);
+const ETCD_POLL_INTERVAL: Duration = Duration::from_secs(10);
+const ETCD_TIMEOUT: Duration = Duration::from_secs(5);
+/// Answers of a registry larger than this are refused.
+const MAX_ANSWER_SIZE: usize = 4 << 20;
+
+/// Builds the proxy of a discovered instance from its `host:port`, like the
+/// ones given in the rule.
+pub(crate) type ProxyBuilder = Arc<dyn Fn(&str) -> anyhow::Result<ProxyConfig> + Send + Sync>;
+
+/// Where the proxies of a rule are discovered, instead of listed.
+#[derive(Debug, Clone)]
+pub(crate) enum Source {
+    /// An SRV name, asked again once its answer expired.
+    Srv(String),
+    /// Passing instances of a Consul service, watched with blocking queries.
+    Consul { agent: String, service: String },
+    /// Keys under an etcd prefix, valued like `host:port` or
+    /// `host:port,weight=3`, polled.
+    Etcd { endpoint: String, prefix: String },
+}
+
+impl FromStr for Source {
+    type Err = String;
+
+    /// `consul://127.0.0.1:8500/service` or `etcd://127.0.0.1:2379/prefix`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid discovery {}, expect scheme://host:port/name", s);
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        let (addr, name) = match rest.split_once('/') {
+            Some((addr, name)) if !addr.is_empty() && !name.is_empty() => {
+                (addr.to_string(), name.to_string())
+            }
+            _ => return Err(invalid()),
+        };
+        match scheme {
+            "consul" => Ok(Source::Consul {
+                agent: addr,
+                service: name,
+            }),
+            "etcd" => Ok(Source::Etcd {
+                endpoint: addr,
+                // keys are looked up as given, with their leading slash
+                prefix: format!("/{}", name),
+            }),
+            _ => Err(format!("unknown discovery scheme {}", scheme)),
+        }
+    }
+}
+
+/// A proxy of the answer, with its weight and priority.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Instance {
+    pub(crate) address: String,
+    pub(crate) weight: u32,
+    pub(crate) priority: u16,
+}
+
+/// Proxies of a rule kept up to date from a source.
+#[derive(Clone)]
+pub(crate) struct Discovery {
+    pub(crate) source: Source,
+    pub(crate) build: ProxyBuilder,
+    /// Before asking the first time, the ttl of the SRV answer the pool
+    /// starts with.
+    pub(crate) wait: Duration,
+}
+
+impl fmt::Debug for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Discovery")
+            .field("source", &self.source)
+            .field("wait", &self.wait)
+            .finish()
+    }
+}
+
+impl fmt::Display for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            Source::Srv(name) => write!(f, "srv {}", name),
+            Source::Consul { agent, service } => {
+                write!(f, "consul service {} at {}", service, agent)
+            }
+            Source::Etcd { endpoint, prefix } => {
+                write!(f, "etcd prefix {} at {}", prefix, endpoint)
+            }
+        }
+    }
+}
+
+/// How far watching a source got, between answers.
+#[derive(Debug, Default)]
+pub(crate) struct Watch {
+    /// Before asking again.
+    wait: Duration,
+    /// Consul index of the last answer, blocking until it changes.
+    index: Option<u64>,
+}
+
+impl Watch {
+    pub(crate) fn new(wait: Duration) -> Self {
+        Self { wait, index: None }
+    }
+
+    /// Ask again from scratch after a failure.
+    pub(crate) fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl Discovery {
+    /// Proxies of `instances` paired with their weights and priorities.
+    pub(crate) fn proxies(
+        &self,
+        instances: &[Instance],
+    ) -> anyhow::Result<Vec<(ProxyConfig, u32, u16)>> {
+        instances
+            .iter()
+            .map(|instance| {
+                let proxy = (self.build)(&instance.address)?;
+                Ok((proxy, instance.weight, instance.priority))
+            })
+            .collect()
+    }
+
+    /// The instances once they may have changed.
+    pub(crate) async fn next(&self, watch: &mut Watch) -> anyhow::Result<Vec<Instance>> {
+        match &self.source {
+            Source::Srv(name) => {
+                tokio::time::sleep(watch.wait.max(SRV_MIN_REFRESH)).await;
+                let name = name.clone();
+                let records = tokio::task::spawn_blocking(move || dns::lookup_srv(&name)).await??;
+                watch.wait = records.iter().map(|r| r.ttl).min().unwrap_or_default();
+                Ok(srv_instances(&records))
+            }
+            Source::Consul { agent, service } => consul_instances(agent, service, watch).await,
+            Source::Etcd { endpoint, prefix } => {
+                tokio::time::sleep(watch.wait).await;
+                watch.wait = ETCD_POLL_INTERVAL;
+                etcd_instances(endpoint, prefix).await
+            }
+        }
+    }
+}
+
+pub(crate) fn srv_instances(records: &[Srv]) -> Vec<Instance> {
+    records
+        .iter()
+        .map(|srv| Instance {
+            address: format!("{}:{}", srv.target, srv.port),
+            // weight 0 is for instances seldom picked, as little as possible
+            weight: u32::from(srv.weight).max(1),
+            priority: srv.priority,
+        })
+        .collect()
+}
+
+/// ACL token of `CONSUL_HTTP_TOKEN`, or read from the file of
+/// `CONSUL_HTTP_TOKEN_FILE` like the consul cli does.
+fn consul_token() -> anyhow::Result<Option<String>> {
+    if let Ok(token) = std::env::var("CONSUL_HTTP_TOKEN") {
+        return Ok(Some(token));
+    }
+    match std::env::var("CONSUL_HTTP_TOKEN_FILE") {
+        Ok(path) => {
+            let token = std::fs::read_to_string(&path)
+                .with_context(|| format!("unable to read consul token file {}", path))?;
+            Ok(Some(token.trim().to_string()))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Passing instances of `service` from the Consul agent, blocking until
+/// they changed since the last answer.
+async fn consul_instances(
+    agent: &str,
+    service: &str,
+    watch: &mut Watch,
+) -> anyhow::Result<Vec<Instance>> {
+    let mut path = format!("/v1/health/service/{}?passing=true", service);
+    if let Some(index) = watch.index {
+        path.push_str(&format!("&index={}&wait={}s", index, CONSUL_WAIT.as_secs()));
+    }
+    let headers = match consul_token()? {
+        Some(token) => format!("X-Consul-Token: {}\r\n", token),
+        None => String::new(),
+    };
+    // a blocking query answers within the wait, give the agent some slack
+    let timeout = CONSUL_WAIT + CONSUL_WAIT / 16;
+    let response = tokio::time::timeout(timeout, request(agent, "GET", &path, &headers, &[]))
+        .await
+        .map_err(|_| anyhow::anyhow!("consul agent {} timed out", agent))??;
+    if response.status != 200 {
+        anyhow::bail!("consul agent {} answered status {}", agent, response.status);
+    }
+    let index = response
+        .header("x-consul-index")
+        .and_then(|index| index.parse::<u64>().ok());
+    // an index going backwards means the state was reset, start over
+    watch.index = match (watch.index, index) {
+        (Some(last), Some(index)) if index < last => None,
+        (_, index) => index,
+    };
+
+    let entries: Vec<Value> =
+        serde_json::from_slice(&response.body).context("invalid consul answer")?;
+    let mut instances = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let service = &entry["Service"];
+        // the address of the node when the service has none of its own
+        let host = match service["Address"].as_str() {
+            Some(address) if !address.is_empty() => address,
+            _ => entry["Node"]["Address"]
+                .as_str()
+                .context("consul instance without address")?,
+        };
+        let port = service["Port"]
+            .as_u64()
+            .context("consul instance without port")?;
+        let address = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        let weight = service["Weights"]["Passing"].as_u64().unwrap_or(1).max(1);
+        instances.push(Instance {
+            address,
+            weight: weight as u32,
+            priority: 0,
+        });
+    }
+    Ok(instances)
+}
+
+/// Instances under `prefix` from the etcd v3 json gateway.
+async fn etcd_instances(endpoint: &str, prefix: &str) -> anyhow::Result<Vec<Instance>> {
+    let mut range_end = prefix.as_bytes().to_vec();
+    // every key starting with the prefix, with its last byte incremented
+    if let Some(last) = range_end.last_mut() {
+        *last += 1;
+    }
+    let body = serde_json::json!({
+        "key": base64::encode(prefix),
+        "range_end": base64::encode(&range_end),
+    })
+    .to_string();
+    let headers = "Content-Type: application/json\r\n";
+    let response = tokio::time::timeout(
+        ETCD_TIMEOUT,
+        request(endpoint, "POST", "/v3/kv/range", headers, body.as_bytes()),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("etcd {} timed out", endpoint))??;
+    if response.status != 200 {
+        anyhow::bail!("etcd {} answered status {}", endpoint, response.status);
+    }
+    let answer: Value = serde_json::from_slice(&response.body).context("invalid etcd answer")?;
+    let mut instances = Vec::new();
+    for kv in answer["kvs"].as_array().into_iter().flatten() {
+        let value = kv["value"].as_str().unwrap_or_default();
+        let value = base64::decode(value).context("invalid etcd value")?;
+        let value = String::from_utf8(value).context("invalid etcd value")?;
+        let (address, weight) = parse_weighted(value.trim())?;
+        instances.push(Instance {
+            address,
+            weight,
+            priority: 0,
+        });
+    }
+    Ok(instances)
+}
+
+/// Plain http to the registry at `addr`, over a connection of its own.
+async fn request(
+    addr: &str,
+    method: &str,
+    path: &str,
+    headers: &str,
+    body: &[u8],
+) -> anyhow::Result<http_client::Response> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("unable to connect to {}", addr))?;
+    http_client::request(
+        &mut stream,
+        method,
+        addr,
+        path,
+        headers,
+        body,
+        MAX_ANSWER_SIZE,
+    )
+    .await
+}
@@ -0,0 +1,370 @@
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use anyhow::Context;
+
+/// DNS messages are at most this large.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 65535;
+/// Time to wait for the answer of a nameserver before trying the next.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+#[cfg(unix)]
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+pub(crate) const TYPE_A: u16 = 1;
+pub(crate) const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+/// A service instance of an SRV answer(RFC 2782).
+#[derive(Debug, Clone)]
+pub(crate) struct Srv {
+    /// Lower is preferred, higher ones are only used when those are down.
+    pub(crate) priority: u16,
+    /// Share of the connections among instances of the same priority.
+    pub(crate) weight: u16,
+    pub(crate) port: u16,
+    pub(crate) target: String,
+    pub(crate) ttl: Duration,
+}
+
+/// A query for `name` with recursion desired.
+pub(crate) fn encode_query(id: u16, name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(18 + name.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("invalid domain name {}", name);
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Addresses in the answer section, following CNAMEs is left to the
+/// recursive resolver which adds their records too.
+pub(crate) fn decode_addresses(message: &[u8]) -> anyhow::Result<Vec<IpAddr>> {
+    let mut ips = Vec::new();
+    for answer in answers(message)? {
+        let data = &message[answer.data..answer.data + answer.len];
+        match (answer.rtype, answer.len) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = data.try_into()?;
+                ips.push(IpAddr::from(octets));
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = data.try_into()?;
+                ips.push(IpAddr::from(octets));
+            }
+            _ => {}
+        }
+    }
+    Ok(ips)
+}
+
+fn decode_srv(message: &[u8]) -> anyhow::Result<Vec<Srv>> {
+    let mut records = Vec::new();
+    for answer in answers(message)? {
+        if answer.rtype != TYPE_SRV || answer.len < 7 {
+            continue;
+        }
+        let target = read_name(message, answer.data + 6)?;
+        // "." means the service is decidedly not available there
+        if target.is_empty() {
+            continue;
+        }
+        records.push(Srv {
+            priority: u16_at(message, answer.data)?,
+            weight: u16_at(message, answer.data + 2)?,
+            port: u16_at(message, answer.data + 4)?,
+            target,
+            ttl: Duration::from_secs(answer.ttl.into()),
+        });
+    }
+    Ok(records)
+}
+
+struct Answer {
+    rtype: u16,
+    ttl: u32,
+    /// Position of the record data in the message.
+    data: usize,
+    len: usize,
+}
+
+fn answers(message: &[u8]) -> anyhow::Result<Vec<Answer>> {
+    let rcode = u16_at(message, 2)? & 0x000f;
+    if rcode != 0 {
+        anyhow::bail!("response code {}", rcode);
+    }
+    let questions = u16_at(message, 4)?;
+    let count = u16_at(message, 6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos)? + 4;
+    }
+    let mut answers = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        pos = skip_name(message, pos)?;
+        let rtype = u16_at(message, pos)?;
+        let ttl = message.get(pos + 4..pos + 8).context("truncated message")?;
+        let ttl = u32::from_be_bytes(ttl.try_into()?);
+        let len = u16_at(message, pos + 8)? as usize;
+        let data = pos + 10;
+        if message.len() < data + len {
+            anyhow::bail!("truncated message");
+        }
+        answers.push(Answer {
+            rtype,
+            ttl,
+            data,
+            len,
+        });
+        pos = data + len;
+    }
+    Ok(answers)
+}
+
+fn u16_at(message: &[u8], pos: usize) -> anyhow::Result<u16> {
+    let bytes = message.get(pos..pos + 2).context("truncated message")?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Position after the name at `pos`, compressed or not.
+fn skip_name(message: &[u8], mut pos: usize) -> anyhow::Result<usize> {
+    loop {
+        let len = *message.get(pos).context("truncated message")? as usize;
+        match len {
+            0 => return Ok(pos + 1),
+            // a pointer ends the name
+            len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+            len => pos += 1 + len,
+        }
+    }
+}
+
+/// The name at `pos`, following compression pointers.
+fn read_name(message: &[u8], mut pos: usize) -> anyhow::Result<String> {
+    let mut labels = Vec::new();
+    // pointers only go backwards in valid messages, bounded against loops
+    for _ in 0..128 {
+        let len = *message.get(pos).context("truncated message")? as usize;
+        match len {
+            0 => return Ok(labels.join(".")),
+            len if len & 0xc0 == 0xc0 => {
+                pos = (u16_at(message, pos)? & 0x3fff) as usize;
+            }
+            len => {
+                let label = message
+                    .get(pos + 1..pos + 1 + len)
+                    .context("truncated message")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + len;
+            }
+        }
+    }
+    anyhow::bail!("name compression loop")
+}
+
+/// Service instances of the SRV name, like `_socks._tcp.example.com`, asked
+/// to the nameservers of the system. Blocks for up to a few seconds.
+pub(crate) fn lookup_srv(name: &str) -> anyhow::Result<Vec<Srv>> {
+    let id = rand::random::<u16>();
+    let query = encode_query(id, name, TYPE_SRV)?;
+    let mut last_error = None;
+    for server in nameservers()? {
+        let res = query_udp(server, id, &query).and_then(|message| {
+            // truncated, ask again over tcp for the whole answer
+            if message[2] & 0x02 != 0 {
+                query_tcp(server, id, &query)
+            } else {
+                Ok(message)
+            }
+        });
+        match res {
+            Ok(message) => {
+                return decode_srv(&message).with_context(|| format!("invalid answer for {}", name))
+            }
+            Err(e) => last_error = Some(e.context(format!("nameserver {}", server))),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| anyhow::anyhow!("no nameserver"))
+        .context(format!("unable to look up srv {}", name)))
+}
+
+/// Nameservers of resolv.conf, or the local one when it lists none like
+/// the system resolver does.
+#[cfg(unix)]
+fn nameservers() -> anyhow::Result<Vec<SocketAddr>> {
+    let conf = std::fs::read_to_string(RESOLV_CONF)
+        .with_context(|| format!("unable to read {}", RESOLV_CONF))?;
+    let servers: Vec<SocketAddr> = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|server| server.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
+    if servers.is_empty() {
+        return Ok(vec![([127, 0, 0, 1], 53).into()]);
+    }
+    Ok(servers)
+}
+
+#[cfg(not(unix))]
+fn nameservers() -> anyhow::Result<Vec<SocketAddr>> {
+    anyhow::bail!("srv lookups are only supported on unix")
+}
+
+fn query_udp(server: SocketAddr, id: u16, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect(server)?;
+    socket.send(query)?;
+    let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+    loop {
+        let len = socket.recv(&mut buf)?;
+        // answers to someone else's query are spoofed or late
+        if len >= 12 && u16_at(&buf, 0)? == id {
+            buf.truncate(len);
+            return Ok(buf);
+        }
+    }
+}
+
+fn query_tcp(server: SocketAddr, id: u16, query: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&server, QUERY_TIMEOUT)?;
+    stream.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len)?;
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message)?;
+    if message.len() < 12 || u16_at(&message, 0)? != id {
+        anyhow::bail!("answer to another query");
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An answer to the SRV query of `_socks._tcp.example.com` with the
+    /// records given as (priority, weight, port, encoded target).
+    fn srv_answer(records: &[(u16, u16, u16, &[u8])]) -> Vec<u8> {
+        let mut message = encode_query(0x1234, "_socks._tcp.example.com", TYPE_SRV).unwrap();
+        message[2] = 0x81;
+        message[3] = 0x80;
+        message[7] = records.len() as u8;
+        for (priority, weight, port, target) in records {
+            // the name of the question, at the end of the header
+            message.extend_from_slice(&[0xc0, 12]);
+            message.extend_from_slice(&TYPE_SRV.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&300u32.to_be_bytes());
+            message.extend_from_slice(&(6 + target.len() as u16).to_be_bytes());
+            message.extend_from_slice(&priority.to_be_bytes());
+            message.extend_from_slice(&weight.to_be_bytes());
+            message.extend_from_slice(&port.to_be_bytes());
+            message.extend_from_slice(target);
+        }
+        message
+    }
+
+    /// `proxy` followed by a pointer to `example.com` in the question.
+    const COMPRESSED_TARGET: &[u8] = b"\x05proxy\xc0\x18";
+
+    #[test]
+    fn decode_srv_follows_compression() {
+        let message = srv_answer(&[(10, 5, 1080, COMPRESSED_TARGET)]);
+        let records = decode_srv(&message).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].priority, 10);
+        assert_eq!(records[0].weight, 5);
+        assert_eq!(records[0].port, 1080);
+        assert_eq!(records[0].target, "proxy.example.com");
+        assert_eq!(records[0].ttl, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn decode_srv_skips_unavailable_service() {
+        let message = srv_answer(&[(0, 0, 0, b"\x00"), (10, 5, 1080, COMPRESSED_TARGET)]);
+        let records = decode_srv(&message).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].target, "proxy.example.com");
+    }
+
+    #[test]
+    fn truncated_messages_fail() {
+        let message = srv_answer(&[(10, 5, 1080, COMPRESSED_TARGET)]);
+        for len in 0..message.len() {
+            assert!(decode_srv(&message[..len]).is_err(), "read {} bytes", len);
+        }
+    }
+
+    #[test]
+    fn error_responses_fail() {
+        let mut message = srv_answer(&[(10, 5, 1080, COMPRESSED_TARGET)]);
+        // NXDOMAIN
+        message[3] |= 0x03;
+        assert!(decode_srv(&message).is_err());
+    }
+
+    #[test]
+    fn read_name_bounds_compression_loops() {
+        // a pointer to itself
+        assert!(read_name(&[0xc0, 0x00], 0).is_err());
+        // two pointers to each other
+        assert!(read_name(&[0xc0, 0x02, 0xc0, 0x00], 0).is_err());
+        // a pointer past the end
+        assert!(read_name(&[0xc0, 0x10], 0).is_err());
+        assert_eq!(
+            read_name(b"\x07example\x03com\x00\x03www\xc0\x00", 13).unwrap(),
+            "www.example.com"
+        );
+    }
+
+    #[test]
+    fn decode_addresses_of_both_families() {
+        let mut message = encode_query(1, "example.com", TYPE_A).unwrap();
+        message[2] = 0x81;
+        message[3] = 0x80;
+        message[7] = 2;
+        for (rtype, data) in [
+            (TYPE_A, &[192u8, 0, 2, 1][..]),
+            (
+                TYPE_AAAA,
+                &[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1][..],
+            ),
+        ]
+        .iter()
+        {
+            message.extend_from_slice(&[0xc0, 12]);
+            message.extend_from_slice(&rtype.to_be_bytes());
+            message.extend_from_slice(&CLASS_IN.to_be_bytes());
+            message.extend_from_slice(&60u32.to_be_bytes());
+            message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            message.extend_from_slice(data);
+        }
+        let ips = decode_addresses(&message).unwrap();
+        assert_eq!(
+            ips,
+            vec![
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "2001:db8::1".parse().unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::dns::{decode_addresses, encode_query, MAX_MESSAGE_SIZE, TYPE_A, TYPE_AAAA};
+use crate::http_client;
+use crate::outbound::Outbound;
+use crate::tls::TlsClient;
+use crate::utils::split_host_port;
+
+/// Time limit of a lookup, both queries included.
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves names with DNS-over-HTTPS(RFC 8484), queries are POSTed to the
+/// endpoint over a fresh tls connection each.
+#[derive(Debug)]
+pub(crate) struct Doh {
+    /// The endpoint as connected to, `host:port`.
+    authority: String,
+    host: String,
+    path: String,
+    tls: TlsClient,
+    outbound: Outbound,
+}
+
+impl Doh {
+    /// `url` is like `https://cloudflare-dns.com/dns-query`, its host is
+    /// resolved by the system resolver.
+    pub(crate) fn new(url: &str, outbound: Outbound) -> anyhow::Result<Self> {
+        let rest = url
+            .strip_prefix("https://")
+            .with_context(|| format!("doh endpoint {} is not an https url", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/dns-query"),
+        };
+        let (host, port) = if authority.ends_with(']') || !authority.contains(':') {
+            (authority.trim_start_matches('[').trim_end_matches(']'), 443)
+        } else {
+            split_host_port(authority)?
+        };
+        Ok(Self {
+            authority: format!("{}:{}", host, port),
+            host: host.to_string(),
+            path: path.to_string(),
+            tls: TlsClient::new(None, host)?,
+            outbound,
+        })
+    }
+
+    /// Addresses of `host:port`, ipv4 ones unless it has none.
+    pub(crate) async fn lookup(&self, addr: &str) -> anyhow::Result<Vec<SocketAddr>> {
+        let (host, port) = split_host_port(addr)?;
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let ips = tokio::time::timeout(DOH_TIMEOUT, async {
+            let ips = self.query(host, TYPE_A).await?;
+            if ips.is_empty() {
+                self.query(host, TYPE_AAAA).await
+            } else {
+                Ok(ips)
+            }
+        })
+        .await
+        .map_err(|_| anyhow::anyhow!("doh lookup of {} timed out", host))??;
+        if ips.is_empty() {
+            anyhow::bail!("{} resolved to no address with doh", host);
+        }
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    async fn query(&self, name: &str, qtype: u16) -> anyhow::Result<Vec<IpAddr>> {
+        // id 0 as recommended for caching
+        let message = encode_query(0, name, qtype)?;
+        let stream = self.outbound.connect(&self.authority).await?;
+        let mut stream = self.tls.connect(stream).await?;
+        let response = http_client::request(
+            &mut stream,
+            "POST",
+            &self.host,
+            &self.path,
+            "Content-Type: application/dns-message\r\nAccept: application/dns-message\r\n",
+            &message,
+            MAX_MESSAGE_SIZE,
+        )
+        .await?;
+        if response.status != 200 {
+            anyhow::bail!("doh endpoint answered status {}", response.status);
+        }
+        decode_addresses(&response.body).with_context(|| format!("invalid doh answer for {}", name))
+    }
+}
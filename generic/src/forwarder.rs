@@ -0,0 +1,789 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinHandle;
+use tracing::{field, Instrument};
+
+use crate::config::{self, Rule};
+use crate::connections::{self, Relays};
+use crate::hooks::{AcceptEvent, Hooks};
+use crate::limit::{AcceptRates, Limits, Permit};
+use crate::listener::{self, Accepted, Listener};
+use crate::metrics::metrics;
+use crate::privileges::Privileges;
+use crate::relay::{self, dispatch};
+use crate::resolver::{self, Resolver};
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+use crate::seccomp;
+#[cfg(windows)]
+use crate::service;
+#[cfg(unix)]
+use crate::utils::set_tcp_keepalive;
+use crate::utils::{is_fd_exhausted, unmap_addr};
+#[cfg(unix)]
+use crate::{handoff, sandbox};
+use crate::{health, pool, statsd, target_check, udp};
+
+/// Time relays in flight are given to finish on shutdown by default.
+const DEFAULT_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+/// Forwarding rules, in the format of the config file.
+pub struct Config {
+    rules: Vec<Rule>,
+    /// Where the rules are reloaded from on SIGHUP.
+    path: Option<PathBuf>,
+}
+
+impl Config {
+    /// Load the rules of a config file, reloaded from it on SIGHUP when
+    /// handling signals. Blocks while SRV names of proxies are looked up.
+    pub fn load<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        Ok(Self::from_rules(
+            config::load_rules(path)?,
+            Some(path.to_path_buf()),
+        ))
+    }
+
+    /// Parse rules written like in a config file.
+    pub fn from_toml(content: &str) -> anyhow::Result<Self> {
+        Ok(Self::from_rules(config::parse_rules(content)?, None))
+    }
+
+    pub(crate) fn from_rules(rules: Vec<Rule>, path: Option<PathBuf>) -> Self {
+        Self { rules, path }
+    }
+}
+
+/// Serves the rules of a config until shut down, then lets relays in flight
+/// finish before returning.
+pub struct Forwarder {
+    config: Config,
+    grace: Duration,
+    signals: bool,
+    pub(crate) limits: Arc<Limits>,
+    pub(crate) upgrade_socket: Option<PathBuf>,
+    pub(crate) privileges: Option<Privileges>,
+    /// Changed to once the listeners are bound, where Landlock is missing.
+    #[cfg(unix)]
+    pub(crate) chroot: Option<PathBuf>,
+    pub(crate) seccomp: bool,
+    hooks: Option<Arc<dyn Hooks>>,
+    resolver: Option<Arc<dyn Resolver>>,
+}
+
+impl Forwarder {
+    pub fn new(config: Config) -> Self {
+        let unlimited = Limits::new(None, None, AcceptRates::default(), None, None)
+            .expect("no limit is always valid");
+        Self {
+            config,
+            grace: DEFAULT_SHUTDOWN_GRACE,
+            signals: false,
+            limits: Arc::new(unlimited),
+            upgrade_socket: None,
+            privileges: None,
+            #[cfg(unix)]
+            chroot: None,
+            seccomp: false,
+            hooks: None,
+            resolver: None,
+        }
+    }
+
+    /// How long relays in flight are given to finish on shutdown before
+    /// being closed, 30 seconds by default.
+    pub fn shutdown_grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    /// Shut down on SIGTERM or SIGINT(Ctrl-C) and reload the config file on
+    /// SIGHUP. Off by default, signals are left to the program.
+    pub fn handle_signals(mut self, handle: bool) -> Self {
+        self.signals = handle;
+        self
+    }
+
+    /// Call `hooks` on the lifecycle of every connection.
+    pub fn hooks<H: Hooks>(mut self, hooks: H) -> Self {
+        self.hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Resolve proxies and targets connected to directly with `resolver`
+    /// instead of the system one. It is shared by the whole process, set
+    /// once the forwarder starts.
+    pub fn resolver<R: Resolver>(mut self, resolver: R) -> Self {
+        self.resolver = Some(Arc::new(resolver));
+        self
+    }
+
+    /// Listen on every rule and relay connections until shut down.
+    pub async fn run(self) -> anyhow::Result<()> {
+        self.start().await?.drained().await
+    }
+
+    /// Listen on every rule, returning once bound while connections are
+    /// relayed in the background until shut down.
+    pub async fn start(mut self) -> anyhow::Result<ServeHandle> {
+        if let Some(resolver) = self.resolver.take() {
+            resolver::set(resolver);
+        }
+        let rules = std::mem::take(&mut self.config.rules);
+        let mut bound = Vec::with_capacity(rules.len());
+        for rule in rules {
+            bound.push(bind_rule(rule, &self.limits).await?);
+        }
+        let local_addrs = bound
+            .iter()
+            .flat_map(|bound| bound.listeners.iter().filter_map(Listener::local_addr))
+            .collect();
+        let stop = Arc::new(Notify::new());
+        let task = tokio::spawn(serve(self, bound, stop.clone()));
+        Ok(ServeHandle {
+            local_addrs,
+            stop,
+            task,
+        })
+    }
+}
+
+/// A running forwarder, shut down with [`ServeHandle::shutdown`] or a
+/// signal.
+pub struct ServeHandle {
+    local_addrs: Vec<SocketAddr>,
+    stop: Arc<Notify>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl ServeHandle {
+    /// Addresses the tcp listeners are bound to, in the order of the rules,
+    /// with the port picked by the system for port 0.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// Stop accepting and start draining the relays in flight.
+    pub fn shutdown(&self) {
+        // a permit is stored when shut down before the forwarder waits
+        self.stop.notify_one();
+    }
+
+    /// Resolves once shut down and every relay is drained, or the forwarder
+    /// failed.
+    pub async fn drained(self) -> anyhow::Result<()> {
+        self.task.await?
+    }
+}
+
+type RuleSender = watch::Sender<Arc<Rule>>;
+/// Where listeners report failing, which stops the forwarder.
+type Failures = mpsc::UnboundedSender<anyhow::Error>;
+
+async fn serve(
+    forwarder: Forwarder,
+    bound: Vec<BoundRule>,
+    stop: Arc<Notify>,
+) -> anyhow::Result<()> {
+    let Forwarder {
+        config,
+        grace,
+        signals,
+        limits,
+        upgrade_socket,
+        privileges,
+        #[cfg(unix)]
+        chroot,
+        seccomp,
+        hooks,
+        resolver: _,
+    } = forwarder;
+    #[cfg(unix)]
+    handoff::close_unused();
+    #[cfg(unix)]
+    let upgrade_socket = upgrade_socket
+        .as_deref()
+        .map(handoff::listen_upgrades)
+        .transpose()?;
+    // the config is loaded and the listeners are bound from the real root
+    #[cfg(unix)]
+    if let Some(root) = chroot {
+        sandbox::chroot(&root)?;
+        tracing::info!(
+            "Filesystem access restricted with chroot to {}",
+            root.display()
+        );
+    }
+    // privileged ports are bound, nothing is accepted as root
+    if let Some(privileges) = privileges {
+        privileges.drop_to()?;
+    }
+    // the user upgrades are accepted from, geteuid is not allowed past seccomp
+    #[cfg(unix)]
+    let upgrade_socket = upgrade_socket.map(|listener| (listener, unsafe { libc::geteuid() }));
+    // setuid and friends are not allowed past this point
+    if seccomp {
+        install_seccomp()?;
+    }
+    let (failures, mut failed) = mpsc::unbounded_channel();
+    let relays = Arc::new(Relays::default());
+    let mut listeners = HashMap::with_capacity(bound.len());
+    for bound in bound {
+        let listen = bound.rule.listen.clone();
+        let tx = start_listener(bound, &limits, &hooks, &relays, &failures);
+        listeners.insert(listen, tx);
+    }
+    health::set_listening(true);
+
+    let serving = relays.clone();
+    let run = async move {
+        match config.path.filter(|_| signals) {
+            Some(path) => reload_on_hangup(path, listeners, limits, hooks, serving, failures).await,
+            None => {
+                // nothing to reload, listeners run until shutdown
+                let _listeners = listeners;
+                std::future::pending::<()>().await;
+                Ok(())
+            }
+        }
+    };
+    // the listeners go along with their rule senders once `run` is dropped
+    tokio::select! {
+        res = run => return res,
+        // a dead listener would leave the process serving nothing
+        Some(e) = failed.recv() => return Err(e),
+        res = shutdown_signal(), if signals => res?,
+        res = upgrade_requested(upgrade_socket) => res?,
+        _ = stop.notified() => tracing::info!("Shutdown requested"),
+    }
+    health::set_listening(false);
+    drain(grace, &relays).await;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("Received SIGTERM, shutting down"),
+        res = tokio::signal::ctrl_c() => {
+            res?;
+            tracing::info!("Received SIGINT, shutting down");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(windows)]
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res?,
+        _ = service::stopped() => {
+            tracing::info!("Service stop requested, shutting down");
+            return Ok(());
+        }
+    }
+    #[cfg(not(windows))]
+    tokio::signal::ctrl_c().await?;
+    tracing::info!("Received Ctrl-C, shutting down");
+    Ok(())
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn install_seccomp() -> anyhow::Result<()> {
+    seccomp::install()?;
+    tracing::info!("Seccomp filter installed");
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn install_seccomp() -> anyhow::Result<()> {
+    anyhow::bail!("seccomp is only supported on linux x86_64 and aarch64")
+}
+
+/// Resolves once the listening sockets are handed to a new instance.
+#[cfg(unix)]
+async fn upgrade_requested(
+    listener: Option<(tokio::net::UnixListener, libc::uid_t)>,
+) -> anyhow::Result<()> {
+    match listener {
+        Some((listener, uid)) => {
+            handoff::serve_upgrades(listener, uid).await?;
+            tracing::info!("Upgraded, shutting down");
+            Ok(())
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn upgrade_requested(path: Option<PathBuf>) -> anyhow::Result<()> {
+    if path.is_some() {
+        tracing::warn!(
+            "Upgrading with listening sockets handoff is not supported on this platform"
+        );
+    }
+    std::future::pending().await
+}
+
+/// Give relays in flight up to `grace` to finish, then close the rest.
+async fn drain(grace: Duration, relays: &Relays) {
+    let active = relays.len();
+    if active > 0 {
+        tracing::info!(
+            "Waiting up to {}s for {} relays to finish",
+            grace.as_secs(),
+            active
+        );
+    }
+    if !wait_drained(grace, relays).await {
+        let killed = relays.kill_all();
+        tracing::warn!("Grace period over, closing {} relays", killed);
+        wait_drained(DRAIN_KILL_TIMEOUT, relays).await;
+    }
+    tracing::info!("Shutdown complete");
+}
+
+/// Time killed relays are given to close their sockets.
+const DRAIN_KILL_TIMEOUT: Duration = Duration::from_secs(1);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wait up to `timeout` for no relay to be active, returns whether none is.
+async fn wait_drained(timeout: Duration, relays: &Relays) -> bool {
+    let deadline = Instant::now() + timeout;
+    while relays.len() > 0 {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    true
+}
+
+async fn spawn_listener(
+    rule: Rule,
+    limits: &Arc<Limits>,
+    hooks: &Option<Arc<dyn Hooks>>,
+    relays: &Arc<Relays>,
+    failures: &Failures,
+) -> anyhow::Result<RuleSender> {
+    Ok(start_listener(
+        bind_rule(rule, limits).await?,
+        limits,
+        hooks,
+        relays,
+        failures,
+    ))
+}
+
+/// A rule with its sockets bound, not accepting yet.
+struct BoundRule {
+    rule: Rule,
+    listeners: Vec<Listener>,
+    udp_socket: Option<UdpSocket>,
+}
+
+async fn bind_rule(mut rule: Rule, limits: &Arc<Limits>) -> anyhow::Result<BoundRule> {
+    limits.apply(&mut rule);
+    tracing::info!("Listening at {}", rule.listen);
+    for pool in rule.pools() {
+        for proxy in pool.proxies() {
+            tracing::info!("Will use socks proxy {} for {}", proxy.address, rule.listen);
+        }
+    }
+    let listeners = listener::bind_listeners(&rule).await?;
+    let udp_socket = if rule.udp {
+        Some(listener::bind_udp(&rule).await?)
+    } else {
+        None
+    };
+    Ok(BoundRule {
+        rule,
+        listeners,
+        udp_socket,
+    })
+}
+
+fn start_listener(
+    bound: BoundRule,
+    limits: &Arc<Limits>,
+    hooks: &Option<Arc<dyn Hooks>>,
+    relays: &Arc<Relays>,
+    failures: &Failures,
+) -> RuleSender {
+    let BoundRule {
+        rule,
+        listeners,
+        udp_socket,
+    } = bound;
+    for pool in rule.pools() {
+        pool::spawn_background(pool);
+    }
+    let listen = rule.listen.clone();
+    let rule = Arc::new(rule);
+    target_check::spawn(&rule);
+    let (tx, rx) = watch::channel(rule);
+    if let Some(socket) = udp_socket {
+        let rx = rx.clone();
+        let failures = failures.clone();
+        let listen = listen.clone();
+        #[cfg(unix)]
+        let registration = {
+            use std::os::unix::io::AsRawFd;
+            handoff::register_udp(&listen, socket.as_raw_fd())
+        };
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let _registration = registration;
+            if let Err(e) = udp::serve_udp(socket, rx).await {
+                tracing::error!("Udp listener failed: {}", e);
+                let _ = failures.send(e.context(format!("udp listener {} failed", listen)));
+            }
+        });
+    }
+    for listener in listeners {
+        let rx = rx.clone();
+        let limits = limits.clone();
+        let hooks = hooks.clone();
+        let relays = relays.clone();
+        let failures = failures.clone();
+        let listen = listen.clone();
+        // offered to the next instance on upgrade while the listener lives
+        #[cfg(unix)]
+        let registration = listener.raw_fd().map(|fd| handoff::register(&listen, fd));
+        tokio::spawn(async move {
+            #[cfg(unix)]
+            let _registration = registration;
+            if let Err(e) = serve_rule(listener, rx, limits, hooks, relays).await {
+                tracing::error!("Listener failed: {}", e);
+                let _ = failures.send(e.context(format!("listener {} failed", listen)));
+            }
+        });
+    }
+    tx
+}
+
+/// Reload the config file on SIGHUP. Listeners still in the config pick up
+/// their new rule for new connections, relays in flight keep the old one.
+#[cfg(unix)]
+async fn reload_on_hangup(
+    path: PathBuf,
+    mut listeners: HashMap<String, RuleSender>,
+    limits: Arc<Limits>,
+    hooks: Option<Arc<dyn Hooks>>,
+    relays: Arc<Relays>,
+    failures: Failures,
+) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        tracing::info!("Reloading config file {}", path.display());
+        let loading = path.clone();
+        let rules = match tokio::task::spawn_blocking(move || config::load_rules(loading)).await? {
+            Ok(rules) => rules,
+            Err(e) => {
+                tracing::error!("Reload failed, keep the current config: {:#}", e);
+                continue;
+            }
+        };
+
+        let mut reloaded = HashMap::with_capacity(rules.len());
+        for rule in rules {
+            let listen = rule.listen.clone();
+            match listeners.remove(&listen) {
+                Some(tx) => {
+                    let mut rule = rule;
+                    limits.apply(&mut rule);
+                    for field in socket_changes(&tx.borrow(), &rule) {
+                        tracing::warn!(
+                            "{} of listener {} changed, the sockets bound keep the old one until restarted",
+                            field,
+                            listen
+                        );
+                    }
+                    for pool in rule.pools() {
+                        pool::spawn_background(pool);
+                    }
+                    let rule = Arc::new(rule);
+                    target_check::spawn(&rule);
+                    let _ = tx.send(rule);
+                    reloaded.insert(listen, tx);
+                }
+                None => match spawn_listener(rule, &limits, &hooks, &relays, &failures).await {
+                    Ok(tx) => {
+                        reloaded.insert(listen, tx);
+                    }
+                    Err(e) => tracing::error!("Unable to listen at {}: {}", listen, e),
+                },
+            }
+        }
+        // senders left behind belong to removed rules, dropping them stops the listeners
+        listeners = reloaded;
+    }
+    Ok(())
+}
+
+/// Options of `new` the sockets bound for `old` are stuck with, reloading
+/// only hands the accept loops the new rule.
+#[cfg(unix)]
+fn socket_changes(old: &Rule, new: &Rule) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    if old.udp != new.udp {
+        changed.push("udp");
+    }
+    if old.dns != new.dns {
+        changed.push("dns");
+    }
+    if old.acceptors != new.acceptors {
+        changed.push("acceptors");
+    }
+    if old.backlog != new.backlog {
+        changed.push("backlog");
+    }
+    if old.bind_device != new.bind_device {
+        changed.push("bind_device");
+    }
+    if old.v6only != new.v6only {
+        changed.push("v6only");
+    }
+    changed
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(
+    _path: PathBuf,
+    _listeners: HashMap<String, RuleSender>,
+    _limits: Arc<Limits>,
+    _hooks: Option<Arc<dyn Hooks>>,
+    _relays: Arc<Relays>,
+    _failures: Failures,
+) -> anyhow::Result<()> {
+    tracing::warn!("Config reloading is not supported on this platform");
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
+/// Pause of accepting after running out of file descriptors, for some to be
+/// released by finishing relays.
+const FD_EXHAUSTED_BACKOFF: Duration = Duration::from_millis(100);
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Tells apart the logs of connections relayed at the same time.
+fn next_connection_id() -> u64 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+type RelayFuture = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>>;
+
+/// Check the client `ip` against the acl and the per-ip limits of its
+/// connection, returns how long to hold it back with the country of the
+/// client, or `None` when it is rejected.
+fn admit(
+    rule: &Rule,
+    limits: &Limits,
+    permit: &mut Permit,
+    ip: IpAddr,
+) -> Option<(Duration, Option<String>)> {
+    let country = rule.acl.country(ip);
+    if !rule.acl.allows(ip, country.as_deref()) {
+        tracing::warn!("Client {} is not allowed, rejected", ip);
+        metrics().rejected();
+        statsd::count("connections.rejected", &rule.statsd_tags, 1);
+        return None;
+    }
+    if let Some(country) = country.as_deref() {
+        tracing::Span::current().record("country", &country);
+    }
+    match limits.admit(permit, ip) {
+        Some(delay) => Some((delay, country)),
+        None => {
+            metrics().rejected();
+            statsd::count("connections.rejected", &rule.statsd_tags, 1);
+            None
+        }
+    }
+}
+
+/// Relay a connection from a load balancer, admitting the client of its
+/// PROXY header rather than the balancer.
+async fn serve_proxied(
+    mut conn: TcpStream,
+    rule: Arc<Rule>,
+    bound: Option<SocketAddr>,
+    limits: Arc<Limits>,
+    mut permit: Option<Permit>,
+) -> anyhow::Result<()> {
+    let header = relay::read_client_header(&mut conn, &rule).await?;
+    if let (Some(addrs), Some(held)) = (header.as_ref(), permit.as_mut()) {
+        tracing::Span::current().record("client", &field::display(addrs.source));
+        match admit(&rule, &limits, held, addrs.source.ip()) {
+            Some((delay, _)) if !delay.is_zero() => tokio::time::sleep(delay).await,
+            Some(_) => {}
+            None => return Ok(()),
+        }
+    }
+    // counted against the client until the relay finishes
+    let _permit = permit;
+    relay::dispatch_from(conn, rule, bound, header).await
+}
+
+async fn serve_rule(
+    mut listener: Listener,
+    mut rules: watch::Receiver<Arc<Rule>>,
+    limits: Arc<Limits>,
+    hooks: Option<Arc<dyn Hooks>>,
+    relays: Arc<Relays>,
+) -> anyhow::Result<()> {
+    let listen = rules.borrow().listen.clone();
+    let bound = listener.local_addr();
+
+    loop {
+        tokio::select! {
+            (permit, res) = async {
+                // only accept what is allowed to be relayed
+                let permit = limits.acquire().await;
+                (permit, listener.accept().await)
+            } => match res {
+                Ok(conn) => {
+                    // taken by connections admitted only once their PROXY header is read
+                    let mut permit = Some(permit);
+                    let rule = rules.borrow().clone();
+                    let id = next_connection_id();
+                    let mut client = None;
+                    let span = tracing::info_span!(
+                        "connection",
+                        id,
+                        listener = %listen,
+                        client = field::Empty,
+                        country = field::Empty,
+                        target = field::Empty,
+                        proxy = field::Empty,
+                    );
+                    let mut delay = Duration::ZERO;
+                    let mut country = None;
+                    let tags = rule.statsd_tags.clone();
+                    let relay: RelayFuture = match conn {
+                        Accepted::Tcp(conn) => {
+                            if let Ok(peer) = conn.peer_addr() {
+                                let peer = unmap_addr(peer);
+                                span.record("client", &field::display(peer));
+                                client = Some(peer);
+                            }
+                            // the peer of proxied connections is only the load balancer
+                            let admitted = match (client, permit.as_mut()) {
+                                (Some(peer), Some(held)) if !rule.accept_proxy_protocol => {
+                                    match span.in_scope(|| admit(&rule, &limits, held, peer.ip())) {
+                                        Some((wait, known)) => {
+                                            delay = wait;
+                                            country = known;
+                                            true
+                                        }
+                                        None => false,
+                                    }
+                                }
+                                _ => true,
+                            };
+                            if !admitted {
+                                continue;
+                            }
+                            #[cfg(unix)]
+                            if let Some(keepalive) = rule.keepalive.as_ref() {
+                                // the peer may be gone already, which its relay will find
+                                if let Err(e) = set_tcp_keepalive(&conn, keepalive) {
+                                    span.in_scope(|| tracing::warn!("Unable to set keepalive: {}", e));
+                                }
+                            }
+                            if rule.accept_proxy_protocol {
+                                let limits = limits.clone();
+                                Box::pin(serve_proxied(conn, rule, bound, limits, permit.take()))
+                            } else {
+                                Box::pin(dispatch(conn, rule, bound))
+                            }
+                        }
+                        #[cfg(unix)]
+                        Accepted::Unix(conn) => Box::pin(relay::dispatch_unaddressed(conn, rule)),
+                        #[cfg(target_os = "linux")]
+                        Accepted::Vsock(conn) => Box::pin(relay::dispatch_unaddressed(conn, rule)),
+                    };
+                    if let Some(hooks) = hooks.as_ref() {
+                        let event = AcceptEvent { id, listener: &listen, client };
+                        if !hooks.on_accept(&event) {
+                            tracing::warn!(id, "Connection rejected by hooks");
+                            metrics().rejected();
+                            statsd::count("connections.rejected", &tags, 1);
+                            continue;
+                        }
+                    }
+                    metrics().accepted(country.as_deref());
+                    statsd::count("connections.accepted", &tags, 1);
+                    span.in_scope(|| tracing::info!("Receive new incoming connection"));
+                    let listener = listen.clone();
+                    let hooks = hooks.clone();
+                    // drained from now on, even while held back
+                    let draining = relays.start(id);
+                    tokio::spawn(async move {
+                        let relay = async move {
+                            // smooth out clients connecting faster than allowed
+                            if !delay.is_zero() {
+                                tokio::time::sleep(delay).await;
+                            }
+                            let active = metrics().relay_started();
+                            let start = Instant::now();
+                            let res = relay.await;
+                            statsd::timing("relay.duration", &tags, start.elapsed());
+                            drop(active);
+                            res
+                        };
+                        let tracked = connections::track(id, &listener, client, hooks, relay);
+                        if let Err(e) = tracked.await {
+                            tracing::error!(error = %e, "Relay failed");
+                        }
+                        drop(draining);
+                        drop(permit);
+                    }.instrument(span));
+                }
+                Err(e) if is_fd_exhausted(&e) => {
+                    tracing::error!(
+                        "Out of file descriptors accepting connections, raise the limit(ulimit -n) or lower --max-connections: {}",
+                        e
+                    );
+                    // the pending connection stays in the backlog, retrying at once would spin
+                    tokio::time::sleep(FD_EXHAUSTED_BACKOFF).await;
+                }
+                Err(e) => {
+                    tracing::error!("Receiving incoming connection in failure: {}", e);
+                }
+            },
+            res = rules.changed() => match res {
+                Ok(()) => tracing::info!("Rule for {} updated", listen),
+                Err(_) => {
+                    tracing::info!("Rule for {} removed, listener closed", listen);
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
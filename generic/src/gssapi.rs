@@ -0,0 +1,273 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::Context;
+use libgssapi::context::{ClientCtx, CtxFlags, SecurityContext};
+use libgssapi::credential::{Cred, CredUsage};
+use libgssapi::name::Name;
+use libgssapi::oid::{OidSet, GSS_MECH_KRB5, GSS_NT_HOSTBASED_SERVICE};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::utils::AsyncStream;
+
+pub(crate) const SOCKS5_AUTH_GSSAPI: u8 = 0x01;
+
+/// RFC 1961 message header: version, type and length of the token.
+const VERSION: u8 = 0x01;
+const MTYP_AUTH: u8 = 0x01;
+const MTYP_PROTECTION: u8 = 0x02;
+const MTYP_DATA: u8 = 0x03;
+const MTYP_ABORT: u8 = 0xff;
+const HEADER_SIZE: usize = 4;
+
+const PROTECTION_INTEGRITY: u8 = 0x01;
+const PROTECTION_CONFIDENTIALITY: u8 = 0x02;
+/// Plain bytes wrapped per message, their token stays within the u16 length.
+const MAX_CHUNK: usize = 16 * 1024;
+
+/// A security context established with the proxy, and whether the data it
+/// protects is encrypted or only integrity checked.
+pub(crate) struct Protection {
+    ctx: ClientCtx,
+    encrypt: bool,
+}
+
+/// Authenticate with Kerberos to the proxy as `service` (the host based
+/// service name like `rcmd@proxy.example.com`) once it selected GSSAPI, with
+/// the ticket of the credential cache. Confidentiality is asked for, the
+/// proxy may settle for integrity.
+pub(crate) async fn authenticate<S>(stream: &mut S, service: &str) -> anyhow::Result<Protection>
+where
+    S: AsyncStream,
+{
+    let mut ctx = client_ctx(service).context("gssapi initialization failed")?;
+    let mut input: Option<Vec<u8>> = None;
+    loop {
+        let output = ctx
+            .step(input.as_deref())
+            .context("gssapi context failed")?;
+        if let Some(output) = output {
+            write_message(stream, MTYP_AUTH, &output).await?;
+        }
+        if ctx.is_complete() {
+            break;
+        }
+        input = Some(read_message(stream, MTYP_AUTH).await?);
+    }
+
+    let request = ctx
+        .wrap(false, &[PROTECTION_CONFIDENTIALITY])
+        .context("gssapi wrap failed")?;
+    write_message(stream, MTYP_PROTECTION, &request).await?;
+    let reply = read_message(stream, MTYP_PROTECTION).await?;
+    let level = ctx.unwrap(&reply).context("gssapi unwrap failed")?;
+    let encrypt = match level.first() {
+        Some(&PROTECTION_CONFIDENTIALITY) => true,
+        Some(&PROTECTION_INTEGRITY) => false,
+        level => anyhow::bail!("proxy chose unsupported gssapi protection {:?}", level),
+    };
+    Ok(Protection { ctx, encrypt })
+}
+
+fn client_ctx(service: &str) -> Result<ClientCtx, libgssapi::error::Error> {
+    let mut mechs = OidSet::new()?;
+    mechs.add(&GSS_MECH_KRB5)?;
+    let cred = Cred::acquire(None, None, CredUsage::Initiate, Some(&mechs))?;
+    let name = Name::new(service.as_bytes(), Some(&GSS_NT_HOSTBASED_SERVICE))?
+        .canonicalize(Some(&GSS_MECH_KRB5))?;
+    Ok(ClientCtx::new(
+        cred,
+        name,
+        CtxFlags::GSS_C_MUTUAL_FLAG,
+        Some(&GSS_MECH_KRB5),
+    ))
+}
+
+async fn write_message<S>(stream: &mut S, mtyp: u8, token: &[u8]) -> anyhow::Result<()>
+where
+    S: AsyncStream,
+{
+    if token.len() > u16::MAX as usize {
+        anyhow::bail!("gssapi token too large");
+    }
+    let mut message = Vec::with_capacity(HEADER_SIZE + token.len());
+    message.extend_from_slice(&[VERSION, mtyp]);
+    message.extend_from_slice(&(token.len() as u16).to_be_bytes());
+    message.extend_from_slice(token);
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+async fn read_message<S>(stream: &mut S, mtyp: u8) -> anyhow::Result<Vec<u8>>
+where
+    S: AsyncStream,
+{
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    match head {
+        // no length follows an abort
+        [VERSION, MTYP_ABORT] => anyhow::bail!("proxy refused gssapi authentication"),
+        [VERSION, t] if t == mtyp => {}
+        _ => anyhow::bail!("invalid gssapi message {:?}", head),
+    }
+    let len = stream.read_u16().await?;
+    let mut token = vec![0u8; len as usize];
+    stream.read_exact(&mut token).await?;
+    Ok(token)
+}
+
+/// Socks5 messages and data after authentication, every write wrapped in a
+/// message of its own as RFC 1961 section 4 requires.
+pub(crate) struct GssStream<S> {
+    inner: S,
+    protection: Protection,
+    /// Unwrapped data not returned yet.
+    plain: Vec<u8>,
+    plain_pos: usize,
+    /// Message being read, header included.
+    message: Vec<u8>,
+    message_filled: usize,
+    /// Wrapped message not fully written yet.
+    pending: Vec<u8>,
+    pending_pos: usize,
+}
+
+impl<S> GssStream<S> {
+    pub(crate) fn new(inner: S, protection: Protection) -> Self {
+        Self {
+            inner,
+            protection,
+            plain: Vec::new(),
+            plain_pos: 0,
+            message: vec![0; HEADER_SIZE],
+            message_filled: 0,
+            pending: Vec::new(),
+            pending_pos: 0,
+        }
+    }
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> GssStream<S> {
+    fn poll_pending(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while self.pending_pos < self.pending.len() {
+            let n =
+                match Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_pos..]) {
+                    Poll::Ready(Ok(n)) => n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                };
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_pos += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for GssStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.plain_pos >= this.plain.len() {
+            // fill the header, then the token its length announces
+            let mut read = ReadBuf::new(&mut this.message[this.message_filled..]);
+            match Pin::new(&mut this.inner).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let n = read.filled().len();
+            if n == 0 {
+                return match this.message_filled {
+                    0 => Poll::Ready(Ok(())),
+                    _ => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                };
+            }
+            this.message_filled += n;
+            if this.message_filled < this.message.len() {
+                continue;
+            }
+            if this.message.len() == HEADER_SIZE {
+                if this.message[..2] != [VERSION, MTYP_DATA] {
+                    return Poll::Ready(Err(invalid_data("invalid gssapi data message")));
+                }
+                let len = u16::from_be_bytes([this.message[2], this.message[3]]) as usize;
+                this.message.resize(HEADER_SIZE + len, 0);
+                if len > 0 {
+                    continue;
+                }
+            }
+            let plain = this
+                .protection
+                .ctx
+                .unwrap(&this.message[HEADER_SIZE..])
+                .map_err(invalid_data)?;
+            this.plain = plain.to_vec();
+            this.plain_pos = 0;
+            this.message.truncate(HEADER_SIZE);
+            this.message_filled = 0;
+        }
+        let len = buf.remaining().min(this.plain.len() - this.plain_pos);
+        buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + len]);
+        this.plain_pos += len;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for GssStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let len = buf.len().min(MAX_CHUNK);
+        let token = this
+            .protection
+            .ctx
+            .wrap(this.protection.encrypt, &buf[..len])
+            .map_err(invalid_data)?;
+        if token.len() > u16::MAX as usize {
+            return Poll::Ready(Err(invalid_data("gssapi token too large")));
+        }
+        this.pending.clear();
+        this.pending.extend_from_slice(&[VERSION, MTYP_DATA]);
+        this.pending
+            .extend_from_slice(&(token.len() as u16).to_be_bytes());
+        this.pending.extend_from_slice(&token);
+        this.pending_pos = 0;
+        // accepted once wrapped, written out by the next calls if not now
+        let _ = this.poll_pending(cx)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_flush(cx),
+            other => other,
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut this.inner).poll_shutdown(cx),
+            other => other,
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::fs::Permissions;
+use std::io;
+use std::mem;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use tokio::net::UnixListener;
+
+/// Sockets passed in one message, the kernel refuses more.
+const MAX_FDS: usize = 253;
+const MAX_PAYLOAD: usize = 65536;
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Names of udp sockets, apart from the tcp listener on the same address.
+const UDP_PREFIX: &str = "udp:";
+
+/// Listening sockets of this instance, handed to the next one on upgrade.
+static REGISTERED: Lazy<Mutex<Vec<(u64, String, RawFd)>>> = Lazy::new(Default::default);
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(1);
+
+/// Sockets taken over from the previous instance and not used yet.
+static INHERITED: Lazy<Mutex<Vec<(String, RawFd)>>> = Lazy::new(Default::default);
+
+/// A socket offered to the next instance for as long as it is kept.
+pub(crate) struct Registration(u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        REGISTERED
+            .lock()
+            .unwrap()
+            .retain(|(token, _, _)| *token != self.0);
+    }
+}
+
+/// Offer the listening socket of `listen` to the next instance.
+pub(crate) fn register(listen: &str, fd: RawFd) -> Registration {
+    register_as(listen.to_string(), fd)
+}
+
+pub(crate) fn register_udp(listen: &str, fd: RawFd) -> Registration {
+    register_as(format!("{}{}", UDP_PREFIX, listen), fd)
+}
+
+fn register_as(name: String, fd: RawFd) -> Registration {
+    let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+    REGISTERED.lock().unwrap().push((token, name, fd));
+    Registration(token)
+}
+
+/// Take a listening socket of `listen` passed by the previous instance.
+pub(crate) fn take(listen: &str) -> Option<RawFd> {
+    take_named(listen)
+}
+
+pub(crate) fn take_udp(listen: &str) -> Option<RawFd> {
+    take_named(&format!("{}{}", UDP_PREFIX, listen))
+}
+
+fn take_named(name: &str) -> Option<RawFd> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let index = inherited.iter().position(|(n, _)| n == name)?;
+    Some(inherited.remove(index).1)
+}
+
+/// Sockets of the previous instance that no rule asked for, closed since
+/// nothing will accept on them.
+pub(crate) fn close_unused() {
+    for (name, fd) in INHERITED.lock().unwrap().drain(..) {
+        tracing::info!("Closing socket of {} not in the rules anymore", name);
+        unsafe { libc::close(fd) };
+    }
+}
+
+/// Take over the listening sockets of the instance serving upgrades at
+/// `path`, returns how many were passed. Nothing is taken if no instance
+/// is running.
+pub(crate) fn receive(path: &Path) -> anyhow::Result<usize> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                || e.kind() == io::ErrorKind::ConnectionRefused =>
+        {
+            return Ok(0)
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("unable to connect to {}", path.display()))
+        }
+    };
+    stream.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+    let (payload, fds) = recv_fds(stream.as_raw_fd()).context("unable to receive sockets")?;
+    let names = String::from_utf8(payload).context("invalid socket names")?;
+    let names: Vec<&str> = names.lines().collect();
+    if names.len() != fds.len() {
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+        anyhow::bail!("{} socket names for {} sockets", names.len(), fds.len());
+    }
+    let mut inherited = INHERITED.lock().unwrap();
+    for (name, fd) in names.into_iter().zip(fds) {
+        inherited.push((name.to_string(), fd));
+    }
+    Ok(inherited.len())
+}
+
+/// Listen at `path` for the next instance, before a chroot would hide it.
+pub(crate) fn listen_upgrades(path: &Path) -> anyhow::Result<UnixListener> {
+    // left behind by the previous instance, which already handed off
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("unable to listen at {}", path.display()))?;
+    std::fs::set_permissions(path, Permissions::from_mode(0o600))
+        .with_context(|| format!("unable to restrict {}", path.display()))?;
+    Ok(listener)
+}
+
+/// Wait for the next instance, then pass it every listening socket.
+/// Returns once handed off, the caller is then expected to drain. Only
+/// processes of `uid`, our effective one, or root which new instances
+/// dropping privileges still are, are handed the sockets.
+pub(crate) async fn serve_upgrades(listener: UnixListener, uid: libc::uid_t) -> anyhow::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        match stream.peer_cred() {
+            Ok(cred) if cred.uid() == uid || cred.uid() == 0 => {}
+            Ok(cred) => {
+                tracing::warn!(
+                    "Refused to hand off listening sockets to uid {}, not {}",
+                    cred.uid(),
+                    uid
+                );
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("Unable to check the upgrading process: {}", e);
+                continue;
+            }
+        }
+        let registered = REGISTERED.lock().unwrap().clone();
+        if registered.len() > MAX_FDS {
+            anyhow::bail!(
+                "too many listening sockets to hand off, at most {}",
+                MAX_FDS
+            );
+        }
+        let names: Vec<&str> = registered
+            .iter()
+            .map(|(_, name, _)| name.as_str())
+            .collect();
+        let fds: Vec<RawFd> = registered.iter().map(|(_, _, fd)| *fd).collect();
+        // a fresh connection has room for the message, no need to wait
+        match send_fds(stream.as_raw_fd(), names.join("\n").as_bytes(), &fds) {
+            Ok(()) => {
+                tracing::info!("Handed {} listening sockets to the new instance", fds.len());
+                // the new instance listens at the path from now on
+                return Ok(());
+            }
+            Err(e) => tracing::error!("Handing off listening sockets in failure: {}", e),
+        }
+    }
+}
+
+fn send_fds(socket: RawFd, payload: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let fds_len = (fds.len() * mem::size_of::<RawFd>()) as u32;
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = vec![0u64; (space + 7) / 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let sent = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        libc::sendmsg(socket, &msg, 0)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if sent as usize != payload.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "socket names partially sent",
+        ));
+    }
+    Ok(())
+}
+
+fn recv_fds(socket: RawFd) -> io::Result<(Vec<u8>, Vec<RawFd>)> {
+    let mut payload = vec![0u8; MAX_PAYLOAD];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let space = unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize;
+    let mut control = vec![0u64; (space + 7) / 8];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    let received = unsafe { libc::recvmsg(socket, &mut msg, 0) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned(data.add(i));
+                    // not inherited by processes the forwarder may run
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    fds.push(fd);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        for fd in fds {
+            unsafe { libc::close(fd) };
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many sockets passed",
+        ));
+    }
+    payload.truncate(received as usize);
+    Ok((payload, fds))
+}
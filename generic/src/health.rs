@@ -0,0 +1,30 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::api::{self, Response};
+use crate::pool;
+
+/// Set once every listener of the initial rules is bound, cleared when
+/// shutting down.
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_listening(listening: bool) {
+    LISTENING.store(listening, Ordering::Relaxed);
+}
+
+/// Serve `GET /healthz`, answering as long as the process runs, and
+/// `GET /readyz`, answering only once listening and while every rule with
+/// proxies has one usable. Proxies of rules without health checks are
+/// probed for it.
+pub(crate) async fn serve_health(addr: SocketAddr) -> anyhow::Result<()> {
+    pool::spawn_readiness_probes();
+    api::serve(addr, "health checks", |method, path| match (method, path) {
+        ("GET", "/healthz") => Response::ok("text/plain", "ok\n".to_string()),
+        ("GET", "/readyz") if LISTENING.load(Ordering::Relaxed) && pool::all_ready() => {
+            Response::ok("text/plain", "ready\n".to_string())
+        }
+        ("GET", "/readyz") => Response::status("503 Service Unavailable"),
+        _ => Response::not_found(),
+    })
+    .await
+}
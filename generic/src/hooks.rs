@@ -0,0 +1,58 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Callbacks on the lifecycle of every relayed connection, for auditing or
+/// policy of programs embedding the forwarder. Called on the runtime, so
+/// they are expected to return quickly.
+pub trait Hooks: Send + Sync + 'static {
+    /// A connection was accepted and allowed by the rule, returning false
+    /// rejects it before anything is read off it.
+    fn on_accept(&self, event: &AcceptEvent<'_>) -> bool {
+        let _ = event;
+        true
+    }
+
+    /// The connection to the target is established, through its proxy if
+    /// any.
+    fn on_connected(&self, event: &ConnectedEvent<'_>) {
+        let _ = event;
+    }
+
+    /// The relay ended, or the connection failed before relaying.
+    fn on_close(&self, event: &CloseEvent<'_>) {
+        let _ = event;
+    }
+}
+
+#[derive(Debug)]
+pub struct AcceptEvent<'a> {
+    /// Identifies the connection across its events.
+    pub id: u64,
+    pub listener: &'a str,
+    /// None when accepted on a unix or vsock socket.
+    pub client: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub struct ConnectedEvent<'a> {
+    pub id: u64,
+    pub target: &'a str,
+    /// Address of the proxy relayed through, None when connected directly.
+    pub proxy: Option<&'a str>,
+}
+
+#[derive(Debug)]
+pub struct CloseEvent<'a> {
+    pub id: u64,
+    pub listener: &'a str,
+    /// The real client when known from a PROXY header.
+    pub client: Option<SocketAddr>,
+    /// None when the connection failed before its target was known.
+    pub target: Option<&'a str>,
+    /// Bytes from the client to the target.
+    pub upload: u64,
+    /// Bytes from the target to the client.
+    pub download: u64,
+    pub duration: Duration,
+    pub error: Option<&'a anyhow::Error>,
+}
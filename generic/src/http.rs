@@ -0,0 +1,142 @@
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::config::{Route, Rule};
+use crate::proxy::MAX_HTTP_HEADER_SIZE;
+use crate::proxy_protocol::ProxiedAddrs;
+use crate::relay::{connect_target, transfer, with_accept_timeout};
+use crate::utils::{host_matches, split_host_port, PrefixedStream};
+
+/// Route plain HTTP connections by the Host header of their first request.
+#[derive(Debug, Clone)]
+pub(crate) struct HostRouting {
+    /// Keyed by host, which may be a `*.example.com` wildcard.
+    pub(crate) routes: Vec<Route>,
+    /// Port of hosts given without one.
+    pub(crate) port: u16,
+}
+
+impl HostRouting {
+    /// Target for `host` from a Host header. Without any route, the host
+    /// itself is the target.
+    pub(crate) fn target(&self, host: &str) -> Option<String> {
+        let (name, port) = match split_host_port(host) {
+            Ok((name, port)) => (name, port),
+            Err(_) => (
+                host.trim_start_matches('[').trim_end_matches(']'),
+                self.port,
+            ),
+        };
+        if self.routes.is_empty() {
+            return Some(if name.contains(':') {
+                format!("[{}]:{}", name, port)
+            } else {
+                format!("{}:{}", name, port)
+            });
+        }
+        // exact hosts take precedence over wildcards
+        self.routes
+            .iter()
+            .find(|route| !route.key.starts_with("*.") && host_matches(&route.key, name))
+            .or_else(|| {
+                self.routes
+                    .iter()
+                    .find(|route| host_matches(&route.key, name))
+            })
+            .map(|route| route.target.clone())
+    }
+}
+
+/// Serve a client speaking HTTP CONNECT to the listener, the requested
+/// destination is relayed through the upstream of the rule.
+pub(crate) async fn serve_connect<S>(
+    mut inbound: S,
+    addrs: ProxiedAddrs,
+    rule: &Arc<Rule>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (read, end) = with_accept_timeout(rule, read_request_header(&mut inbound)).await?;
+    let header = std::str::from_utf8(&read[..end])?;
+    let request_line = header.split("\r\n").next().unwrap_or_default();
+    let target = match request_line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["CONNECT", target, _] => target.to_string(),
+        _ => {
+            inbound
+                .write_all(b"HTTP/1.1 405 Method Not Allowed\r\n\r\n")
+                .await?;
+            anyhow::bail!("unsupported http request {}", request_line);
+        }
+    };
+    // bytes sent by the client right after its request belong to the tunnel
+    let early_data = read[end + 4..].to_vec();
+
+    tracing::info!("Relay {} to {}", addrs.source, target);
+    let (_lease, outbound) = match connect_target(addrs, rule, &target).await {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = inbound.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+            return Err(e);
+        }
+    };
+    inbound
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+    transfer(
+        PrefixedStream::new(early_data, inbound),
+        outbound,
+        addrs,
+        rule,
+        &target,
+    )
+    .await
+}
+
+/// Read the header of the first HTTP request on the stream, returns its Host
+/// header along with every byte read, which still has to be relayed.
+pub(crate) async fn read_host<S>(stream: &mut S) -> anyhow::Result<(Option<String>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let (read, end) = read_request_header(stream).await?;
+    let host = parse_host(&read[..end]);
+    Ok((host, read))
+}
+
+/// Read until the end of a request header, returns every byte read and where
+/// the header ends.
+pub(crate) async fn read_request_header<S>(stream: &mut S) -> anyhow::Result<(Vec<u8>, usize)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut read = Vec::new();
+    let mut buf = [0u8; 1024];
+    loop {
+        if let Some(end) = read.windows(4).position(|w| w == b"\r\n\r\n") {
+            return Ok((read, end));
+        }
+        if read.len() >= MAX_HTTP_HEADER_SIZE {
+            anyhow::bail!("http request header too large");
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            anyhow::bail!("connection closed before http request header");
+        }
+        read.extend_from_slice(&buf[..n]);
+    }
+}
+
+fn parse_host(header: &[u8]) -> Option<String> {
+    let header = std::str::from_utf8(header).ok()?;
+    // the first line is the request line
+    header.split("\r\n").skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("host") {
+            Some(value.trim().to_string())
+        } else {
+            None
+        }
+    })
+}
@@ -0,0 +1,124 @@
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAX_HEADER_SIZE: usize = 8192;
+
+/// An answer to `request`, the header names lowercased.
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Response {
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Send a HTTP/1.1 request for `path` with `headers`, each ended by CRLF,
+/// and read the answer on a connection used for it only. Bodies larger than
+/// `max_body` are refused.
+pub(crate) async fn request<S>(
+    stream: &mut S,
+    method: &str,
+    host: &str,
+    path: &str,
+    headers: &str,
+    body: &[u8],
+    max_body: usize,
+) -> anyhow::Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        method,
+        path,
+        host,
+        headers,
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut header = Vec::with_capacity(256);
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_HEADER_SIZE {
+            anyhow::bail!("http response header too large");
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let mut lines = header.lines();
+    let status_line = lines.next().unwrap_or_default();
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .with_context(|| format!("invalid http status line {}", status_line))?;
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    let mut response = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+
+    let chunked = response
+        .header("transfer-encoding")
+        .map_or(false, |encoding| encoding.contains("chunked"));
+    let content_length = response
+        .header("content-length")
+        .and_then(|len| len.parse::<usize>().ok());
+    response.body = match (chunked, content_length) {
+        (true, _) => read_chunked(stream, max_body).await?,
+        (false, Some(len)) if len <= max_body => {
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body).await?;
+            body
+        }
+        (false, Some(len)) => anyhow::bail!("http response body of {} bytes too large", len),
+        (false, None) => {
+            let mut body = Vec::new();
+            stream.take(max_body as u64).read_to_end(&mut body).await?;
+            body
+        }
+    };
+    Ok(response)
+}
+
+async fn read_chunked<S>(stream: &mut S, max_body: usize) -> anyhow::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut body = Vec::new();
+    loop {
+        let mut line = Vec::new();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= MAX_HEADER_SIZE {
+                anyhow::bail!("http chunk header too large");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = String::from_utf8_lossy(&line);
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).context("invalid chunk size")?;
+        if size == 0 {
+            return Ok(body);
+        }
+        if body.len() + size > max_body {
+            anyhow::bail!("http response body too large");
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        stream.read_exact(&mut body[start..]).await?;
+        let mut crlf = [0u8; 2];
+        stream.read_exact(&mut crlf).await?;
+    }
+}
@@ -0,0 +1,80 @@
+//! Forward connections to targets through socks5 and other proxies, the
+//! library behind the socks5-forwarder binary.
+//!
+//! ```no_run
+//! use socks5_forwarder::{Config, Forwarder};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let config = Config::from_toml(
+//!     r#"
+//!     [[rules]]
+//!     listen = "127.0.0.1:8000"
+//!     target = "1.1.1.1:443"
+//!     proxy_addr = "10.0.0.1:8080"
+//!     "#,
+//! )?;
+//! Forwarder::new(config).handle_signals(true).run().await
+//! # }
+//! ```
+
+mod access_log;
+mod acl;
+mod api;
+mod bind;
+mod check;
+#[doc(hidden)]
+pub mod cli;
+mod config;
+mod connections;
+#[cfg(unix)]
+mod daemon;
+mod discovery;
+mod dns;
+mod doh;
+mod forwarder;
+#[cfg(feature = "gssapi")]
+mod gssapi;
+#[cfg(unix)]
+mod handoff;
+mod health;
+mod hooks;
+mod http;
+mod http_client;
+mod limit;
+mod listener;
+mod logging;
+mod metrics;
+mod mux;
+mod outbound;
+mod pool;
+mod privileges;
+mod proxy;
+mod proxy_protocol;
+mod relay;
+mod resolver;
+#[cfg(unix)]
+mod sandbox;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp;
+#[cfg(windows)]
+mod service;
+mod sni;
+mod socks_server;
+mod statsd;
+#[cfg(unix)]
+mod syslog;
+mod target_check;
+mod telemetry;
+mod throttle;
+mod tls;
+mod udp;
+mod utils;
+
+pub use forwarder::{Config, Forwarder, ServeHandle};
+pub use hooks::{AcceptEvent, CloseEvent, ConnectedEvent, Hooks};
+#[cfg(feature = "hickory-dns")]
+pub use resolver::HickoryResolver;
+pub use resolver::{Resolver, Resolving, SystemResolver};
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Rule;
+use crate::throttle::Bandwidth;
+
+/// Buckets of clients not seen for a while are dropped beyond this.
+const MAX_IDLE_BUCKETS: usize = 4096;
+/// Quota usage of past periods is dropped beyond this.
+const MAX_QUOTA_CLIENTS: usize = 4096;
+
+/// Limits on accepted connections, shared by every listener.
+#[derive(Debug, Default)]
+pub(crate) struct Limits {
+    connections: Option<Arc<Semaphore>>,
+    per_ip: Option<Arc<PerIp>>,
+    accept_rate: Option<Mutex<TokenBucket>>,
+    accept_rate_per_ip: Option<RatePerIp>,
+    bandwidth: Option<Bandwidth>,
+    quotas: Option<Arc<Quotas>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QuotaPeriod {
+    Hour,
+    Day,
+}
+
+impl FromStr for QuotaPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hour" => Ok(QuotaPeriod::Hour),
+            "day" => Ok(QuotaPeriod::Day),
+            _ => Err(format!("unknown quota period {}, expect hour or day", s)),
+        }
+    }
+}
+
+impl QuotaPeriod {
+    fn duration(self) -> Duration {
+        match self {
+            QuotaPeriod::Hour => Duration::from_secs(3600),
+            QuotaPeriod::Day => Duration::from_secs(24 * 3600),
+        }
+    }
+}
+
+/// Bytes every client ip may relay in a period, counted from its first
+/// relay in the period.
+#[derive(Debug)]
+pub(crate) struct Quotas {
+    bytes: u64,
+    period: Duration,
+    usage: Mutex<HashMap<IpAddr, (Instant, Arc<AtomicU64>)>>,
+}
+
+impl Quotas {
+    /// Counter of bytes relayed by `ip` in its current period, `None` once it
+    /// is over the quota.
+    pub(crate) fn usage(&self, ip: IpAddr) -> Option<Arc<AtomicU64>> {
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        if usage.len() > MAX_QUOTA_CLIENTS {
+            let period = self.period;
+            usage.retain(|_, (start, _)| now.saturating_duration_since(*start) < period);
+        }
+        let (start, counter) = usage
+            .entry(ip)
+            .or_insert_with(|| (now, Arc::new(AtomicU64::new(0))));
+        if now.saturating_duration_since(*start) >= self.period {
+            *start = now;
+            *counter = Arc::new(AtomicU64::new(0));
+        }
+        if counter.load(Ordering::Relaxed) >= self.bytes {
+            return None;
+        }
+        Some(counter.clone())
+    }
+}
+
+/// Accept rates given in connections per second, bursting up to one second
+/// worth of them.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AcceptRates {
+    pub(crate) global: Option<u32>,
+    pub(crate) per_ip: Option<u32>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Take a token, returns how long to wait until it is really there. With
+    /// `bounded`, gives up when that is longer than refilling a full burst.
+    fn reserve(&mut self, now: Instant, bounded: bool) -> Option<Duration> {
+        self.refill(now);
+        let wait = if self.tokens >= 1.0 {
+            0.0
+        } else {
+            (1.0 - self.tokens) / self.rate
+        };
+        if bounded && wait > 1.0 {
+            return None;
+        }
+        self.tokens -= 1.0;
+        Some(Duration::from_secs_f64(wait))
+    }
+}
+
+#[derive(Debug)]
+struct RatePerIp {
+    rate: u32,
+    buckets: Mutex<HashMap<IpAddr, TokenBucket>>,
+}
+
+#[derive(Debug)]
+struct PerIp {
+    max: usize,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// Held by a connection until it is closed.
+pub(crate) struct Permit {
+    _connection: Option<OwnedSemaphorePermit>,
+    ip: Option<(Arc<PerIp>, IpAddr)>,
+}
+
+impl Limits {
+    pub(crate) fn new(
+        max_connections: Option<usize>,
+        max_per_ip: Option<usize>,
+        rates: AcceptRates,
+        bandwidth: Option<u64>,
+        quota: Option<(u64, QuotaPeriod)>,
+    ) -> anyhow::Result<Self> {
+        if rates.global == Some(0) || rates.per_ip == Some(0) {
+            anyhow::bail!("accept rates must be positive");
+        }
+        if bandwidth == Some(0) {
+            anyhow::bail!("bandwidth must be positive");
+        }
+        Ok(Self {
+            bandwidth: bandwidth.map(Bandwidth::new),
+            quotas: quota.map(|(bytes, period)| {
+                Arc::new(Quotas {
+                    bytes,
+                    period: period.duration(),
+                    usage: Mutex::new(HashMap::new()),
+                })
+            }),
+            accept_rate: rates.global.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            accept_rate_per_ip: rates.per_ip.map(|rate| RatePerIp {
+                rate,
+                buckets: Mutex::new(HashMap::new()),
+            }),
+            connections: max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            per_ip: max_per_ip.map(|max| {
+                Arc::new(PerIp {
+                    max,
+                    active: Mutex::new(HashMap::new()),
+                })
+            }),
+        })
+    }
+
+    /// Wait for room for one more connection, connections beyond the limit
+    /// or the accept rate are left in the backlog of the listener meanwhile.
+    pub(crate) async fn acquire(&self) -> Permit {
+        let connection = match self.connections.as_ref() {
+            Some(connections) => {
+                if connections.available_permits() == 0 {
+                    tracing::warn!("Connection limit reached, accepting paused");
+                }
+                // never closed
+                Some(connections.clone().acquire_owned().await.unwrap())
+            }
+            None => None,
+        };
+        if let Some(bucket) = self.accept_rate.as_ref() {
+            let wait = bucket.lock().unwrap().reserve(Instant::now(), false);
+            if let Some(wait) = wait.filter(|wait| !wait.is_zero()) {
+                tokio::time::sleep(wait).await;
+            }
+        }
+        Permit {
+            _connection: connection,
+            ip: None,
+        }
+    }
+
+    /// Share the bandwidth and quotas of the process with relays of `rule`.
+    pub(crate) fn apply(&self, rule: &mut Rule) {
+        rule.bandwidth = self.bandwidth.clone();
+        rule.quotas = self.quotas.clone();
+    }
+
+    /// Count the connection of `permit` against its client ip, returns how
+    /// long to hold it back for the accept rate of the client, or `None` when
+    /// it is rejected.
+    pub(crate) fn admit(&self, permit: &mut Permit, ip: IpAddr) -> Option<Duration> {
+        let delay = match self.accept_rate_per_ip.as_ref() {
+            Some(rate) => {
+                let now = Instant::now();
+                let mut buckets = rate.buckets.lock().unwrap();
+                if buckets.len() > MAX_IDLE_BUCKETS {
+                    buckets.retain(|_, bucket| {
+                        bucket.refill(now);
+                        bucket.tokens < bucket.rate
+                    });
+                }
+                let bucket = buckets
+                    .entry(ip)
+                    .or_insert_with(|| TokenBucket::new(rate.rate));
+                match bucket.reserve(now, true) {
+                    Some(delay) => delay,
+                    None => {
+                        tracing::warn!("Connecting too fast from {}, rejected", ip);
+                        return None;
+                    }
+                }
+            }
+            None => Duration::ZERO,
+        };
+
+        if let Some(per_ip) = self.per_ip.as_ref() {
+            let mut active = per_ip.active.lock().unwrap();
+            let count = active.entry(ip).or_insert(0);
+            if *count >= per_ip.max {
+                tracing::warn!("Too many connections from {}, rejected", ip);
+                return None;
+            }
+            *count += 1;
+            permit.ip = Some((per_ip.clone(), ip));
+        }
+        Some(delay)
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some((per_ip, ip)) = self.ip.take() {
+            let mut active = per_ip.active.lock().unwrap();
+            if let Some(count) = active.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&ip);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::Path;
+
+use anyhow::Context;
+#[cfg(unix)]
+use once_cell::sync::Lazy;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(target_os = "linux")]
+use tokio_vsock::{VsockListener, VsockStream};
+
+use crate::config::{Rule, Target};
+use crate::utils::UNIX_PREFIX;
+
+/// Listen addresses with this prefix are sockets passed by systemd socket
+/// activation, by index like `systemd:0` or by `FileDescriptorName=`.
+pub(crate) const SYSTEMD_PREFIX: &str = "systemd:";
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+/// Listen addresses with this prefix are vsock `cid:port`, with `any` for
+/// every cid.
+pub(crate) const VSOCK_PREFIX: &str = "vsock:";
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+    #[cfg(target_os = "linux")]
+    Vsock(VsockListener),
+}
+
+pub(crate) enum Accepted {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(target_os = "linux")]
+    Vsock(VsockStream),
+}
+
+impl Listener {
+    pub(crate) fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+            #[cfg(target_os = "linux")]
+            Listener::Vsock(_) => None,
+        }
+    }
+
+    /// The socket to hand to a new instance on upgrade, vsock is not handed off.
+    #[cfg(unix)]
+    pub(crate) fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        match self {
+            Listener::Tcp(listener) => Some(listener.as_raw_fd()),
+            Listener::Unix(listener) => Some(listener.as_raw_fd()),
+            #[cfg(target_os = "linux")]
+            Listener::Vsock(_) => None,
+        }
+    }
+
+    pub(crate) async fn accept(&mut self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(listener) => Ok(Accepted::Tcp(listener.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Accepted::Unix(listener.accept().await?.0)),
+            #[cfg(target_os = "linux")]
+            Listener::Vsock(listener) => Ok(Accepted::Vsock(listener.accept().await?.0)),
+        }
+    }
+}
+
+/// Bind the listeners of a rule, one for every acceptor.
+pub(crate) async fn bind_listeners(rule: &Rule) -> anyhow::Result<Vec<Listener>> {
+    let mut listeners = Vec::with_capacity(rule.acceptors);
+    for _ in 0..rule.acceptors {
+        listeners.push(bind_listener(rule).await?);
+    }
+    Ok(listeners)
+}
+
+async fn bind_listener(rule: &Rule) -> anyhow::Result<Listener> {
+    #[cfg(unix)]
+    if let Some(fd) = crate::handoff::take(&rule.listen) {
+        use std::os::unix::io::FromRawFd;
+
+        tracing::info!("Taking over the listening socket of {}", rule.listen);
+        return from_socket(unsafe { Socket::from_raw_fd(fd) }, &rule.listen);
+    }
+    if let Some(path) = rule.listen.strip_prefix(UNIX_PREFIX) {
+        return bind_unix(path);
+    }
+    if let Some(name) = rule.listen.strip_prefix(SYSTEMD_PREFIX) {
+        return take_systemd(name);
+    }
+    if let Some(addr) = rule.listen.strip_prefix(VSOCK_PREFIX) {
+        return bind_vsock(addr);
+    }
+    let addr = resolve_listen(rule).await?;
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    // the kernel spreads connections over sockets sharing the port
+    #[cfg(unix)]
+    if rule.acceptors > 1 {
+        socket.set_reuse_port(true)?;
+    }
+    if addr.is_ipv6() {
+        // [::] takes IPv4 clients too unless told otherwise
+        socket.set_only_v6(rule.v6only)?;
+    }
+    if let Target::Local = rule.target {
+        set_ip_transparent(&socket)?;
+    }
+    if let Some(device) = rule.bind_device.as_deref() {
+        bind_device(&socket, device)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(rule.backlog)?;
+    socket.set_nonblocking(true)?;
+    Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+}
+
+/// Bind the udp socket of a rule, on the same address as its tcp listener.
+pub(crate) async fn bind_udp(rule: &Rule) -> anyhow::Result<UdpSocket> {
+    #[cfg(unix)]
+    if let Some(fd) = crate::handoff::take_udp(&rule.listen) {
+        use std::os::unix::io::FromRawFd;
+
+        let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+        socket.set_nonblocking(true)?;
+        return Ok(UdpSocket::from_std(socket)?);
+    }
+    let addr = resolve_listen(rule).await?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(rule.v6only)?;
+    }
+    if let Some(device) = rule.bind_device.as_deref() {
+        bind_device(&socket, device)?;
+    }
+    #[cfg(unix)]
+    if let Some(dscp) = rule.inbound_dscp {
+        crate::utils::set_dscp(socket2::SockRef::from(&socket), dscp)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+pub(crate) async fn resolve_listen(rule: &Rule) -> anyhow::Result<SocketAddr> {
+    lookup_host(rule.listen.as_str())
+        .await?
+        .next()
+        .with_context(|| format!("unable to resolve listen address {}", rule.listen))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str) -> anyhow::Result<Listener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // a socket left behind by a previous run would fail the bind
+    let path = Path::new(path);
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("unable to listen at {}", path.display()))?;
+    Ok(Listener::Unix(listener))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str) -> anyhow::Result<Listener> {
+    anyhow::bail!("unix socket is only supported on unix")
+}
+
+#[cfg(target_os = "linux")]
+fn bind_vsock(addr: &str) -> anyhow::Result<Listener> {
+    let (cid, port) = addr
+        .split_once(':')
+        .with_context(|| format!("invalid vsock address {}", addr))?;
+    let cid = if cid == "any" {
+        libc::VMADDR_CID_ANY
+    } else {
+        cid.parse()
+            .with_context(|| format!("invalid vsock cid {}", cid))?
+    };
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid vsock port {}", port))?;
+    let listener = VsockListener::bind(cid, port)
+        .with_context(|| format!("unable to listen at vsock {}", addr))?;
+    Ok(Listener::Vsock(listener))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_vsock(_addr: &str) -> anyhow::Result<Listener> {
+    anyhow::bail!("vsock is only supported on linux")
+}
+
+/// Sockets passed by systemd with their names, owned here once so listening
+/// twice on one, or again after a reload, gets a duplicate of its own.
+#[cfg(unix)]
+static SYSTEMD_SOCKETS: Lazy<Result<Vec<(String, Socket)>, String>> = Lazy::new(take_passed);
+
+/// Take the sockets passed with `LISTEN_FDS`, see sd_listen_fds(3), and
+/// unset its variables so they are not taken again nor inherited.
+#[cfg(unix)]
+fn take_passed() -> Result<Vec<(String, Socket)>, String> {
+    use std::os::unix::io::FromRawFd;
+
+    let pid: Option<u32> = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok());
+    let count: Option<i32> = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok());
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].iter() {
+        std::env::remove_var(name);
+    }
+    let (pid, count) = match (pid, count) {
+        (Some(pid), Some(count)) => (pid, count),
+        _ => return Err("no socket passed by systemd".to_string()),
+    };
+    if pid != std::process::id() {
+        return Err("sockets passed by systemd are not for this process".to_string());
+    }
+    let mut names = names.split(':');
+    let mut sockets = Vec::with_capacity(count.max(0) as usize);
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count {
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        // not to leak into processes spawned later
+        socket.set_cloexec(true).map_err(|e| e.to_string())?;
+        let name = names.next().unwrap_or_default().to_string();
+        sockets.push((name, socket));
+    }
+    Ok(sockets)
+}
+
+/// Listen on a socket passed by systemd, by index or name.
+#[cfg(unix)]
+fn take_systemd(name: &str) -> anyhow::Result<Listener> {
+    let sockets = SYSTEMD_SOCKETS
+        .as_ref()
+        .map_err(|e| anyhow::anyhow!("{}", e))?;
+    let socket = match name.parse::<usize>() {
+        Ok(index) => sockets.get(index).with_context(|| {
+            format!(
+                "socket {} not passed by systemd, only {} passed",
+                name,
+                sockets.len()
+            )
+        })?,
+        Err(_) => sockets
+            .iter()
+            .find(|(fd_name, _)| fd_name == name)
+            .with_context(|| format!("no socket named {} passed by systemd", name))?,
+    };
+    from_socket(socket.1.try_clone()?, name)
+}
+
+/// Listen on a socket bound by someone else, systemd or the previous instance.
+#[cfg(unix)]
+fn from_socket(socket: Socket, name: &str) -> anyhow::Result<Listener> {
+    if socket.r#type()? != Type::STREAM {
+        anyhow::bail!("socket {} passed is not a stream socket", name);
+    }
+    socket.set_nonblocking(true)?;
+    if socket.local_addr()?.as_socket().is_some() {
+        Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+    } else {
+        Ok(Listener::Unix(UnixListener::from_std(socket.into())?))
+    }
+}
+
+#[cfg(not(unix))]
+fn take_systemd(_name: &str) -> anyhow::Result<Listener> {
+    anyhow::bail!("systemd socket activation is only supported on unix")
+}
+
+/// Needed by TPROXY to accept connections destined to non-local addresses.
+#[cfg(target_os = "linux")]
+fn set_ip_transparent(socket: &Socket) -> io::Result<()> {
+    socket.set_ip_transparent(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_ip_transparent(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "IP_TRANSPARENT is only available on linux",
+    ))
+}
+
+/// Only take traffic arriving on the interface `device`.
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, device: &str) -> anyhow::Result<()> {
+    socket
+        .bind_device(Some(device.as_bytes()))
+        .with_context(|| format!("unable to bind to device {}", device))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _device: &str) -> anyhow::Result<()> {
+    anyhow::bail!("SO_BINDTODEVICE is only available on linux")
+}
@@ -0,0 +1,128 @@
+use std::str::FromStr;
+
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+
+#[cfg(unix)]
+use crate::syslog::Syslog;
+use crate::telemetry;
+
+/// How log records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogFormat {
+    Text,
+    /// One JSON object per record, with the fields of its spans.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {}, expect text or json", s)),
+        }
+    }
+}
+
+/// Syslog facility, by its code of RFC 5424.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Facility(u8);
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let code = match s {
+            "user" => 1,
+            "mail" => 2,
+            "daemon" => 3,
+            "auth" => 4,
+            "syslog" => 5,
+            "authpriv" => 10,
+            _ => match s
+                .strip_prefix("local")
+                .and_then(|n| n.parse::<u8>().ok())
+            {
+                Some(n) if n <= 7 => 16 + n,
+                _ => {
+                    return Err(format!(
+                        "unknown syslog facility {}, expect user, mail, daemon, auth, syslog, authpriv or local0-7",
+                        s
+                    ))
+                }
+            },
+        };
+        Ok(Facility(code))
+    }
+}
+
+impl Facility {
+    pub(crate) fn code(self) -> u8 {
+        self.0
+    }
+}
+
+/// Install the global subscriber, logging to syslog if a facility is given
+/// or else to stderr when stdout carries the relayed stream in stdio mode, and
+/// exporting spans to `otlp_endpoint`.
+pub(crate) fn init(
+    format: LogFormat,
+    stdio: bool,
+    syslog: Option<Facility>,
+    otlp_endpoint: Option<&str>,
+) -> anyhow::Result<()> {
+    let otlp = otlp_endpoint.map(telemetry::otlp_layer).transpose()?;
+    let writer = || -> anyhow::Result<BoxMakeWriter> {
+        Ok(match syslog {
+            Some(facility) => syslog_writer(facility)?,
+            None if stdio => BoxMakeWriter::new(std::io::stderr),
+            None => BoxMakeWriter::new(std::io::stdout),
+        })
+    };
+    let text = match (format, syslog) {
+        (LogFormat::Text, None) => Some(tracing_subscriber::fmt::layer().with_writer(writer()?)),
+        _ => None,
+    };
+    // syslog has its own timestamps
+    let syslog_text = match (format, syslog) {
+        (LogFormat::Text, Some(_)) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .without_time()
+                .with_writer(writer()?),
+        ),
+        _ => None,
+    };
+    let json = match format {
+        LogFormat::Json => Some(
+            tracing_subscriber::fmt::layer()
+                .json()
+                .with_current_span(true)
+                .with_span_list(false)
+                .with_writer(writer()?),
+        ),
+        LogFormat::Text => None,
+    };
+    tracing_subscriber::registry()
+        .with(LevelFilter::INFO)
+        .with(text)
+        .with(syslog_text)
+        .with(json)
+        .with(otlp)
+        .try_init()?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn syslog_writer(facility: Facility) -> anyhow::Result<BoxMakeWriter> {
+    Ok(BoxMakeWriter::new(Syslog::connect(facility)?))
+}
+
+#[cfg(not(unix))]
+fn syslog_writer(_facility: Facility) -> anyhow::Result<BoxMakeWriter> {
+    anyhow::bail!("syslog is only supported on unix");
+}
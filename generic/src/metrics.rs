@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use crate::api::{self, Response};
+
+/// Upper bounds of relay duration buckets in seconds, the last one catches
+/// everything longer.
+const DURATION_BUCKETS: [f64; 8] = [0.1, 1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, f64::INFINITY];
+
+/// Clients from more countries are counted as unknown.
+const MAX_COUNTRIES: usize = 256;
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Counters of the whole process, across every listener.
+pub(crate) fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Start counting the uptime.
+pub(crate) fn start() {
+    Lazy::force(&STARTED);
+}
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    active: AtomicU64,
+    /// Bytes from clients to targets.
+    upload: AtomicU64,
+    /// Bytes from targets to clients.
+    download: AtomicU64,
+    handshake_failures: AtomicU64,
+    durations: [AtomicU64; DURATION_BUCKETS.len()],
+    duration_millis: AtomicU64,
+    /// Accepted clients by country, for listeners checking it.
+    countries: Mutex<HashMap<String, u64>>,
+    /// Failures to connect through each proxy.
+    proxy_failures: Mutex<HashMap<String, u64>>,
+}
+
+impl Metrics {
+    pub(crate) fn accepted(&self, country: Option<&str>) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        if let Some(country) = country {
+            let mut countries = self.countries.lock().unwrap();
+            let country = if countries.len() < MAX_COUNTRIES || countries.contains_key(country) {
+                country
+            } else {
+                "unknown"
+            };
+            *countries.entry(country.to_owned()).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn active(&self) -> u64 {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn rejected(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn uploaded(&self, bytes: usize) {
+        self.upload.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn downloaded(&self, bytes: usize) {
+        self.download.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn proxy_failed(&self, proxy: &str) {
+        let mut failures = self.proxy_failures.lock().unwrap();
+        match failures.get_mut(proxy) {
+            Some(count) => *count += 1,
+            None => {
+                failures.insert(proxy.to_owned(), 1);
+            }
+        }
+    }
+
+    /// Log a summary, for a look at a process without a metrics stack.
+    pub(crate) fn log_summary(&self) {
+        tracing::info!(
+            "Up for {}s, {} connections accepted, {} rejected, {} active, {} bytes uploaded, {} bytes downloaded, {} proxy handshakes failed",
+            STARTED.elapsed().as_secs(),
+            self.accepted.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.active(),
+            self.upload.load(Ordering::Relaxed),
+            self.download.load(Ordering::Relaxed),
+            self.handshake_failures.load(Ordering::Relaxed)
+        );
+        let mut failures: Vec<_> = self
+            .proxy_failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(proxy, count)| (proxy.clone(), *count))
+            .collect();
+        failures.sort();
+        for (proxy, count) in failures {
+            tracing::info!("Connecting through proxy {} failed {} times", proxy, count);
+        }
+    }
+
+    /// Count a relay as active until the returned guard is dropped.
+    pub(crate) fn relay_started(&'static self) -> ActiveRelay {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveRelay {
+            metrics: self,
+            start: Instant::now(),
+        }
+    }
+
+    fn relay_finished(&self, duration: Duration) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        let secs = duration.as_secs_f64();
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(DURATION_BUCKETS.len() - 1);
+        self.durations[bucket].fetch_add(1, Ordering::Relaxed);
+        self.duration_millis
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "connections_accepted_total",
+                "counter",
+                "Connections accepted for relaying.",
+                &self.accepted,
+            ),
+            (
+                "connections_rejected_total",
+                "counter",
+                "Connections rejected by access control or limits.",
+                &self.rejected,
+            ),
+            (
+                "connections_active",
+                "gauge",
+                "Connections being relayed.",
+                &self.active,
+            ),
+            (
+                "proxy_handshake_failures_total",
+                "counter",
+                "Failed or timed out handshakes with proxies.",
+                &self.handshake_failures,
+            ),
+        ];
+        for (name, kind, help, value) in counters.iter() {
+            let _ = writeln!(out, "# HELP socks5_forwarder_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE socks5_forwarder_{} {}", name, kind);
+            let _ = writeln!(
+                out,
+                "socks5_forwarder_{} {}",
+                name,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP socks5_forwarder_bytes_total Bytes relayed.\n");
+        out.push_str("# TYPE socks5_forwarder_bytes_total counter\n");
+        for (direction, value) in [("upload", &self.upload), ("download", &self.download)].iter() {
+            let _ = writeln!(
+                out,
+                "socks5_forwarder_bytes_total{{direction=\"{}\"}} {}",
+                direction,
+                value.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str(
+            "# HELP socks5_forwarder_relay_duration_seconds Durations of finished relays.\n",
+        );
+        out.push_str("# TYPE socks5_forwarder_relay_duration_seconds histogram\n");
+        let mut count = 0;
+        for (bound, value) in DURATION_BUCKETS.iter().zip(self.durations.iter()) {
+            count += value.load(Ordering::Relaxed);
+            let bound = if bound.is_infinite() {
+                "+Inf".to_owned()
+            } else {
+                bound.to_string()
+            };
+            let _ = writeln!(
+                out,
+                "socks5_forwarder_relay_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "socks5_forwarder_relay_duration_seconds_sum {}",
+            self.duration_millis.load(Ordering::Relaxed) as f64 / 1000.0
+        );
+        let _ = writeln!(
+            out,
+            "socks5_forwarder_relay_duration_seconds_count {}",
+            count
+        );
+
+        let failures = self.proxy_failures.lock().unwrap();
+        if !failures.is_empty() {
+            out.push_str(
+                "# HELP socks5_forwarder_proxy_failures_total Failures to connect through a proxy.\n",
+            );
+            out.push_str("# TYPE socks5_forwarder_proxy_failures_total counter\n");
+            for (proxy, value) in failures.iter() {
+                let _ = writeln!(
+                    out,
+                    "socks5_forwarder_proxy_failures_total{{proxy=\"{}\"}} {}",
+                    proxy, value
+                );
+            }
+        }
+        drop(failures);
+
+        let countries = self.countries.lock().unwrap();
+        if !countries.is_empty() {
+            out.push_str(
+                "# HELP socks5_forwarder_country_connections_total Connections accepted by client country.\n",
+            );
+            out.push_str("# TYPE socks5_forwarder_country_connections_total counter\n");
+            for (country, value) in countries.iter() {
+                let _ = writeln!(
+                    out,
+                    "socks5_forwarder_country_connections_total{{country=\"{}\"}} {}",
+                    country, value
+                );
+            }
+        }
+        out
+    }
+}
+
+/// A relay counted as active, its duration is recorded once dropped.
+pub(crate) struct ActiveRelay {
+    metrics: &'static Metrics,
+    start: Instant,
+}
+
+impl Drop for ActiveRelay {
+    fn drop(&mut self) {
+        self.metrics.relay_finished(self.start.elapsed());
+    }
+}
+
+/// Serve `GET /metrics` for Prometheus to scrape.
+pub(crate) async fn serve_metrics(addr: SocketAddr) -> anyhow::Result<()> {
+    api::serve(addr, "metrics", |method, path| match (method, path) {
+        ("GET", "/metrics") => Response::ok("text/plain; version=0.0.4", metrics().render()),
+        _ => Response::not_found(),
+    })
+    .await
+}
+
+/// Log a summary of the metrics on every SIGUSR1.
+#[cfg(unix)]
+pub(crate) fn summarize_on_signal() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            metrics().log_summary();
+        }
+    });
+    Ok(())
+}
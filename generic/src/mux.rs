@@ -0,0 +1,137 @@
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::sync::{oneshot, watch, Mutex};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+use yamux::{Config, Connection, Control, Mode};
+
+use crate::config::Rule;
+use crate::proxy_protocol::ProxiedAddrs;
+use crate::relay::{connect_upstream, relay};
+use crate::utils::BoxedStream;
+
+/// Yamux sessions to a forwarder running with `--demux`, relays of the rule
+/// are spread over them as streams. Sessions are opened on first use and
+/// reopened once closed.
+pub(crate) struct MuxSessions {
+    sessions: Vec<Mutex<Option<Control>>>,
+    next: AtomicUsize,
+    /// Dropped along with the rule, which closes the sessions.
+    _closed: watch::Sender<()>,
+    closed_rx: watch::Receiver<()>,
+}
+
+impl fmt::Debug for MuxSessions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxSessions")
+            .field("sessions", &self.sessions.len())
+            .finish()
+    }
+}
+
+impl MuxSessions {
+    pub(crate) fn new(size: usize) -> Self {
+        let (tx, rx) = watch::channel(());
+        Self {
+            sessions: (0..size.max(1)).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+            _closed: tx,
+            closed_rx: rx,
+        }
+    }
+
+    /// Open a new stream to the target of `rule`.
+    pub(crate) async fn open_stream(&self, rule: &Arc<Rule>) -> anyhow::Result<BoxedStream> {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.sessions.len();
+        let mut session = self.sessions[idx].lock().await;
+        if let Some(control) = session.as_mut() {
+            match control.open_stream().await {
+                Ok(stream) => return Ok(Box::new(stream.compat())),
+                Err(e) => {
+                    tracing::warn!("Mux session lost: {}, reconnecting", e);
+                    *session = None;
+                }
+            }
+        }
+        let mut control = self.connect(rule.clone()).await?;
+        let stream = control.open_stream().await?;
+        *session = Some(control);
+        Ok(Box::new(stream.compat()))
+    }
+
+    async fn connect(&self, rule: Arc<Rule>) -> anyhow::Result<Control> {
+        let (tx, rx) = oneshot::channel();
+        let mut closed = self.closed_rx.clone();
+        tokio::spawn(async move {
+            let target = match rule.target.fixed() {
+                Some(target) => target.to_string(),
+                None => {
+                    let _ = tx.send(Err(anyhow::anyhow!("mux needs a fixed target")));
+                    return;
+                }
+            };
+            // shared by every client, source routes do not apply
+            let outbound = match connect_upstream(&rule, rule.proxy.as_ref(), &target).await {
+                // a session is not a relay, it does not count as active on the proxy
+                Ok((_, outbound)) => outbound,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            };
+            // holding the rule would keep the sessions from being closed
+            drop(rule);
+
+            let mut connection =
+                Connection::new(outbound.compat(), Config::default(), Mode::Client);
+            let _ = tx.send(Ok(connection.control()));
+            tracing::info!("Mux session to {} opened", target);
+            loop {
+                tokio::select! {
+                    // the session has to be polled to make progress, streams opened by
+                    // the remote side are not expected and dropped
+                    res = connection.next_stream() => match res {
+                        Ok(Some(_)) => continue,
+                        Ok(None) => break,
+                        Err(e) => {
+                            tracing::warn!("Mux session to {} failed: {}", target, e);
+                            break;
+                        }
+                    },
+                    _ = closed.changed() => break,
+                }
+            }
+            tracing::info!("Mux session to {} closed", target);
+        });
+        rx.await.context("mux session task exited")?
+    }
+}
+
+/// Serve a yamux session accepted from a forwarder running with `--mux`,
+/// every stream in it is relayed to the target of the rule.
+pub(crate) async fn demux(
+    inbound: BoxedStream,
+    addrs: ProxiedAddrs,
+    rule: Arc<Rule>,
+) -> anyhow::Result<()> {
+    let target = rule
+        .target
+        .fixed()
+        .context("demux needs a fixed target")?
+        .to_string();
+    let mut connection = Connection::new(inbound.compat(), Config::default(), Mode::Server);
+    tracing::info!("Mux session from {} accepted", addrs.source);
+    while let Some(stream) = connection.next_stream().await? {
+        let rule = rule.clone();
+        let target = target.clone();
+        tokio::spawn(async move {
+            if let Err(e) = relay(stream.compat(), addrs, &rule, &target).await {
+                tracing::error!(error = %e, "Relay failed");
+            }
+        });
+    }
+    tracing::info!("Mux session finished");
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use socket2::SockRef;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+use crate::resolver;
+use crate::utils::Keepalive;
+#[cfg(unix)]
+use crate::utils::{set_dscp, set_tcp_keepalive};
+
+/// How sockets to proxies and targets are opened.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Outbound {
+    /// Local address to send from, instead of the one picked by the kernel.
+    pub(crate) bind: Option<IpAddr>,
+    /// Firewall mark, for policy routing of our own traffic(linux only).
+    pub(crate) mark: Option<u32>,
+    /// DSCP class of sent packets(unix only).
+    pub(crate) dscp: Option<u8>,
+    pub(crate) keepalive: Option<Keepalive>,
+    /// Disable Nagle's algorithm, for interactive protocols.
+    pub(crate) nodelay: bool,
+    /// Give up connecting to an address after this, instead of the minutes
+    /// taken by the system against blackholed addresses.
+    pub(crate) connect_timeout: Option<Duration>,
+}
+
+impl Outbound {
+    /// Connect to `addr`, trying every address it resolves to.
+    pub(crate) async fn connect(&self, addr: &str) -> io::Result<TcpStream> {
+        self.connect_addrs(&resolver::lookup(addr).await?).await
+    }
+
+    /// Connect to the first of `addrs` that accepts.
+    pub(crate) async fn connect_addrs(&self, addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for addr in addrs.iter().copied() {
+            match self.connect_addr(addr).await {
+                Ok(stream) => {
+                    stream.set_nodelay(self.nodelay)?;
+                    #[cfg(unix)]
+                    if let Some(keepalive) = self.keepalive.as_ref() {
+                        set_tcp_keepalive(&stream, keepalive)?;
+                    }
+                    return Ok(stream);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )
+        }))
+    }
+
+    async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        self.set_options(SockRef::from(&socket))?;
+        if let Some(ip) = self.bind {
+            socket.bind((ip, 0).into())?;
+        }
+        match self.connect_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, socket.connect(addr))
+                .await
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("connecting to {} timed out after {:?}", addr, timeout),
+                    )
+                })?,
+            None => socket.connect(addr).await,
+        }
+    }
+
+    /// Open a udp socket sending to `addr` only.
+    pub(crate) async fn connect_udp(&self, addr: SocketAddr) -> io::Result<UdpSocket> {
+        let bind_addr: SocketAddr = match (self.bind, addr) {
+            (Some(ip), _) => (ip, 0).into(),
+            (None, SocketAddr::V4(_)) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            (None, SocketAddr::V6(_)) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        self.set_options(SockRef::from(&socket))?;
+        socket.connect(addr).await?;
+        Ok(socket)
+    }
+
+    fn set_options(&self, socket: SockRef<'_>) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = self.mark {
+            socket.set_mark(mark)?;
+        }
+        #[cfg(unix)]
+        if let Some(dscp) = self.dscp {
+            set_dscp(socket, dscp)?;
+        }
+        Ok(())
+    }
+}
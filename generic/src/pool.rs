@@ -0,0 +1,654 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+
+use crate::config::{Balance, ProxyConfig};
+use crate::discovery::{Discovery, Watch};
+use crate::metrics::metrics;
+use crate::proxy;
+use crate::utils::BoxedStream;
+
+/// Pools of the rules being served, for readiness checks.
+static POOLS: Lazy<Mutex<Vec<Weak<ProxyPool>>>> = Lazy::new(Default::default);
+
+/// Prewarmed connections idle for longer are likely closed by the proxy.
+const PREWARM_MAX_IDLE: Duration = Duration::from_secs(30);
+const PREWARM_REFILL_INTERVAL: Duration = Duration::from_secs(5);
+const DISCOVERY_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How often proxies of pools without health checks are probed for
+/// readiness.
+const READINESS_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+const READINESS_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Upstream {
+    config: ProxyConfig,
+    weight: u32,
+    /// Lower is preferred, only the discovered instances of the lowest
+    /// priority still usable are picked.
+    priority: u16,
+    /// Relays currently going through this proxy.
+    active: AtomicUsize,
+    /// Cleared by health checks when the proxy fails to respond.
+    healthy: AtomicBool,
+    /// Set by readiness probes when the pool has no health checks.
+    reachable: AtomicBool,
+    consecutive_failures: AtomicU32,
+    /// Set by the circuit breaker, no connection is attempted before it.
+    paused_until: Mutex<Option<Instant>>,
+    idle: IdleConns,
+}
+
+impl Upstream {
+    fn new(config: ProxyConfig, weight: u32, priority: u16) -> Self {
+        Self {
+            config,
+            weight,
+            priority,
+            active: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            reachable: AtomicBool::new(false),
+            consecutive_failures: AtomicU32::new(0),
+            paused_until: Mutex::new(None),
+            idle: IdleConns::default(),
+        }
+    }
+
+    fn paused(&self, now: Instant) -> bool {
+        matches!(*self.paused_until.lock().unwrap(), Some(until) if now < until)
+    }
+}
+
+/// Prewarmed connections to a proxy with the time they were opened.
+#[derive(Default)]
+struct IdleConns(Mutex<VecDeque<(Instant, BoxedStream)>>);
+
+impl IdleConns {
+    /// Drop connections idle for too long, returns how many are left.
+    fn expire(&self) -> usize {
+        let mut conns = self.0.lock().unwrap();
+        conns.retain(|(opened, _)| opened.elapsed() < PREWARM_MAX_IDLE);
+        conns.len()
+    }
+
+    fn push(&self, stream: BoxedStream) {
+        self.0.lock().unwrap().push_back((Instant::now(), stream));
+    }
+
+    /// Drop every connection, like those to addresses the proxy moved from.
+    fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    fn pop(&self) -> Option<BoxedStream> {
+        let mut conns = self.0.lock().unwrap();
+        while let Some((opened, stream)) = conns.pop_front() {
+            if opened.elapsed() < PREWARM_MAX_IDLE {
+                return Some(stream);
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Debug for IdleConns {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IdleConns")
+            .field(&self.0.lock().unwrap().len())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct HealthCheck {
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+}
+
+/// Pause a proxy for `cooldown` after `failures` connect failures in a row.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CircuitBreaker {
+    pub(crate) failures: u32,
+    pub(crate) cooldown: Duration,
+}
+
+/// Keep `size` connections to every proxy opened ahead of time. With a
+/// `target`, the proxy handshake to it is done ahead of time as well.
+#[derive(Debug, Clone)]
+pub(crate) struct Prewarm {
+    pub(crate) size: usize,
+    pub(crate) target: Option<String>,
+}
+
+/// A connection taken from the prewarmed ones.
+pub(crate) enum Prewarmed {
+    /// Connected to the proxy, the handshake to the target is still needed.
+    Connected(BoxedStream),
+    /// Already relaying to the prewarm target.
+    Handshaked(BoxedStream),
+}
+
+/// Upstream proxies of a rule, new connections are spread over them.
+#[derive(Debug)]
+pub(crate) struct ProxyPool {
+    /// Replaced as a whole when discovered again, leases keep theirs.
+    upstreams: RwLock<Arc<Vec<Arc<Upstream>>>>,
+    discovery: Option<Discovery>,
+    balance: Balance,
+    health_check: Option<HealthCheck>,
+    circuit_breaker: Option<CircuitBreaker>,
+    prewarm: Option<Prewarm>,
+    /// Wakes up the refill task when a prewarmed connection is taken.
+    refill: Arc<Notify>,
+    /// Set once the background tasks are spawned, the pool may be shared by
+    /// several rules.
+    started: AtomicBool,
+    next: AtomicUsize,
+}
+
+/// A proxy picked for a relay, counted as active until dropped.
+pub(crate) struct ProxyLease<'a> {
+    pool: &'a ProxyPool,
+    upstream: Arc<Upstream>,
+}
+
+impl ProxyLease<'_> {
+    /// Take a prewarmed connection to the proxy if there is one usable for
+    /// `target`, those handshaked are left for the prewarm target.
+    pub(crate) fn take_prewarmed(&self, target: &str) -> Option<Prewarmed> {
+        let prewarm = self.pool.prewarm.as_ref()?;
+        if prewarm
+            .target
+            .as_deref()
+            .map_or(false, |prewarmed| prewarmed != target)
+        {
+            return None;
+        }
+        let stream = self.upstream.idle.pop()?;
+        self.pool.refill.notify_one();
+        Some(match prewarm.target {
+            Some(_) => Prewarmed::Handshaked(stream),
+            None => Prewarmed::Connected(stream),
+        })
+    }
+
+    /// Report whether connecting through the proxy succeeded, feeds the
+    /// circuit breaker.
+    pub(crate) fn connected(&self, ok: bool) {
+        if !ok {
+            metrics().proxy_failed(&self.upstream.config.address);
+        }
+        let breaker = match self.pool.circuit_breaker {
+            Some(breaker) => breaker,
+            None => return,
+        };
+        if ok {
+            self.upstream
+                .consecutive_failures
+                .store(0, Ordering::Relaxed);
+            return;
+        }
+        let failures = self
+            .upstream
+            .consecutive_failures
+            .fetch_add(1, Ordering::Relaxed)
+            + 1;
+        if failures >= breaker.failures {
+            *self.upstream.paused_until.lock().unwrap() = Some(Instant::now() + breaker.cooldown);
+            tracing::warn!(
+                "Proxy {} failed {} times in a row, paused for {:?}",
+                self.upstream.config.address,
+                failures,
+                breaker.cooldown
+            );
+        }
+    }
+}
+
+impl Deref for ProxyLease<'_> {
+    type Target = ProxyConfig;
+
+    fn deref(&self) -> &Self::Target {
+        &self.upstream.config
+    }
+}
+
+impl Drop for ProxyLease<'_> {
+    fn drop(&mut self) {
+        self.upstream.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ProxyPool {
+    /// `proxies` are paired with their weights, which must be positive, and
+    /// priorities. With `discovery`, they are its first answer, possibly
+    /// none yet.
+    pub(crate) fn new(
+        proxies: Vec<(ProxyConfig, u32, u16)>,
+        balance: Balance,
+        health_check: Option<HealthCheck>,
+        circuit_breaker: Option<CircuitBreaker>,
+        prewarm: Option<Prewarm>,
+        discovery: Option<Discovery>,
+    ) -> Self {
+        assert!(
+            !proxies.is_empty() || discovery.is_some(),
+            "proxy pool must not be empty"
+        );
+        let upstreams: Vec<_> = proxies
+            .into_iter()
+            .map(|(config, weight, priority)| Arc::new(Upstream::new(config, weight, priority)))
+            .collect();
+        Self {
+            upstreams: RwLock::new(Arc::new(upstreams)),
+            discovery,
+            balance,
+            health_check,
+            circuit_breaker,
+            prewarm,
+            refill: Arc::new(Notify::new()),
+            started: AtomicBool::new(false),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether a proxy is usable, neither paused by the circuit breaker nor
+    /// failing health checks, or readiness probes without them.
+    fn ready(&self) -> bool {
+        let now = Instant::now();
+        let checked = self.health_check.is_some();
+        self.upstreams().iter().any(|u| {
+            !u.paused(now)
+                && u.healthy.load(Ordering::Relaxed)
+                && (checked || u.reachable.load(Ordering::Relaxed))
+        })
+    }
+
+    fn upstreams(&self) -> Arc<Vec<Arc<Upstream>>> {
+        self.upstreams.read().unwrap().clone()
+    }
+
+    /// The proxies currently in the pool.
+    pub(crate) fn proxies(&self) -> Vec<ProxyConfig> {
+        self.upstreams()
+            .iter()
+            .map(|upstream| upstream.config.clone())
+            .collect()
+    }
+
+    /// Replace the proxies, those already in the pool keep their relays,
+    /// health and prewarmed connections. Returns whether any changed.
+    fn replace(&self, proxies: Vec<(ProxyConfig, u32, u16)>) -> bool {
+        let current = self.upstreams();
+        let upstreams: Vec<_> = proxies
+            .into_iter()
+            .map(|(config, weight, priority)| {
+                match current.iter().find(|u| {
+                    u.config.address == config.address
+                        && u.config.credential == config.credential
+                        && u.weight == weight
+                        && u.priority == priority
+                }) {
+                    Some(upstream) => upstream.clone(),
+                    None => Arc::new(Upstream::new(config, weight, priority)),
+                }
+            })
+            .collect();
+        let changed = upstreams.len() != current.len()
+            || upstreams
+                .iter()
+                .any(|u| !current.iter().any(|c| Arc::ptr_eq(u, c)));
+        *self.upstreams.write().unwrap() = Arc::new(upstreams);
+        changed
+    }
+
+    /// Pick the proxy for a new connection, the lease should be kept until
+    /// the relay finishes. None if every proxy is paused by the circuit breaker,
+    /// or none is discovered yet.
+    pub(crate) fn pick(&self) -> Option<ProxyLease<'_>> {
+        let upstreams = self.upstreams();
+        let len = upstreams.len();
+        let now = Instant::now();
+        let available = |u: &Upstream| !u.paused(now);
+        if !upstreams.iter().any(|u| available(u)) {
+            return None;
+        }
+        // when every proxy fails health checks, keep trying all of them rather than none
+        let any_healthy = upstreams
+            .iter()
+            .any(|u| available(u) && u.healthy.load(Ordering::Relaxed));
+        let any_usable =
+            |u: &Upstream| available(u) && (!any_healthy || u.healthy.load(Ordering::Relaxed));
+        // lower priorities are only fallbacks of the preferred ones
+        let priority = upstreams
+            .iter()
+            .filter(|u| any_usable(u))
+            .map(|u| u.priority)
+            .min()
+            .unwrap_or_default();
+        let usable = |u: &Upstream| any_usable(u) && u.priority == priority;
+
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+
+        let idx = match self.balance {
+            // every proxy takes `weight` turns out of the total weight
+            Balance::RoundRobin => {
+                let total_weight: usize = upstreams
+                    .iter()
+                    .filter(|&u| usable(u))
+                    .map(|u| u.weight as usize)
+                    .sum();
+                let mut turn = next % total_weight;
+                upstreams
+                    .iter()
+                    .position(|u| {
+                        if !usable(u) {
+                            return false;
+                        }
+                        if turn < u.weight as usize {
+                            return true;
+                        }
+                        turn -= u.weight as usize;
+                        false
+                    })
+                    .unwrap_or(0)
+            }
+            // lowest active / weight, the starting point rotates so ties are
+            // not always broken the same way
+            Balance::LeastConn => {
+                let start = next % len;
+                (0..len)
+                    .map(|offset| (start + offset) % len)
+                    .filter(|&idx| usable(&upstreams[idx]))
+                    .min_by(|&a, &b| {
+                        let (a, b) = (&upstreams[a], &upstreams[b]);
+                        let load_a = a.active.load(Ordering::Relaxed) as u64 * b.weight as u64;
+                        let load_b = b.active.load(Ordering::Relaxed) as u64 * a.weight as u64;
+                        load_a.cmp(&load_b)
+                    })
+                    .unwrap_or(start)
+            }
+        };
+        let upstream = upstreams[idx].clone();
+        upstream.active.fetch_add(1, Ordering::Relaxed);
+        Some(ProxyLease {
+            pool: self,
+            upstream,
+        })
+    }
+}
+
+/// Start the background tasks of the pool, they stop once the pool is dropped.
+pub(crate) fn spawn_background(pool: &Arc<ProxyPool>) {
+    if pool.started.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    POOLS.lock().unwrap().push(Arc::downgrade(pool));
+    spawn_health_check(pool);
+    spawn_prewarm(pool);
+    spawn_re_resolve(pool);
+    spawn_discovery(pool);
+}
+
+/// Wait until a proxy of the pool is reachable and accepts the credentials,
+/// retrying every second, fails after `timeout`.
+pub(crate) async fn wait_reachable(pool: &ProxyPool, timeout: Duration) -> anyhow::Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut last_error = None;
+        for upstream in pool.upstreams().iter() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match tokio::time::timeout(remaining, proxy::probe(&upstream.config)).await {
+                Ok(Ok(())) => {
+                    tracing::info!("Proxy {} is reachable", upstream.config.address);
+                    return Ok(());
+                }
+                Ok(Err(e)) => {
+                    tracing::warn!(
+                        "Proxy {} is not reachable yet: {}",
+                        upstream.config.address,
+                        e
+                    );
+                    last_error = Some(e);
+                }
+                Err(_) => last_error = Some(anyhow::anyhow!("timed out")),
+            }
+        }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            let e = last_error.unwrap_or_else(|| anyhow::anyhow!("timed out"));
+            anyhow::bail!("no proxy became reachable within {:?}: {}", timeout, e);
+        }
+        tokio::time::sleep(remaining.min(Duration::from_secs(1))).await;
+    }
+}
+
+/// Whether every pool being served has a usable proxy.
+pub(crate) fn all_ready() -> bool {
+    let mut pools = POOLS.lock().unwrap();
+    // pools of removed rules are gone
+    pools.retain(|pool| pool.strong_count() > 0);
+    pools
+        .iter()
+        .filter_map(Weak::upgrade)
+        .all(|pool| pool.ready())
+}
+
+/// Periodically probe the proxies of pools without health checks, only for
+/// `all_ready` to tell whether they are reachable.
+pub(crate) fn spawn_readiness_probes() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(READINESS_PROBE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let pools: Vec<_> = POOLS
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(Weak::upgrade)
+                .filter(|pool| pool.health_check.is_none())
+                .collect();
+            for pool in pools {
+                for upstream in pool.upstreams().iter() {
+                    let reachable = matches!(
+                        tokio::time::timeout(
+                            READINESS_PROBE_TIMEOUT,
+                            proxy::probe(&upstream.config)
+                        )
+                        .await,
+                        Ok(Ok(()))
+                    );
+                    upstream.reachable.store(reachable, Ordering::Relaxed);
+                }
+            }
+        }
+    });
+}
+
+/// Periodically probe every proxy of the pool, failing ones are skipped by
+/// `pick` until they recover.
+fn spawn_health_check(pool: &Arc<ProxyPool>) {
+    let check = match pool.health_check {
+        Some(check) => check,
+        None => return,
+    };
+    let pool = Arc::downgrade(pool);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(check.interval);
+        loop {
+            interval.tick().await;
+            let pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => return,
+            };
+            for upstream in pool.upstreams().iter() {
+                let res = match tokio::time::timeout(check.timeout, proxy::probe(&upstream.config))
+                    .await
+                {
+                    Ok(res) => res,
+                    Err(_) => Err(anyhow::anyhow!("timed out")),
+                };
+                let healthy = res.is_ok();
+                if upstream.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                    match res {
+                        Ok(()) => tracing::info!("Proxy {} is back", upstream.config.address),
+                        Err(e) => tracing::warn!(
+                            "Proxy {} failed health check, removed from rotation: {}",
+                            upstream.config.address,
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Periodically re-resolve the proxies given by hostname, so moving them in
+/// DNS takes effect without a restart.
+fn spawn_re_resolve(pool: &Arc<ProxyPool>) {
+    let interval = match pool
+        .upstreams()
+        .iter()
+        .find_map(|upstream| upstream.config.resolved.as_ref().map(|r| r.interval))
+    {
+        Some(interval) => interval,
+        None => return,
+    };
+    let pool = Arc::downgrade(pool);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => return,
+            };
+            for upstream in pool.upstreams().iter() {
+                let resolved = match upstream.config.resolved.as_ref() {
+                    Some(resolved) => resolved,
+                    None => continue,
+                };
+                match resolved.refresh().await {
+                    Ok(Some(addrs)) => {
+                        tracing::info!(
+                            "Proxy {} resolves to {:?} now",
+                            upstream.config.address,
+                            addrs
+                        );
+                        // prewarmed connections go to the previous addresses
+                        upstream.idle.clear();
+                    }
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!(
+                        "Re-resolving proxy {} failed, keep its addresses: {}",
+                        upstream.config.address,
+                        e
+                    ),
+                }
+            }
+        }
+    });
+}
+
+/// Follow the discovery source of the pool, proxies no longer in it stop
+/// taking connections.
+fn spawn_discovery(pool: &Arc<ProxyPool>) {
+    let discovery = match pool.discovery.clone() {
+        Some(discovery) => discovery,
+        None => return,
+    };
+    let pool = Arc::downgrade(pool);
+    tokio::spawn(async move {
+        let mut watch = Watch::new(discovery.wait);
+        loop {
+            // not holding the pool while waiting, or it would never be dropped
+            let res = match discovery.next(&mut watch).await {
+                Ok(instances) if instances.is_empty() => Err(anyhow::anyhow!("no instance")),
+                Ok(instances) => discovery.proxies(&instances),
+                Err(e) => Err(e),
+            };
+            let pool = match pool.upgrade() {
+                Some(pool) => pool,
+                None => return,
+            };
+            match res {
+                Ok(proxies) => {
+                    let count = proxies.len();
+                    if pool.replace(proxies) {
+                        tracing::info!("Proxies of {} updated, {} now", discovery, count);
+                        // pick them up for prewarming at once
+                        pool.refill.notify_one();
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Discovering {} failed, keep the current proxies: {:#}",
+                        discovery,
+                        e
+                    );
+                    watch.reset();
+                    drop(pool);
+                    tokio::time::sleep(DISCOVERY_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    });
+}
+
+/// Keep the prewarmed connections of every available proxy topped up.
+fn spawn_prewarm(pool: &Arc<ProxyPool>) {
+    let prewarm = match pool.prewarm.clone() {
+        Some(prewarm) => prewarm,
+        None => return,
+    };
+    let refill = pool.refill.clone();
+    let pool = Arc::downgrade(pool);
+    tokio::spawn(async move {
+        loop {
+            // not holding the pool while waiting, or it would never be dropped
+            {
+                let pool = match pool.upgrade() {
+                    Some(pool) => pool,
+                    None => return,
+                };
+                let now = Instant::now();
+                for upstream in pool.upstreams().iter() {
+                    if upstream.paused(now) || !upstream.healthy.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let mut idle = upstream.idle.expire();
+                    while idle < prewarm.size {
+                        match open_prewarmed(&upstream.config, prewarm.target.as_deref()).await {
+                            Ok(stream) => upstream.idle.push(stream),
+                            Err(e) => {
+                                tracing::warn!(
+                                    "Prewarming connection to proxy {} failed: {}",
+                                    upstream.config.address,
+                                    e
+                                );
+                                break;
+                            }
+                        }
+                        idle += 1;
+                    }
+                }
+            }
+            let _ = tokio::time::timeout(PREWARM_REFILL_INTERVAL, refill.notified()).await;
+        }
+    });
+}
+
+async fn open_prewarmed(proxy: &ProxyConfig, target: Option<&str>) -> anyhow::Result<BoxedStream> {
+    let stream = proxy::open(proxy).await?;
+    match target {
+        Some(target) => proxy::connect_through(stream, proxy, target).await,
+        None => Ok(stream),
+    }
+}
@@ -0,0 +1,106 @@
+use anyhow::Context;
+
+/// Account to switch to once the listening sockets are bound.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Privileges {
+    #[cfg(unix)]
+    uid: Option<libc::uid_t>,
+    #[cfg(unix)]
+    gid: libc::gid_t,
+}
+
+impl Privileges {
+    /// Look up `user` and `group` by name or numeric id. Without a group, the
+    /// primary group of the user is taken.
+    #[cfg(unix)]
+    pub(crate) fn resolve(user: Option<&str>, group: Option<&str>) -> anyhow::Result<Self> {
+        let user = user.map(lookup_user).transpose()?;
+        let gid = match (group, user) {
+            (Some(group), _) => lookup_group(group)?,
+            (None, Some((_, gid))) => gid,
+            (None, None) => anyhow::bail!("--user or --group is needed to drop privileges"),
+        };
+        Ok(Self {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn resolve(_user: Option<&str>, _group: Option<&str>) -> anyhow::Result<Self> {
+        anyhow::bail!("dropping privileges is only supported on unix")
+    }
+
+    /// Switch the whole process to the group, then the user; the group goes
+    /// first as it can no longer be changed afterwards.
+    #[cfg(unix)]
+    pub(crate) fn drop_to(&self) -> anyhow::Result<()> {
+        use std::io;
+
+        // supplementary groups of root would be kept otherwise
+        if unsafe { libc::setgroups(1, &self.gid) } != 0 {
+            return Err(io::Error::last_os_error()).context("unable to set groups");
+        }
+        if unsafe { libc::setgid(self.gid) } != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("unable to switch to group {}", self.gid));
+        }
+        if let Some(uid) = self.uid {
+            if unsafe { libc::setuid(uid) } != 0 {
+                return Err(io::Error::last_os_error())
+                    .with_context(|| format!("unable to switch to user {}", uid));
+            }
+            // a process able to get root back has not dropped anything
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                anyhow::bail!(
+                    "privileges could be regained after switching to user {}",
+                    uid
+                );
+            }
+        }
+        tracing::info!(
+            "Dropped privileges to user {:?} group {}",
+            self.uid,
+            self.gid
+        );
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn drop_to(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Uid and primary gid of `user`.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+    let name = std::ffi::CString::new(user).context("invalid user name")?;
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if !passwd.is_null() {
+        return Ok(unsafe { ((*passwd).pw_uid, (*passwd).pw_gid) });
+    }
+    let uid = user
+        .parse()
+        .with_context(|| format!("no such user {}", user))?;
+    let passwd = unsafe { libc::getpwuid(uid) };
+    // a bare uid without an account keeps the group the same as the uid
+    let gid = if passwd.is_null() {
+        uid
+    } else {
+        unsafe { (*passwd).pw_gid }
+    };
+    Ok((uid, gid))
+}
+
+#[cfg(unix)]
+fn lookup_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    let name = std::ffi::CString::new(group).context("invalid group name")?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if !entry.is_null() {
+        return Ok(unsafe { (*entry).gr_gid });
+    }
+    group
+        .parse()
+        .with_context(|| format!("no such group {}", group))
+}
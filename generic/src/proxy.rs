@@ -0,0 +1,394 @@
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+use tracing::Instrument;
+
+use crate::config::{ProxyConfig, ProxyProtocol, Resolve};
+#[cfg(feature = "gssapi")]
+use crate::gssapi::{self, GssStream, SOCKS5_AUTH_GSSAPI};
+use crate::metrics::metrics;
+use crate::resolver;
+use crate::utils::{split_host_port, AsyncStream, BoxedStream};
+
+pub(crate) const SOCKS5_VERSION: u8 = 0x05;
+pub(crate) const SOCKS5_AUTH_NONE: u8 = 0x00;
+const SOCKS5_AUTH_PASSWORD: u8 = 0x02;
+#[cfg(feature = "gssapi")]
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+pub(crate) const SOCKS5_ATYP_IPV4: u8 = 0x01;
+pub(crate) const SOCKS5_ATYP_DOMAIN: u8 = 0x03;
+pub(crate) const SOCKS5_ATYP_IPV6: u8 = 0x04;
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS4_CMD_CONNECT: u8 = 0x01;
+const SOCKS4_REPLY_GRANTED: u8 = 0x5a;
+
+pub(crate) const MAX_HTTP_HEADER_SIZE: usize = 8192;
+
+/// Open the connection to the proxy itself, over TLS if configured.
+pub(crate) async fn open(proxy: &ProxyConfig) -> anyhow::Result<BoxedStream> {
+    let stream = connect(proxy).await?;
+    match proxy.tls.as_ref() {
+        Some(tls) => {
+            let stream =
+                with_handshake_timeout(proxy, async { Ok(tls.connect(stream).await?) }).await?;
+            Ok(Box::new(stream))
+        }
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// The tcp connection to the proxy, at its addresses last resolved when
+/// they are re-resolved.
+pub(crate) async fn connect(proxy: &ProxyConfig) -> anyhow::Result<TcpStream> {
+    Ok(match proxy.resolved.as_ref() {
+        Some(resolved) => proxy.outbound.connect_addrs(&resolved.get().await?).await?,
+        None => proxy.outbound.connect(&proxy.address).await?,
+    })
+}
+
+/// Check the proxy is alive: connect and, for socks5, authenticate.
+pub(crate) async fn probe(proxy: &ProxyConfig) -> anyhow::Result<()> {
+    let mut stream = open(proxy).await?;
+    if proxy.protocol == ProxyProtocol::Socks5 {
+        socks5_auth(&mut stream, proxy).await?;
+    }
+    Ok(())
+}
+
+/// Socks5 method negotiation and username/password authentication (RFC 1929),
+/// or GSSAPI authentication (RFC 1961) whose protection is left unused.
+pub(crate) async fn socks5_auth<S>(stream: &mut S, proxy: &ProxyConfig) -> anyhow::Result<()>
+where
+    S: AsyncStream,
+{
+    if let Some(service) = proxy.gssapi.as_ref() {
+        #[cfg(feature = "gssapi")]
+        return gssapi_auth(stream, service).await.map(drop);
+        #[cfg(not(feature = "gssapi"))]
+        anyhow::bail!(
+            "gssapi support is not built in, unable to authenticate as {}",
+            service
+        );
+    }
+    let credential = proxy.stream_credential();
+    let method = match credential {
+        Some(_) => SOCKS5_AUTH_PASSWORD,
+        None => SOCKS5_AUTH_NONE,
+    };
+    stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [SOCKS5_VERSION, method] {
+        anyhow::bail!("proxy refused authentication method {}", method);
+    }
+
+    if let Some((username, password)) = credential.as_ref() {
+        if username.len() > 255 || password.len() > 255 {
+            anyhow::bail!("proxy username or password too long");
+        }
+        let mut request = Vec::with_capacity(3 + username.len() + password.len());
+        request.push(0x01);
+        request.push(username.len() as u8);
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        stream.write_all(&request).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0x00 {
+            anyhow::bail!("proxy authentication failed");
+        }
+    }
+    Ok(())
+}
+
+/// Connect to `target` through the proxy behind `stream` and its chained hops,
+/// returns the stream to talk with the target.
+pub(crate) async fn connect_through<S>(
+    stream: S,
+    proxy: &ProxyConfig,
+    target: &str,
+) -> anyhow::Result<BoxedStream>
+where
+    S: AsyncStream + 'static,
+{
+    let handshakes = async {
+        let target = resolve_target(proxy, target).await?;
+        let mut stream: BoxedStream = Box::new(stream);
+        let mut current = proxy;
+        for hop in proxy.chain.iter() {
+            tracing::info!("Handshake for proxy hop {}", hop.address);
+            stream = handshake(stream, current, &hop.address).await?;
+            current = hop;
+        }
+        handshake(stream, current, &target).await
+    };
+    let res = with_handshake_timeout(proxy, handshakes)
+        .instrument(tracing::info_span!("handshake", proxy = %proxy.address))
+        .await;
+    if res.is_err() {
+        metrics().handshake_failed();
+    }
+    res
+}
+
+/// The target as sent to the proxy, resolved here first with local
+/// resolution. SOCKS4 only carries IPv4, so its addresses are preferred
+/// there.
+pub(crate) async fn resolve_target(proxy: &ProxyConfig, target: &str) -> anyhow::Result<String> {
+    match proxy.resolve {
+        Resolve::Remote => Ok(target.to_string()),
+        Resolve::Local => {
+            let addrs = match proxy.doh.as_ref() {
+                Some(doh) => doh.lookup(target).await?,
+                None => resolver::lookup(target).await?,
+            };
+            let ipv4_only = matches!(
+                proxy.protocol,
+                ProxyProtocol::Socks4 | ProxyProtocol::Socks4a
+            );
+            let addr = addrs
+                .iter()
+                .find(|addr| !ipv4_only || addr.is_ipv4())
+                .or_else(|| addrs.first())
+                .with_context(|| format!("unable to resolve {}", target))?;
+            Ok(addr.to_string())
+        }
+    }
+}
+
+/// A proxy accepting connections without ever answering would hold relays
+/// forever without a time limit.
+pub(crate) async fn with_handshake_timeout<F, T>(
+    proxy: &ProxyConfig,
+    handshake: F,
+) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    match proxy.handshake_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "handshake with proxy {} timed out after {:?}",
+                    proxy.address,
+                    timeout
+                )
+            })?,
+        None => handshake.await,
+    }
+}
+
+/// Ask the proxy behind `stream` to connect to `target`, returns the stream
+/// to talk with the target.
+async fn handshake<S>(stream: S, proxy: &ProxyConfig, target: &str) -> anyhow::Result<BoxedStream>
+where
+    S: AsyncStream + 'static,
+{
+    match proxy.protocol {
+        ProxyProtocol::Socks5 if proxy.gssapi.is_some() => {
+            gssapi_connect(stream, proxy, target).await
+        }
+        ProxyProtocol::Socks5 => {
+            let stream = match proxy.stream_credential().as_ref() {
+                None => Socks5Stream::connect_with_socket(stream, target).await?,
+                Some((username, password)) => {
+                    Socks5Stream::connect_with_password_and_socket(
+                        stream, target, username, password,
+                    )
+                    .await?
+                }
+            };
+            Ok(Box::new(stream))
+        }
+        ProxyProtocol::Socks4 | ProxyProtocol::Socks4a => {
+            Ok(Box::new(socks4_connect(stream, proxy, target).await?))
+        }
+        ProxyProtocol::Http => Ok(Box::new(http_connect(stream, proxy, target).await?)),
+    }
+}
+
+#[cfg(feature = "gssapi")]
+async fn gssapi_auth<S>(stream: &mut S, service: &str) -> anyhow::Result<gssapi::Protection>
+where
+    S: AsyncStream,
+{
+    stream
+        .write_all(&[SOCKS5_VERSION, 1, SOCKS5_AUTH_GSSAPI])
+        .await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [SOCKS5_VERSION, SOCKS5_AUTH_GSSAPI] {
+        anyhow::bail!("proxy refused gssapi authentication method");
+    }
+    gssapi::authenticate(stream, service).await
+}
+
+/// Socks5 CONNECT after GSSAPI authentication, the request and everything
+/// after it going through the protected messages the proxy expects.
+#[cfg(feature = "gssapi")]
+async fn gssapi_connect<S>(
+    mut stream: S,
+    proxy: &ProxyConfig,
+    target: &str,
+) -> anyhow::Result<BoxedStream>
+where
+    S: AsyncStream + 'static,
+{
+    let service = proxy.gssapi.as_deref().unwrap_or_default();
+    let protection = gssapi_auth(&mut stream, service).await?;
+    let mut stream = GssStream::new(stream, protection);
+
+    let mut request = vec![SOCKS5_VERSION, SOCKS5_CMD_CONNECT, 0x00];
+    request.extend_from_slice(&encode_address(target)?);
+    stream.write_all(&request).await?;
+    stream.flush().await?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    if head[0] != SOCKS5_VERSION {
+        anyhow::bail!("invalid socks5 reply version {}", head[0]);
+    }
+    if head[1] != 0x00 {
+        anyhow::bail!("socks5 request rejected by proxy, reply code {}", head[1]);
+    }
+    // the bound address is of no use, skip it and its port
+    let addr_len = match head[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => stream.read_u8().await? as usize,
+        atyp => anyhow::bail!("invalid socks5 address type {}", atyp),
+    };
+    let mut bound = vec![0u8; addr_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(feature = "gssapi"))]
+async fn gssapi_connect<S>(
+    _stream: S,
+    proxy: &ProxyConfig,
+    _target: &str,
+) -> anyhow::Result<BoxedStream>
+where
+    S: AsyncStream + 'static,
+{
+    anyhow::bail!(
+        "gssapi support is not built in, unable to connect through {}",
+        proxy.address
+    )
+}
+
+/// Socks5 address of `target` with its port, as in requests and datagram
+/// headers.
+pub(crate) fn encode_address(target: &str) -> anyhow::Result<Vec<u8>> {
+    let (host, port) = split_host_port(target)?;
+    let mut addr = Vec::with_capacity(2 + host.len() + 2);
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            addr.push(SOCKS5_ATYP_IPV4);
+            addr.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            addr.push(SOCKS5_ATYP_IPV6);
+            addr.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                anyhow::bail!("target domain too long");
+            }
+            addr.push(SOCKS5_ATYP_DOMAIN);
+            addr.push(host.len() as u8);
+            addr.extend_from_slice(host.as_bytes());
+        }
+    }
+    addr.extend_from_slice(&port.to_be_bytes());
+    Ok(addr)
+}
+
+/// SOCKS4 CONNECT. With SOCKS4a, domain targets are resolved by the proxy,
+/// otherwise they are resolved here since the request only carries IPv4.
+async fn socks4_connect<S>(mut stream: S, proxy: &ProxyConfig, target: &str) -> anyhow::Result<S>
+where
+    S: AsyncStream,
+{
+    let (host, port) = split_host_port(target)?;
+    let (ip, domain) = match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => (ip, None),
+        Ok(IpAddr::V6(_)) => anyhow::bail!("socks4 does not support ipv6 target {}", target),
+        // 0.0.0.1 tells a SOCKS4a proxy that the domain follows
+        Err(_) if proxy.protocol == ProxyProtocol::Socks4a => ([0, 0, 0, 1].into(), Some(host)),
+        Err(_) => {
+            let ip = resolver::lookup(target)
+                .await?
+                .into_iter()
+                .find_map(|addr| match addr {
+                    SocketAddr::V4(addr) => Some(*addr.ip()),
+                    SocketAddr::V6(_) => None,
+                })
+                .with_context(|| format!("unable to resolve {} to ipv4", target))?;
+            (ip, None)
+        }
+    };
+
+    let user_id = proxy
+        .credential
+        .as_ref()
+        .map(|(username, _)| username.as_str())
+        .unwrap_or_default();
+    let mut request = Vec::with_capacity(10 + user_id.len() + host.len());
+    request.push(SOCKS4_VERSION);
+    request.push(SOCKS4_CMD_CONNECT);
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&ip.octets());
+    request.extend_from_slice(user_id.as_bytes());
+    request.push(0x00);
+    if let Some(domain) = domain {
+        request.extend_from_slice(domain.as_bytes());
+        request.push(0x00);
+    }
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 8];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != SOCKS4_REPLY_GRANTED {
+        anyhow::bail!("socks4 request rejected by proxy, reply code {}", reply[1]);
+    }
+    Ok(stream)
+}
+
+/// HTTP CONNECT, the response is read byte by byte so nothing sent by the
+/// target right after the header is consumed.
+async fn http_connect<S>(mut stream: S, proxy: &ProxyConfig, target: &str) -> anyhow::Result<S>
+where
+    S: AsyncStream,
+{
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some((username, password)) = proxy.credential.as_ref() {
+        let token = base64::encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::with_capacity(256);
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_HTTP_HEADER_SIZE {
+            anyhow::bail!("http proxy response header too large");
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+    let status = status_line.split_whitespace().nth(1);
+    if status != Some("200") {
+        anyhow::bail!("http proxy refused to connect: {}", status_line);
+    }
+    Ok(stream)
+}
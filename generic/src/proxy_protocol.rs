@@ -0,0 +1,293 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::ProxyHeaderVersion;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_CMD_LOCAL: u8 = 0x0;
+const V2_CMD_PROXY: u8 = 0x1;
+const V2_AF_INET: u8 = 0x1;
+const V2_AF_INET6: u8 = 0x2;
+const V2_PROTO_STREAM: u8 = 0x1;
+
+/// Addresses carried by a PROXY protocol header.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ProxiedAddrs {
+    pub(crate) source: SocketAddr,
+    pub(crate) destination: SocketAddr,
+}
+
+/// Read a PROXY protocol v1 or v2 header at the start of the stream, nothing
+/// after it is consumed. None for headers not carrying addresses, like v2
+/// LOCAL used by health checks of load balancers.
+pub(crate) async fn read_header<S>(stream: &mut S) -> anyhow::Result<Option<ProxiedAddrs>>
+where
+    S: AsyncRead + Unpin,
+{
+    // the shortest v1 header, `PROXY UNKNOWN\r\n`, is longer than the v2 signature
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).await?;
+    if head == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !head.starts_with(V1_PREFIX) {
+        anyhow::bail!("missing proxy protocol header");
+    }
+
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            anyhow::bail!("proxy protocol header too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line[V1_PREFIX.len()..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> anyhow::Result<Option<ProxiedAddrs>> {
+    let line = std::str::from_utf8(line)?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [proto, source, destination, source_port, destination_port]
+            if *proto == "TCP4" || *proto == "TCP6" =>
+        {
+            let source: IpAddr = source.parse()?;
+            let destination: IpAddr = destination.parse()?;
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(source, source_port.parse()?),
+                destination: SocketAddr::new(destination, destination_port.parse()?),
+            }))
+        }
+        _ => anyhow::bail!("invalid proxy protocol header {}", line),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> anyhow::Result<Option<ProxiedAddrs>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head).await?;
+    let (version, command, family) = (head[0] >> 4, head[0] & 0x0f, head[1] >> 4);
+    if version != 2 {
+        anyhow::bail!("unsupported proxy protocol version {}", version);
+    }
+    let mut payload = vec![0u8; u16::from_be_bytes([head[2], head[3]]) as usize];
+    stream.read_exact(&mut payload).await?;
+
+    match command {
+        V2_CMD_LOCAL => return Ok(None),
+        V2_CMD_PROXY => {}
+        _ => anyhow::bail!("unsupported proxy protocol command {}", command),
+    }
+    // tlvs following the addresses are ignored
+    let (source, destination, ports) = match family {
+        V2_AF_INET if payload.len() >= 12 => {
+            let mut source = [0u8; 4];
+            let mut destination = [0u8; 4];
+            source.copy_from_slice(&payload[..4]);
+            destination.copy_from_slice(&payload[4..8]);
+            (
+                IpAddr::from(Ipv4Addr::from(source)),
+                IpAddr::from(Ipv4Addr::from(destination)),
+                &payload[8..12],
+            )
+        }
+        V2_AF_INET6 if payload.len() >= 36 => {
+            let mut source = [0u8; 16];
+            let mut destination = [0u8; 16];
+            source.copy_from_slice(&payload[..16]);
+            destination.copy_from_slice(&payload[16..32]);
+            (
+                IpAddr::from(Ipv6Addr::from(source)),
+                IpAddr::from(Ipv6Addr::from(destination)),
+                &payload[32..36],
+            )
+        }
+        // unix sockets and unspecified families carry no usable address
+        V2_AF_INET | V2_AF_INET6 => anyhow::bail!("truncated proxy protocol header"),
+        _ => return Ok(None),
+    };
+    Ok(Some(ProxiedAddrs {
+        source: SocketAddr::new(source, u16::from_be_bytes([ports[0], ports[1]])),
+        destination: SocketAddr::new(destination, u16::from_be_bytes([ports[2], ports[3]])),
+    }))
+}
+
+/// Header announcing `addrs` to the target, sent before any relayed byte.
+pub(crate) fn encode_header(version: ProxyHeaderVersion, addrs: &ProxiedAddrs) -> Vec<u8> {
+    // both addresses must be of the same family
+    let (source, destination) = if addrs.source.is_ipv4() == addrs.destination.is_ipv4() {
+        (addrs.source, addrs.destination)
+    } else {
+        (to_ipv6(addrs.source), to_ipv6(addrs.destination))
+    };
+    match version {
+        ProxyHeaderVersion::V1 => {
+            let proto = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                proto,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyHeaderVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            header.push(0x20 | V2_CMD_PROXY);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                    header.push(V2_AF_INET << 4 | V2_PROTO_STREAM);
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&source.octets());
+                    header.extend_from_slice(&destination.octets());
+                }
+                (source, destination) => {
+                    header.push(V2_AF_INET6 << 4 | V2_PROTO_STREAM);
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&ipv6_octets(source));
+                    header.extend_from_slice(&ipv6_octets(destination));
+                }
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(IpAddr::V6(ipv6_octets(addr.ip()).into()), addr.port())
+}
+
+fn ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs(source: &str, destination: &str) -> ProxiedAddrs {
+        ProxiedAddrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    /// Read the header at the start of `bytes`, along with what is left.
+    fn read(bytes: &[u8]) -> (anyhow::Result<Option<ProxiedAddrs>>, Vec<u8>) {
+        let mut rest = bytes;
+        let res = block_on(read_header(&mut rest));
+        (res, rest.to_vec())
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn round_trip(version: ProxyHeaderVersion, sent: ProxiedAddrs) -> ProxiedAddrs {
+        let mut bytes = encode_header(version, &sent);
+        bytes.extend_from_slice(b"relayed");
+        let (res, rest) = read(&bytes);
+        assert_eq!(rest, b"relayed");
+        res.unwrap().unwrap()
+    }
+
+    #[test]
+    fn round_trip_v1() {
+        let sent = addrs("192.0.2.1:51000", "198.51.100.2:443");
+        assert_eq!(
+            encode_header(ProxyHeaderVersion::V1, &sent),
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 443\r\n"
+        );
+        let read = round_trip(ProxyHeaderVersion::V1, sent);
+        assert_eq!(read.source, sent.source);
+        assert_eq!(read.destination, sent.destination);
+
+        let sent = addrs("[2001:db8::1]:51000", "[2001:db8::2]:443");
+        let read = round_trip(ProxyHeaderVersion::V1, sent);
+        assert_eq!(read.source, sent.source);
+        assert_eq!(read.destination, sent.destination);
+    }
+
+    #[test]
+    fn round_trip_v2() {
+        for sent in [
+            addrs("192.0.2.1:51000", "198.51.100.2:443"),
+            addrs("[2001:db8::1]:51000", "[2001:db8::2]:443"),
+        ]
+        .iter()
+        {
+            let read = round_trip(ProxyHeaderVersion::V2, *sent);
+            assert_eq!(read.source, sent.source);
+            assert_eq!(read.destination, sent.destination);
+        }
+    }
+
+    #[test]
+    fn mixed_families_are_sent_as_ipv6() {
+        let sent = addrs("192.0.2.1:51000", "[2001:db8::2]:443");
+        for version in [ProxyHeaderVersion::V1, ProxyHeaderVersion::V2].iter() {
+            let read = round_trip(*version, sent);
+            assert_eq!(read.source, "[::ffff:192.0.2.1]:51000".parse().unwrap());
+            assert_eq!(read.destination, sent.destination);
+        }
+    }
+
+    #[test]
+    fn truncated_headers_fail() {
+        let sent = addrs("[2001:db8::1]:51000", "[2001:db8::2]:443");
+        for version in [ProxyHeaderVersion::V1, ProxyHeaderVersion::V2].iter() {
+            let bytes = encode_header(*version, &sent);
+            for len in 0..bytes.len() {
+                assert!(read(&bytes[..len]).0.is_err(), "read {} bytes", len);
+            }
+        }
+    }
+
+    #[test]
+    fn v2_addresses_shorter_than_their_family_fail() {
+        let mut bytes = V2_SIGNATURE.to_vec();
+        bytes.extend_from_slice(&[0x20 | V2_CMD_PROXY, V2_AF_INET6 << 4 | V2_PROTO_STREAM]);
+        bytes.extend_from_slice(&12u16.to_be_bytes());
+        bytes.extend_from_slice(&[0u8; 12]);
+        assert!(read(&bytes).0.is_err());
+    }
+
+    #[test]
+    fn v2_local_carries_no_addresses() {
+        let mut bytes = V2_SIGNATURE.to_vec();
+        bytes.extend_from_slice(&[0x20 | V2_CMD_LOCAL, 0x00, 0, 0]);
+        bytes.extend_from_slice(b"relayed");
+        let (res, rest) = read(&bytes);
+        assert!(res.unwrap().is_none());
+        assert_eq!(rest, b"relayed");
+    }
+
+    #[test]
+    fn v1_unknown_and_oversized() {
+        let (res, rest) = read(b"PROXY UNKNOWN\r\nrelayed");
+        assert!(res.unwrap().is_none());
+        assert_eq!(rest, b"relayed");
+
+        let mut line = b"PROXY TCP4 ".to_vec();
+        line.resize(V1_MAX_LENGTH + 1, b'1');
+        line.extend_from_slice(b"\r\n");
+        assert!(read(&line).0.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").0.is_err());
+    }
+}
@@ -0,0 +1,583 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tracing::Instrument;
+
+use crate::access_log;
+use crate::config::{ProxyConfig, Rule, Target};
+use crate::connections;
+use crate::http;
+use crate::mux;
+use crate::pool::{Prewarmed, ProxyLease, ProxyPool};
+use crate::proxy;
+use crate::proxy_protocol::{self, ProxiedAddrs};
+use crate::sni;
+use crate::socks_server;
+use crate::statsd;
+use crate::throttle::{ByteBucket, Throttle, ThrottledStream};
+use crate::utils::{
+    original_dst, unmap_addr, Activity, ActivityStream, AsyncStream, BoxedStream, PrefixedStream,
+    Side, StdioStream, TargetStream,
+};
+#[cfg(unix)]
+use crate::utils::{set_dscp, UNIX_PREFIX};
+
+/// Relay an accepted connection according to its rule, `bound` being the
+/// address of the listener it came from.
+pub(crate) async fn dispatch(
+    mut inbound: TcpStream,
+    rule: Arc<Rule>,
+    bound: Option<SocketAddr>,
+) -> anyhow::Result<()> {
+    let header = read_client_header(&mut inbound, &rule).await?;
+    dispatch_from(inbound, rule, bound, header).await
+}
+
+/// The client behind a load balancer, from the PROXY header when the rule
+/// expects one.
+pub(crate) async fn read_client_header(
+    inbound: &mut TcpStream,
+    rule: &Rule,
+) -> anyhow::Result<Option<ProxiedAddrs>> {
+    with_accept_timeout(rule, read_proxy_header(inbound, rule)).await
+}
+
+/// Relay an accepted connection whose PROXY header, if any, was read already.
+pub(crate) async fn dispatch_from(
+    inbound: TcpStream,
+    rule: Arc<Rule>,
+    bound: Option<SocketAddr>,
+    header: Option<ProxiedAddrs>,
+) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if let Some(dscp) = rule.inbound_dscp {
+        set_dscp(socket2::SockRef::from(&inbound), dscp)?;
+    }
+    inbound.set_nodelay(rule.nodelay)?;
+    let addrs = match header {
+        Some(addrs) => {
+            // the peer is only the load balancer
+            tracing::Span::current().record("client", &tracing::field::display(addrs.source));
+            connections::set_client(addrs.source);
+            addrs
+        }
+        None => ProxiedAddrs {
+            source: unmap_addr(inbound.peer_addr()?),
+            destination: unmap_addr(inbound.local_addr()?),
+        },
+    };
+    let target = match &rule.target {
+        Target::Fixed(target) => Some(target.clone()),
+        Target::PreservePort(host) => {
+            let port = inbound.local_addr()?.port();
+            if host.contains(':') && !host.starts_with('[') {
+                Some(format!("[{}]:{}", host, port))
+            } else {
+                Some(format!("{}:{}", host, port))
+            }
+        }
+        Target::Original => {
+            let target = original_dst(&inbound)?;
+            // connected to the listener directly, relaying would loop back to us
+            if target == unmap_addr(inbound.local_addr()?) {
+                anyhow::bail!("connection to {} is not redirected", target);
+            }
+            Some(target.to_string())
+        }
+        Target::Local => {
+            let target = unmap_addr(inbound.local_addr()?);
+            // connected to the listener itself, relaying would loop back to us
+            if bound.map_or(false, |bound| is_listener_addr(target, unmap_addr(bound))) {
+                anyhow::bail!("connection to {} is not intercepted", target);
+            }
+            Some(target.to_string())
+        }
+        // found in the stream itself
+        _ => None,
+    };
+    serve_stream(inbound, addrs, rule, target).await
+}
+
+/// Whether `addr` is the one a listener is bound to, any of our addresses
+/// when bound to the unspecified one.
+fn is_listener_addr(addr: SocketAddr, bound: SocketAddr) -> bool {
+    addr.port() == bound.port() && (bound.ip().is_unspecified() || addr.ip() == bound.ip())
+}
+
+/// Relay a connection accepted on a unix or vsock socket, which has no ip
+/// addresses.
+pub(crate) async fn dispatch_unaddressed<S>(mut inbound: S, rule: Arc<Rule>) -> anyhow::Result<()>
+where
+    S: AsyncStream + 'static,
+{
+    let addrs = match with_accept_timeout(&rule, read_proxy_header(&mut inbound, &rule)).await? {
+        Some(addrs) => {
+            tracing::Span::current().record("client", &tracing::field::display(addrs.source));
+            connections::set_client(addrs.source);
+            addrs
+        }
+        None => unknown_addrs(),
+    };
+    let target = rule.target.fixed().map(str::to_string);
+    serve_stream(inbound, addrs, rule, target).await
+}
+
+/// Relay stdin and stdout as the only connection, for ssh ProxyCommand or inetd.
+pub(crate) async fn serve_stdio(rule: Rule) -> anyhow::Result<()> {
+    dispatch_unaddressed(StdioStream::new(), Arc::new(rule)).await
+}
+
+/// Placeholder addresses for connections not coming from an ip socket.
+fn unknown_addrs() -> ProxiedAddrs {
+    ProxiedAddrs {
+        source: (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        destination: (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+    }
+}
+
+/// A client never sending what is expected before relaying would hold its
+/// connection and task forever without a time limit.
+pub(crate) async fn with_accept_timeout<F, T>(rule: &Rule, accept: F) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    match rule.accept_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, accept)
+            .await
+            .map_err(|_| anyhow::anyhow!("client sent nothing usable within {:?}", timeout))?,
+        None => accept.await,
+    }
+}
+
+/// Behind a load balancer, the real client is only known from the header.
+async fn read_proxy_header<S>(inbound: &mut S, rule: &Rule) -> anyhow::Result<Option<ProxiedAddrs>>
+where
+    S: AsyncRead + Unpin,
+{
+    if rule.accept_proxy_protocol {
+        proxy_protocol::read_header(inbound).await
+    } else {
+        Ok(None)
+    }
+}
+
+/// Relay `inbound` to `target`, or to the target found in the stream.
+async fn serve_stream<S>(
+    inbound: S,
+    addrs: ProxiedAddrs,
+    rule: Arc<Rule>,
+    target: Option<String>,
+) -> anyhow::Result<()>
+where
+    S: AsyncStream + 'static,
+{
+    let (inbound, target) = with_accept_timeout(&rule, accept(inbound, &rule, target))
+        .instrument(tracing::info_span!("accept"))
+        .await?;
+    let target = match (&rule.target, target) {
+        (_, Some(target)) => target,
+        (Target::Socks5, None) => {
+            return socks_server::serve(inbound, addrs, &rule).await;
+        }
+        (Target::HttpConnect, None) => {
+            return http::serve_connect(inbound, addrs, &rule).await;
+        }
+        (_, None) => anyhow::bail!("no target for connections on {}", rule.listen),
+    };
+    if rule.demux {
+        return mux::demux(inbound, addrs, rule).await;
+    }
+    relay(inbound, addrs, &rule, &target).await
+}
+
+/// Read what the target is found from off `inbound`, terminating tls on the
+/// way. Without a target, the client asks for its destination itself.
+async fn accept<S>(
+    mut inbound: S,
+    rule: &Rule,
+    target: Option<String>,
+) -> anyhow::Result<(BoxedStream, Option<String>)>
+where
+    S: AsyncStream + 'static,
+{
+    // bytes read to find the target, relayed before the rest of the stream
+    let mut peeked = Vec::new();
+    let target = match (&rule.target, target) {
+        (Target::Sni(routing), None) => {
+            let (server_name, read) = sni::read_server_name(&mut inbound).await?;
+            peeked = read;
+            let host = server_name.context("no server name in tls client hello")?;
+            if !routing.allows(&host) {
+                anyhow::bail!("server name {} is not allowed", host);
+            }
+            Some(format!("{}:{}", host, routing.port))
+        }
+        // others are only readable after tls termination
+        (_, target) => target,
+    };
+
+    let inbound = PrefixedStream::new(peeked, inbound);
+    let (mut inbound, target): (BoxedStream, _) = match rule.tls.as_ref() {
+        Some(tls) => {
+            let inbound = tls.accept(inbound).await?;
+            let alpn_target = inbound
+                .get_ref()
+                .1
+                .alpn_protocol()
+                .and_then(|protocol| tls.alpn_target(protocol));
+            let target = alpn_target.map(str::to_string).or(target);
+            (Box::new(inbound), target)
+        }
+        None => (Box::new(inbound), target),
+    };
+    let target = match (&rule.target, target) {
+        (Target::Host(routing), None) => {
+            let (host, read) = http::read_host(&mut inbound).await?;
+            inbound = Box::new(PrefixedStream::new(read, inbound));
+            let host = host.context("no host header in http request")?;
+            Some(
+                routing
+                    .target(&host)
+                    .with_context(|| format!("no route for host {}", host))?,
+            )
+        }
+        (_, target) => target,
+    };
+    Ok((inbound, target))
+}
+
+/// Relay `inbound` to `target` through the upstream of the rule, `addrs` are
+/// the addresses of the client connection.
+pub(crate) async fn relay<S>(
+    inbound: S,
+    addrs: ProxiedAddrs,
+    rule: &Arc<Rule>,
+    target: &str,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tracing::info!("Relay {} to {}", addrs.source, target);
+    // keep the lease until the relay finishes
+    let (_lease, outbound, target) = connect_failover(addrs, rule, target).await?;
+    transfer(inbound, outbound, addrs, rule, target).await
+}
+
+/// Connect to `target`, then to the failover targets in order if it is the
+/// fixed one of the rule, skipping those down. Returns the target connected
+/// to.
+async fn connect_failover<'a>(
+    addrs: ProxiedAddrs,
+    rule: &'a Arc<Rule>,
+    target: &'a str,
+) -> anyhow::Result<(Option<ProxyLease<'a>>, BoxedStream, &'a str)> {
+    // targets routed by alpn have no standby
+    let failover = match rule.target.fixed() {
+        Some(fixed) if fixed == target => rule.target_failover.as_slice(),
+        _ => &[],
+    };
+    let candidates = std::iter::once(target).chain(failover.iter().map(String::as_str));
+    let is_down = |target: &str| {
+        rule.target_check
+            .as_ref()
+            .map_or(false, |check| check.is_down(target))
+    };
+    let mut last_error = None;
+    for target in candidates.filter(|target| !is_down(target)) {
+        if let Some(e) = last_error.take() {
+            tracing::warn!("Fail over to {}: {:#}", target, e);
+        }
+        match connect_target(addrs, rule, target).await {
+            Ok((lease, outbound)) => return Ok((lease, outbound, target)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    // fail fast rather than waiting on a connect bound to fail
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("target {} is down", target)))
+}
+
+/// Connect to `target` for the client at `addrs`, the returned stream is
+/// ready to relay.
+pub(crate) async fn connect_target<'a>(
+    addrs: ProxiedAddrs,
+    rule: &'a Arc<Rule>,
+    target: &str,
+) -> anyhow::Result<(Option<ProxyLease<'a>>, BoxedStream)> {
+    quota_usage(addrs, rule)?;
+    let connection = tracing::Span::current();
+    connection.record("target", &target);
+    connections::set_target(target);
+    let (lease, outbound) = async {
+        let (lease, mut outbound) = match rule.mux.as_ref() {
+            Some(sessions) => (None, sessions.open_stream(rule).await?),
+            None => connect_upstream(rule, rule.proxy_for(addrs.source.ip()), target).await?,
+        };
+        if let Some(version) = rule.send_proxy_protocol {
+            outbound
+                .write_all(&proxy_protocol::encode_header(version, &addrs))
+                .await?;
+        }
+        let outbound: BoxedStream = match rule.target_tls.as_ref() {
+            Some(tls) => Box::new(tls.connect(outbound).await?),
+            None => outbound,
+        };
+        Ok::<_, anyhow::Error>((lease, outbound))
+    }
+    .instrument(tracing::info_span!("connect", target = %target))
+    .await?;
+    let proxy = lease.as_ref().map(|lease| lease.address.as_str());
+    if let Some(proxy) = proxy {
+        connection.record("proxy", &proxy);
+    }
+    connections::set_connected(target, proxy);
+    Ok((lease, outbound))
+}
+
+/// Copy between the client and the target until both sides are done, or
+/// nothing went through for the idle timeout of the rule, or the relay
+/// reached its maximum lifetime.
+pub(crate) async fn transfer<S>(
+    inbound: S,
+    outbound: BoxedStream,
+    addrs: ProxiedAddrs,
+    rule: &Rule,
+    target: &str,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // bytes in either direction go through the inbound stream
+    // exhausted since connecting, let the relay finish anyway
+    let counter = quota_usage(addrs, rule).unwrap_or_default();
+    let activity = Arc::new(Activity::new(counter));
+    let inbound = ActivityStream::new(inbound, activity.clone());
+    let mut outbound = TargetStream::new(outbound, activity.clone());
+    connections::set_activity(activity.clone());
+    let mut upload: Vec<_> = rule
+        .rate_limit
+        .map(ByteBucket::shared)
+        .into_iter()
+        .collect();
+    let mut download: Vec<_> = rule
+        .rate_limit
+        .map(ByteBucket::shared)
+        .into_iter()
+        .collect();
+    if let Some(bandwidth) = rule.bandwidth.as_ref() {
+        upload.push(bandwidth.upload.clone());
+        download.push(bandwidth.download.clone());
+    }
+    let mut inbound = ThrottledStream::new(inbound, Throttle::new(upload), Throttle::new(download));
+
+    tracing::info!("Start relay");
+    let closed = async {
+        tokio::select! {
+            res = tokio::io::copy_bidirectional(&mut inbound, &mut outbound) => {
+                Closed::Finished(res)
+            }
+            idle = wait_idle(&activity, rule.idle_timeout) => Closed::Idle(idle),
+            _ = wait_lifetime(rule.max_lifetime) => Closed::Lifetime,
+        }
+    }
+    .instrument(tracing::info_span!("transfer"))
+    .await;
+    let (upload, download) = activity.bytes();
+    let duration = activity.elapsed();
+    // on timeouts it is the forwarder closing both
+    let closed_by = match closed {
+        Closed::Finished(_) => activity.closed_first().map_or("none", Side::as_str),
+        Closed::Idle(_) | Closed::Lifetime => "forwarder",
+    };
+    statsd::count("bytes.upload", &rule.statsd_tags, upload);
+    statsd::count("bytes.download", &rule.statsd_tags, download);
+    access_log::record(&access_log::Entry {
+        client: addrs.source,
+        listener: &rule.listen,
+        target,
+        duration,
+        upload,
+        download,
+        reason: closed.reason(),
+        closed_by,
+    });
+    match closed {
+        Closed::Finished(res) => {
+            res?;
+            tracing::info!(upload, download, ?duration, closed_by, "Relay finished");
+            return Ok(());
+        }
+        Closed::Idle(idle) => tracing::info!(
+            upload,
+            download,
+            ?duration,
+            "Relay idle for {:?}, closed",
+            idle
+        ),
+        Closed::Lifetime => tracing::info!(
+            upload,
+            download,
+            ?duration,
+            "Relay reached its maximum lifetime, closed"
+        ),
+    }
+    // let both sides see the end of stream rather than a reset
+    async {
+        let _ = inbound.shutdown().await;
+        let _ = outbound.shutdown().await;
+    }
+    .instrument(tracing::info_span!("shutdown"))
+    .await;
+    Ok(())
+}
+
+/// Why a relay ended.
+enum Closed {
+    /// Both sides are done, or either failed.
+    Finished(std::io::Result<(u64, u64)>),
+    Idle(Duration),
+    Lifetime,
+}
+
+impl Closed {
+    fn reason(&self) -> &'static str {
+        match self {
+            Closed::Finished(Ok(_)) => "finished",
+            Closed::Finished(Err(_)) => "error",
+            Closed::Idle(_) => "idle",
+            Closed::Lifetime => "lifetime",
+        }
+    }
+}
+
+async fn wait_lifetime(lifetime: Option<Duration>) {
+    match lifetime {
+        Some(lifetime) => tokio::time::sleep(lifetime).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Quota usage counter of the client, fails once it is over its quota.
+pub(crate) fn quota_usage(
+    addrs: ProxiedAddrs,
+    rule: &Rule,
+) -> anyhow::Result<Option<Arc<AtomicU64>>> {
+    let quotas = match rule.quotas.as_ref() {
+        Some(quotas) => quotas,
+        None => return Ok(None),
+    };
+    let ip = addrs.source.ip();
+    // clients of unix sockets and the like have no address to count against
+    if ip.is_unspecified() {
+        return Ok(None);
+    }
+    match quotas.usage(ip) {
+        Some(counter) => Ok(Some(counter)),
+        None => anyhow::bail!("transfer quota of {} is exhausted", ip),
+    }
+}
+
+async fn wait_idle(activity: &Activity, timeout: Option<Duration>) -> Duration {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return std::future::pending().await,
+    };
+    loop {
+        let idle = activity.idle();
+        if idle >= timeout {
+            return idle;
+        }
+        tokio::time::sleep(timeout - idle).await;
+    }
+}
+
+/// Connect to the target through a proxy of `pool`, one of the rule,
+/// retrying with backoff.
+pub(crate) async fn connect_upstream<'a>(
+    rule: &'a Rule,
+    pool: Option<&'a Arc<ProxyPool>>,
+    target_addr: &str,
+) -> anyhow::Result<(Option<ProxyLease<'a>>, BoxedStream)> {
+    let pool = match pool {
+        Some(pool) => pool,
+        None => return Ok((None, connect_direct(rule, target_addr).await?)),
+    };
+
+    let mut attempt = 0;
+    loop {
+        let res = match pool.pick() {
+            Some(lease) => {
+                let res = match lease.take_prewarmed(target_addr) {
+                    Some(Prewarmed::Handshaked(outbound)) => Ok(outbound),
+                    Some(Prewarmed::Connected(proxy_stream)) => {
+                        proxy::connect_through(proxy_stream, &lease, target_addr).await
+                    }
+                    None => connect_proxy(target_addr, &lease).await,
+                };
+                lease.connected(res.is_ok());
+                if res.is_err() {
+                    statsd::count("proxy.failures", &rule.statsd_tags, 1);
+                }
+                res.map(|outbound| (lease, outbound))
+            }
+            None => Err(anyhow::anyhow!(
+                "no proxy available, paused by circuit breaker or none discovered"
+            )),
+        };
+        match res {
+            Ok((lease, outbound)) => return Ok((Some(lease), outbound)),
+            Err(e) if attempt < rule.retry.retries => {
+                let delay = rule.retry.delay(attempt);
+                tracing::warn!("Connect through proxy failed: {}, retry in {:?}", e, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) if rule.fallback_direct => {
+                tracing::warn!("Connect through proxy failed: {}, fallback to direct", e);
+                return Ok((None, connect_direct(rule, target_addr).await?));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Connect to `target` as relays do and close at once, for health checks.
+/// Failures are not counted against the proxy, the target is at fault.
+pub(crate) async fn probe_target(rule: &Rule, target: &str) -> anyhow::Result<()> {
+    match rule.proxy.as_ref() {
+        Some(pool) => {
+            let lease = pool.pick().context("no proxy available")?;
+            connect_proxy(target, &lease).await?;
+        }
+        None => {
+            connect_direct(rule, target).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn connect_proxy(target_addr: &str, proxy: &ProxyConfig) -> anyhow::Result<BoxedStream> {
+    let proxy_stream = proxy::open(proxy).await?;
+    proxy::connect_through(proxy_stream, proxy, target_addr).await
+}
+
+async fn connect_direct(rule: &Rule, target_addr: &str) -> anyhow::Result<BoxedStream> {
+    if let Some(path) = target_addr.strip_prefix(UNIX_PREFIX) {
+        // clients choosing their destination must not reach local sockets
+        if rule.target.fixed() != Some(target_addr) {
+            anyhow::bail!("unix socket target {} refused", target_addr);
+        }
+        #[cfg(unix)]
+        return Ok(Box::new(UnixStream::connect(path).await?));
+        #[cfg(not(unix))]
+        anyhow::bail!("unix socket {} is not supported here", path);
+    }
+    Ok(Box::new(rule.outbound.connect(target_addr).await?))
+}
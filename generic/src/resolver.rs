@@ -0,0 +1,130 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use once_cell::sync::Lazy;
+
+/// Addresses `host` resolves to, with the port given.
+pub type Resolving<'a> = Pin<Box<dyn Future<Output = io::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+/// Resolves the names of proxies and of targets connected to directly, like
+/// split-horizon DNS or fixtures in tests.
+pub trait Resolver: Send + Sync + 'static {
+    /// `host` is a domain name, ip addresses are not resolved.
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a>;
+}
+
+/// The resolver of the system, getaddrinfo on a blocking thread.
+#[derive(Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        Box::pin(async move { Ok(tokio::net::lookup_host((host, port)).await?.collect()) })
+    }
+}
+
+/// Asynchronous resolver of hickory-dns, reading the nameservers of the
+/// system configuration.
+#[cfg(feature = "hickory-dns")]
+pub struct HickoryResolver(trust_dns_resolver::TokioAsyncResolver);
+
+#[cfg(feature = "hickory-dns")]
+impl HickoryResolver {
+    pub fn from_system_conf() -> anyhow::Result<Self> {
+        use anyhow::Context;
+
+        let resolver = trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf()
+            .context("unable to read the system resolver configuration")?;
+        Ok(Self(resolver))
+    }
+}
+
+#[cfg(feature = "hickory-dns")]
+impl Resolver for HickoryResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> Resolving<'a> {
+        Box::pin(async move {
+            let ips = self
+                .0
+                .lookup_ip(host)
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+        })
+    }
+}
+
+/// Addresses of `host:port` kept between lookups, refreshed every
+/// `interval` by the owner.
+#[derive(Debug)]
+pub(crate) struct CachedAddrs {
+    addr: String,
+    pub(crate) interval: Duration,
+    addrs: RwLock<Vec<SocketAddr>>,
+}
+
+impl CachedAddrs {
+    pub(crate) fn new(addr: &str, interval: Duration) -> Self {
+        Self {
+            addr: addr.to_string(),
+            interval,
+            addrs: RwLock::new(Vec::new()),
+        }
+    }
+
+    /// The addresses last resolved, resolving them on first use.
+    pub(crate) async fn get(&self) -> io::Result<Vec<SocketAddr>> {
+        let addrs = self.addrs.read().unwrap().clone();
+        if !addrs.is_empty() {
+            return Ok(addrs);
+        }
+        self.refresh().await?;
+        Ok(self.addrs.read().unwrap().clone())
+    }
+
+    /// Resolve again, returns the new addresses if they changed. The
+    /// previous ones are kept when the lookup fails.
+    pub(crate) async fn refresh(&self) -> io::Result<Option<Vec<SocketAddr>>> {
+        let addrs = lookup(&self.addr).await?;
+        let mut current = self.addrs.write().unwrap();
+        // the order is kept for connecting, ignored for comparing
+        let (mut old, mut new) = (current.clone(), addrs.clone());
+        old.sort_unstable();
+        new.sort_unstable();
+        if old == new {
+            return Ok(None);
+        }
+        *current = addrs.clone();
+        Ok(Some(addrs))
+    }
+}
+
+static RESOLVER: Lazy<RwLock<Arc<dyn Resolver>>> =
+    Lazy::new(|| RwLock::new(Arc::new(SystemResolver)));
+
+/// Resolve with `resolver` from now on, for the whole process.
+pub(crate) fn set(resolver: Arc<dyn Resolver>) {
+    *RESOLVER.write().unwrap() = resolver;
+}
+
+/// Addresses of `host:port`, taken as is for an ip address.
+pub(crate) async fn lookup(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let (host, port) = crate::utils::split_host_port(addr)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    // cloned, the lock is not held across the lookup
+    let resolver = RESOLVER.read().unwrap().clone();
+    let addrs = resolver.resolve(host, port).await?;
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} resolved to no address", host),
+        ));
+    }
+    Ok(addrs)
+}
@@ -0,0 +1,162 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+/// Where the process may still touch the filesystem once sandboxed.
+#[derive(Debug, Default)]
+pub(crate) struct Sandbox {
+    /// Read only, like the config file, certificates and system files the
+    /// resolver needs.
+    pub(crate) read: Vec<PathBuf>,
+    /// Read and write, like the directory of the access log.
+    pub(crate) write: Vec<PathBuf>,
+    /// Root to change to where Landlock is not available.
+    pub(crate) chroot: Option<PathBuf>,
+}
+
+/// Read by the resolver and the dynamic loader, like /etc/resolv.conf and
+/// the NSS modules.
+pub(crate) const SYSTEM_PATHS: [&str; 5] = ["/etc", "/usr", "/lib", "/lib64", "/dev"];
+
+impl Sandbox {
+    /// Restrict the filesystem access of the process with Landlock,
+    /// including threads started afterwards but not those already running,
+    /// so it is done before the runtime starts. When it fails, the caller
+    /// may fall back to [`chroot`] once the listeners are bound.
+    pub(crate) fn restrict(&self) -> anyhow::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            landlock::restrict(self)
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            anyhow::bail!("Landlock is only available on linux")
+        }
+    }
+}
+
+/// Change the root of the whole process to `root`, paths opened afterwards
+/// are inside it.
+pub(crate) fn chroot(root: &Path) -> anyhow::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(root.as_os_str().as_bytes())?;
+    if unsafe { libc::chroot(path.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("unable to chroot to {}", root.display()));
+    }
+    std::env::set_current_dir("/")?;
+    Ok(())
+}
+
+/// Landlock through raw syscalls, see landlock(7); the syscall numbers are
+/// the same on every architecture.
+#[cfg(target_os = "linux")]
+mod landlock {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::path::Path;
+
+    use anyhow::Context;
+
+    use super::Sandbox;
+
+    const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+    const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+    const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+    const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// Every right of the first Landlock ABI, from executing files to making
+    /// symlinks.
+    const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+    /// Rights that apply to a file, as opposed to a directory.
+    const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE;
+    const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    pub(super) fn restrict(sandbox: &Sandbox) -> anyhow::Result<()> {
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_ALL,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("unable to create landlock ruleset");
+        }
+        let ruleset = unsafe { File::from_raw_fd(fd as i32) };
+
+        for path in sandbox.read.iter() {
+            allow(&ruleset, path, ACCESS_FS_READ)?;
+        }
+        for path in sandbox.write.iter() {
+            allow(&ruleset, path, ACCESS_FS_ALL)?;
+        }
+
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error()).context("unable to set no_new_privs");
+        }
+        if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset.as_raw_fd(), 0) } != 0 {
+            return Err(io::Error::last_os_error()).context("unable to enforce landlock ruleset");
+        }
+        Ok(())
+    }
+
+    fn allow(ruleset: &File, path: &Path, access: u64) -> anyhow::Result<()> {
+        let file = match std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+        {
+            Ok(file) => file,
+            // like /lib64 on distributions without it
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("unable to open {}", path.display())),
+        };
+        let access = if file.metadata()?.is_dir() {
+            access
+        } else {
+            access & ACCESS_FS_FILE
+        };
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: file.as_raw_fd(),
+        };
+        let res = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("unable to allow {} in landlock", path.display()));
+        }
+        Ok(())
+    }
+}
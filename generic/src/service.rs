@@ -0,0 +1,175 @@
+use std::ffi::{OsStr, OsString};
+use std::time::Duration;
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use once_cell::sync::Lazy;
+use tokio::sync::Notify;
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+    ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_dispatcher;
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+use crate::cli::{self, Opts};
+
+const SERVICE_NAME: &str = "socks5-forwarder";
+const SERVICE_DISPLAY_NAME: &str = "Socks5 Forwarder";
+const SERVICE_DESCRIPTION: &str = "Forwards connections through socks5 proxies";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// Notified when the service control manager asks the service to stop.
+static STOP: Lazy<Notify> = Lazy::new(Notify::new);
+
+#[derive(Subcommand)]
+pub(crate) enum ServiceCommand {
+    /// Register the service, started at boot with the forwarder options
+    /// given after `--`.
+    Install {
+        #[clap(last = true)]
+        args: Vec<String>,
+    },
+    /// Stop the service if running and remove it.
+    Uninstall,
+    Start,
+    Stop,
+    /// Run as the service, only done by the service control manager.
+    Run,
+}
+
+/// Manage the service with the service control manager, apart from running it.
+pub(crate) fn manage(command: &ServiceCommand) -> anyhow::Result<()> {
+    match command {
+        ServiceCommand::Install { args } => install(args),
+        ServiceCommand::Uninstall => uninstall(),
+        ServiceCommand::Start => {
+            open(ServiceAccess::START)?.start(&[] as &[&OsStr])?;
+            println!("Service {} started", SERVICE_NAME);
+            Ok(())
+        }
+        ServiceCommand::Stop => {
+            open(ServiceAccess::STOP)?.stop()?;
+            println!("Service {} stopping", SERVICE_NAME);
+            Ok(())
+        }
+        ServiceCommand::Run => dispatch(),
+    }
+}
+
+fn install(args: &[String]) -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .context("unable to connect to the service control manager")?;
+    // options come before the subcommand, like on the command line
+    let mut launch_arguments: Vec<OsString> = args.iter().map(OsString::from).collect();
+    launch_arguments.push("service".into());
+    launch_arguments.push("run".into());
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: SERVICE_DISPLAY_NAME.into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .context("unable to install the service")?;
+    service.set_description(SERVICE_DESCRIPTION)?;
+    println!("Service {} installed", SERVICE_NAME);
+    Ok(())
+}
+
+fn uninstall() -> anyhow::Result<()> {
+    let service = open(ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    // removed for good once the last handle to it is closed
+    service
+        .delete()
+        .context("unable to uninstall the service")?;
+    println!("Service {} uninstalled", SERVICE_NAME);
+    Ok(())
+}
+
+fn open(access: ServiceAccess) -> anyhow::Result<windows_service::service::Service> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .context("unable to connect to the service control manager")?;
+    manager
+        .open_service(SERVICE_NAME, access)
+        .with_context(|| format!("service {} is not installed", SERVICE_NAME))
+}
+
+windows_service::define_windows_service!(ffi_service_main, service_main);
+
+/// Hand the main thread to the service control manager, which calls
+/// `service_main` on another one.
+fn dispatch() -> anyhow::Result<()> {
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .context("not started by the service control manager")
+}
+
+/// Exit code reported to the service control manager when the forwarder
+/// fails, shown in the event log.
+const SERVICE_FAILED: u32 = 1;
+
+fn service_main(_arguments: Vec<OsString>) {
+    // the launch arguments are the process arguments
+    let opt = Opts::parse();
+    if let Err(e) = run_service(opt) {
+        tracing::error!("Service failed: {:#}", e);
+    }
+}
+
+fn run_service(opt: Opts) -> anyhow::Result<()> {
+    let status_handle = service_control_handler::register(SERVICE_NAME, |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            STOP.notify_one();
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })?;
+    let status = |current_state, exit_code| ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted: match current_state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        },
+        exit_code,
+        checkpoint: 0,
+        wait_hint: Duration::ZERO,
+        process_id: None,
+    };
+    let running = status(ServiceState::Running, ServiceExitCode::Win32(0));
+    status_handle.set_service_status(running)?;
+
+    let res = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .map_err(anyhow::Error::from)
+        .and_then(|runtime| runtime.block_on(cli::run(opt, None)));
+
+    // the manager is told the service stopped either way, failing or not
+    let exit_code = match res {
+        Ok(()) => ServiceExitCode::Win32(0),
+        Err(_) => ServiceExitCode::ServiceSpecific(SERVICE_FAILED),
+    };
+    status_handle.set_service_status(status(ServiceState::Stopped, exit_code))?;
+    res
+}
+
+/// Resolves once the service is asked to stop, starting the drain.
+pub(crate) async fn stopped() {
+    STOP.notified().await;
+}
@@ -0,0 +1,221 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::utils::host_matches;
+
+const TLS_RECORD_HANDSHAKE: u8 = 0x16;
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXTENSION_SERVER_NAME: u16 = 0x0000;
+const TLS_SERVER_NAME_HOST: u8 = 0x00;
+const MAX_RECORD_SIZE: usize = 16384 + 2048;
+/// A ClientHello may span several records, but never gets this large.
+const MAX_CLIENT_HELLO_SIZE: usize = 65536;
+
+/// Route connections to the host named in the TLS ClientHello.
+#[derive(Debug, Clone)]
+pub(crate) struct SniRouting {
+    pub(crate) port: u16,
+    /// Hosts allowed as target, `*.example.com` allows the subdomains of
+    /// example.com. Everything is allowed when empty.
+    pub(crate) allow: Vec<String>,
+}
+
+impl SniRouting {
+    pub(crate) fn allows(&self, host: &str) -> bool {
+        if self.allow.is_empty() {
+            return true;
+        }
+        self.allow.iter().any(|pattern| host_matches(pattern, host))
+    }
+}
+
+/// Read the TLS ClientHello at the start of the stream, returns the server
+/// name in it along with every byte read, which still has to be relayed.
+pub(crate) async fn read_server_name<S>(stream: &mut S) -> anyhow::Result<(Option<String>, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut read = Vec::new();
+    let mut handshake = Vec::new();
+    loop {
+        let mut header = [0u8; 5];
+        stream.read_exact(&mut header).await?;
+        read.extend_from_slice(&header);
+        if header[0] != TLS_RECORD_HANDSHAKE {
+            anyhow::bail!("not a tls handshake");
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if len > MAX_RECORD_SIZE || read.len() + len > MAX_CLIENT_HELLO_SIZE {
+            anyhow::bail!("tls record too large");
+        }
+        let start = read.len();
+        read.resize(start + len, 0);
+        stream.read_exact(&mut read[start..]).await?;
+        handshake.extend_from_slice(&read[start..]);
+
+        if handshake.len() >= 4 {
+            if handshake[0] != TLS_HANDSHAKE_CLIENT_HELLO {
+                anyhow::bail!("not a tls client hello");
+            }
+            let hello_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]);
+            if handshake.len() >= 4 + hello_len as usize {
+                let name = parse_client_hello(&handshake[4..4 + hello_len as usize]);
+                return Ok((name, read));
+            }
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+/// Server name from the body of a ClientHello, see RFC 8446 section 4.1.2
+/// and RFC 6066 section 3.
+fn parse_client_hello(hello: &[u8]) -> Option<String> {
+    let mut hello = Reader(hello);
+    // legacy version and random
+    hello.take(2 + 32)?;
+    // session id, cipher suites and compression methods
+    hello.vec8()?;
+    hello.vec16()?;
+    hello.vec8()?;
+
+    let mut extensions = Reader(hello.vec16()?);
+    while let Some(kind) = extensions.u16() {
+        let data = extensions.vec16()?;
+        if kind != TLS_EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Reader(Reader(data).vec16()?);
+        while let Some(name_type) = names.u8() {
+            let name = names.vec16()?;
+            if name_type == TLS_SERVER_NAME_HOST {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec16(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// Body of a ClientHello with the extensions given as (type, data).
+    fn client_hello(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        // session id, cipher suites and compression methods
+        hello.push(0);
+        hello.extend_from_slice(&vec16(&[0x13, 0x01]));
+        hello.extend_from_slice(&[1, 0]);
+        let mut encoded = Vec::new();
+        for (kind, data) in extensions {
+            encoded.extend_from_slice(&kind.to_be_bytes());
+            encoded.extend_from_slice(&vec16(data));
+        }
+        hello.extend_from_slice(&vec16(&encoded));
+        hello
+    }
+
+    fn server_name(host: &str) -> (u16, Vec<u8>) {
+        let mut name = vec![TLS_SERVER_NAME_HOST];
+        name.extend_from_slice(&vec16(host.as_bytes()));
+        (TLS_EXTENSION_SERVER_NAME, vec16(&name))
+    }
+
+    #[test]
+    fn parse_server_name() {
+        // supported_versions before it
+        let hello = client_hello(&[(43, vec![2, 0x03, 0x04]), server_name("example.com")]);
+        assert_eq!(parse_client_hello(&hello).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn parse_without_server_name() {
+        let hello = client_hello(&[(43, vec![2, 0x03, 0x04])]);
+        assert_eq!(parse_client_hello(&hello), None);
+        assert_eq!(parse_client_hello(&client_hello(&[])), None);
+    }
+
+    #[test]
+    fn truncated_hello_has_no_server_name() {
+        let hello = client_hello(&[server_name("example.com")]);
+        for len in 0..hello.len() {
+            assert_eq!(
+                parse_client_hello(&hello[..len]),
+                None,
+                "read {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn read_hello_spanning_records() {
+        let hello = client_hello(&[server_name("example.com")]);
+        let mut handshake = vec![TLS_HANDSHAKE_CLIENT_HELLO, 0];
+        handshake.extend_from_slice(&vec16(&hello));
+        let (first, second) = handshake.split_at(10);
+        let mut stream = Vec::new();
+        for fragment in [first, second].iter() {
+            stream.extend_from_slice(&[TLS_RECORD_HANDSHAKE, 0x03, 0x01]);
+            stream.extend_from_slice(&vec16(fragment));
+        }
+        let sent = stream.clone();
+        stream.extend_from_slice(b"after");
+
+        let mut rest = stream.as_slice();
+        let (name, read) = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(read_server_name(&mut rest))
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("example.com"));
+        assert_eq!(read, sent);
+        assert_eq!(rest, b"after");
+    }
+
+    #[test]
+    fn read_refuses_other_records() {
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n\r\n";
+        let res = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(read_server_name(&mut stream));
+        assert!(res.is_err());
+    }
+}
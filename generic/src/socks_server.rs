@@ -0,0 +1,147 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::bind;
+use crate::config::Rule;
+use crate::proxy::{
+    SOCKS5_ATYP_DOMAIN, SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6, SOCKS5_AUTH_NONE, SOCKS5_VERSION,
+};
+use crate::proxy_protocol::ProxiedAddrs;
+use crate::relay::{connect_target, transfer, with_accept_timeout};
+
+const SOCKS5_AUTH_UNACCEPTABLE: u8 = 0xff;
+const SOCKS5_CMD_CONNECT: u8 = 0x01;
+pub(crate) const SOCKS5_CMD_BIND: u8 = 0x02;
+pub(crate) const SOCKS5_REPLY_SUCCEEDED: u8 = 0x00;
+pub(crate) const SOCKS5_REPLY_FAILURE: u8 = 0x01;
+const SOCKS5_REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const SOCKS5_REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Serve a socks5 client on the listener, its requested destination is
+/// relayed through the upstream of the rule. Only CONNECT without
+/// authentication is supported, and BIND when enabled.
+pub(crate) async fn serve<S>(
+    mut inbound: S,
+    addrs: ProxiedAddrs,
+    rule: &Arc<Rule>,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (command, target) =
+        with_accept_timeout(rule, accept(&mut inbound, rule.socks5_bind.is_some())).await?;
+    if let (SOCKS5_CMD_BIND, Some(socks_bind)) = (command, rule.socks5_bind.as_ref()) {
+        tracing::info!("Bind for {} expecting {}", addrs.source, target);
+        return bind::serve(inbound, addrs, rule, socks_bind, &target).await;
+    }
+    tracing::info!("Relay {} to {}", addrs.source, target);
+    // the client only learns about failures after connecting upstream
+    let (_lease, outbound) = match connect_target(addrs, rule, &target).await {
+        Ok(res) => res,
+        Err(e) => {
+            let _ = reply(&mut inbound, SOCKS5_REPLY_FAILURE).await;
+            return Err(e);
+        }
+    };
+    reply(&mut inbound, SOCKS5_REPLY_SUCCEEDED).await?;
+    transfer(inbound, outbound, addrs, rule, &target).await
+}
+
+/// Method negotiation and request of RFC 1928, returns the command with the
+/// requested destination, or the peer expected to connect back for BIND.
+async fn accept<S>(stream: &mut S, allow_bind: bool) -> anyhow::Result<(u8, String)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).await?;
+    if head[0] != SOCKS5_VERSION {
+        anyhow::bail!("unsupported socks version {}", head[0]);
+    }
+    let mut methods = vec![0u8; head[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&SOCKS5_AUTH_NONE) {
+        stream
+            .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_UNACCEPTABLE])
+            .await?;
+        anyhow::bail!("socks5 client does not accept no authentication");
+    }
+    stream
+        .write_all(&[SOCKS5_VERSION, SOCKS5_AUTH_NONE])
+        .await?;
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let command = request[1];
+    if command != SOCKS5_CMD_CONNECT && !(command == SOCKS5_CMD_BIND && allow_bind) {
+        reply(stream, SOCKS5_REPLY_COMMAND_NOT_SUPPORTED).await?;
+        anyhow::bail!("unsupported socks5 command {}", request[1]);
+    }
+    let host = match request[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            Host::Ip(Ipv4Addr::from(octets).into())
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            Host::Ip(Ipv6Addr::from(octets).into())
+        }
+        SOCKS5_ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut domain = vec![0u8; len as usize];
+            stream.read_exact(&mut domain).await?;
+            Host::Domain(String::from_utf8(domain)?)
+        }
+        atyp => {
+            reply(stream, SOCKS5_REPLY_ADDRESS_NOT_SUPPORTED).await?;
+            anyhow::bail!("unsupported socks5 address type {}", atyp);
+        }
+    };
+    let port = stream.read_u16().await?;
+    let target = match host {
+        Host::Ip(ip) => SocketAddr::new(ip, port).to_string(),
+        Host::Domain(domain) => format!("{}:{}", domain, port),
+    };
+    Ok((command, target))
+}
+
+enum Host {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+pub(crate) async fn reply<S>(stream: &mut S, code: u8) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    // the bound address is of no use to clients of a relay
+    reply_bound(stream, code, (Ipv4Addr::UNSPECIFIED, 0).into()).await
+}
+
+/// A reply carrying `addr`, the one bound or connected back for BIND.
+pub(crate) async fn reply_bound<S>(
+    stream: &mut S,
+    code: u8,
+    addr: SocketAddr,
+) -> std::io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut reply = vec![SOCKS5_VERSION, code, 0x00];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(SOCKS5_ATYP_IPV4);
+            reply.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(SOCKS5_ATYP_IPV6);
+            reply.extend_from_slice(&ip.octets());
+        }
+    }
+    reply.extend_from_slice(&addr.port().to_be_bytes());
+    stream.write_all(&reply).await
+}
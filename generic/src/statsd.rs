@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+use crate::metrics::metrics;
+
+const METRIC_PREFIX: &str = "socks5_forwarder.";
+
+/// Samples waiting for the next flush, more are dropped.
+const SAMPLE_BUFFER: usize = 4096;
+
+/// Datagrams are kept below the common MTU.
+const MAX_PACKET_SIZE: usize = 1400;
+
+static SAMPLES: OnceCell<mpsc::Sender<Sample>> = OnceCell::new();
+
+/// DogStatsD tags of a rule, formatted as the `|#key:value,...` suffix.
+#[derive(Debug, Clone)]
+pub(crate) struct Tags(Arc<str>);
+
+impl Tags {
+    pub(crate) fn new(tags: &[String]) -> Self {
+        if tags.is_empty() {
+            return Self(Arc::from(""));
+        }
+        Self(Arc::from(format!("|#{}", tags.join(","))))
+    }
+}
+
+enum Sample {
+    Count(&'static str, Tags, u64),
+    Timing(&'static str, Tags, Duration),
+}
+
+/// Add `value` to the counter `name`, nothing is sent without `--statsd-addr`.
+pub(crate) fn count(name: &'static str, tags: &Tags, value: u64) {
+    send(Sample::Count(name, tags.clone(), value));
+}
+
+pub(crate) fn timing(name: &'static str, tags: &Tags, duration: Duration) {
+    send(Sample::Timing(name, tags.clone(), duration));
+}
+
+fn send(sample: Sample) {
+    if let Some(samples) = SAMPLES.get() {
+        let _ = samples.try_send(sample);
+    }
+}
+
+/// Push samples to the StatsD server at `addr` every `interval`, counters
+/// are summed up in between.
+pub(crate) fn spawn(addr: SocketAddr, interval: Duration) {
+    let (tx, rx) = mpsc::channel(SAMPLE_BUFFER);
+    if SAMPLES.set(tx).is_err() {
+        return;
+    }
+    tracing::info!("Will push statsd metrics to {}", addr);
+    tokio::spawn(async move {
+        if let Err(e) = push(addr, interval, rx).await {
+            tracing::error!("Statsd exporter failed: {}", e);
+        }
+    });
+}
+
+async fn push(
+    addr: SocketAddr,
+    interval: Duration,
+    mut rx: mpsc::Receiver<Sample>,
+) -> anyhow::Result<()> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(addr).await?;
+
+    let mut counters: HashMap<(&'static str, Arc<str>), u64> = HashMap::new();
+    let mut lines = Vec::new();
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            sample = rx.recv() => match sample {
+                Some(Sample::Count(name, tags, value)) => {
+                    *counters.entry((name, tags.0)).or_default() += value;
+                }
+                Some(Sample::Timing(name, tags, duration)) => {
+                    lines.push(format!(
+                        "{}{}:{}|ms{}",
+                        METRIC_PREFIX,
+                        name,
+                        duration.as_millis(),
+                        tags.0
+                    ));
+                }
+                None => return Ok(()),
+            },
+            _ = ticker.tick() => {
+                for ((name, tags), value) in counters.drain() {
+                    lines.push(format!("{}{}:{}|c{}", METRIC_PREFIX, name, value, tags));
+                }
+                lines.push(format!(
+                    "{}connections.active:{}|g",
+                    METRIC_PREFIX,
+                    metrics().active()
+                ));
+                // a lost datagram only loses its samples, there is no retry
+                for packet in packets(&lines) {
+                    if let Err(e) = socket.send(packet.as_bytes()).await {
+                        tracing::warn!("Sending statsd metrics in failure: {}", e);
+                    }
+                }
+                lines.clear();
+            },
+        }
+    }
+}
+
+/// Join lines into datagrams no larger than `MAX_PACKET_SIZE` where possible.
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = Vec::new();
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
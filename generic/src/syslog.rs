@@ -0,0 +1,113 @@
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Context;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::logging::Facility;
+use crate::utils::format_rfc3339;
+
+/// Where the local syslog daemon listens on linux, macOS and the BSDs.
+const SYSLOG_SOCKETS: [&str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+
+const APP_NAME: &str = "socks5-forwarder";
+
+/// Writes every log record as an RFC 5424 message to the local syslog daemon.
+pub(crate) struct Syslog {
+    socket: Arc<UnixDatagram>,
+    facility: Facility,
+    hostname: String,
+    pid: u32,
+}
+
+impl Syslog {
+    pub(crate) fn connect(facility: Facility) -> anyhow::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        SYSLOG_SOCKETS
+            .iter()
+            .find(|path| socket.connect(path).is_ok())
+            .context("no syslog daemon listening at /dev/log, /var/run/syslog or /var/run/log")?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            facility,
+            hostname: hostname().unwrap_or_else(|| "-".to_string()),
+            pid: std::process::id(),
+        })
+    }
+
+    fn header(&self, level: Level) -> String {
+        let severity = match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            _ => 7,
+        };
+        format!(
+            "<{}>1 {} {} {} {} - - ",
+            u32::from(self.facility.code()) * 8 + severity,
+            format_rfc3339(SystemTime::now()),
+            self.hostname,
+            APP_NAME,
+            self.pid
+        )
+    }
+
+    fn make_message(&self, level: Level) -> Message {
+        Message {
+            socket: self.socket.clone(),
+            buf: self.header(level).into_bytes(),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Message;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.make_message(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.make_message(*meta.level())
+    }
+}
+
+/// A record being formatted, sent as one datagram once dropped.
+pub(crate) struct Message {
+    socket: Arc<UnixDatagram>,
+    buf: Vec<u8>,
+}
+
+impl Write for Message {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message {
+    fn drop(&mut self) {
+        while self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+        }
+        // nowhere left to report a failure to
+        let _ = self.socket.send(&self.buf);
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
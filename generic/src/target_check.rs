@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Rule;
+use crate::relay;
+
+/// Periodic probes of the fixed target of a rule and its failover targets,
+/// connecting through a proxy like relays do.
+#[derive(Debug)]
+pub(crate) struct TargetCheck {
+    interval: Duration,
+    timeout: Duration,
+    /// Targets with whether they answered the last probe, all up until
+    /// probed.
+    targets: Vec<(String, AtomicBool)>,
+}
+
+impl TargetCheck {
+    pub(crate) fn new(targets: Vec<String>, interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            targets: targets
+                .into_iter()
+                .map(|target| (target, AtomicBool::new(true)))
+                .collect(),
+        }
+    }
+
+    /// Whether `target` failed its last probe, targets not checked are never
+    /// down.
+    pub(crate) fn is_down(&self, target: &str) -> bool {
+        self.targets
+            .iter()
+            .any(|(t, up)| t == target && !up.load(Ordering::Relaxed))
+    }
+}
+
+/// Probe the targets of the rule until it is replaced or removed.
+pub(crate) fn spawn(rule: &Arc<Rule>) {
+    let check = match rule.target_check.as_ref() {
+        Some(check) => check,
+        None => return,
+    };
+    let interval = check.interval;
+    let rule = Arc::downgrade(rule);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let rule = match rule.upgrade() {
+                Some(rule) => rule,
+                None => return,
+            };
+            let check = match rule.target_check.as_ref() {
+                Some(check) => check,
+                None => return,
+            };
+            for (target, up) in check.targets.iter() {
+                let res =
+                    match tokio::time::timeout(check.timeout, relay::probe_target(&rule, target))
+                        .await
+                    {
+                        Ok(res) => res,
+                        Err(_) => Err(anyhow::anyhow!("timed out")),
+                    };
+                let healthy = res.is_ok();
+                if up.swap(healthy, Ordering::Relaxed) != healthy {
+                    match res {
+                        Ok(()) => tracing::info!("Target {} is back", target),
+                        Err(e) => tracing::warn!(
+                            "Target {} failed health check, marked down: {:#}",
+                            target,
+                            e
+                        ),
+                    }
+                }
+            }
+        }
+    });
+}
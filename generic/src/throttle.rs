@@ -0,0 +1,187 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+/// Bytes moved at once at most, so relays sharing a bucket take turns.
+const MAX_CHUNK: usize = 16 * 1024;
+
+/// Token bucket of bytes, refilled at `rate` bytes per second up to one
+/// second worth of them.
+#[derive(Debug)]
+pub(crate) struct ByteBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+pub(crate) type SharedBucket = Arc<Mutex<ByteBucket>>;
+
+impl ByteBucket {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub(crate) fn shared(rate: u64) -> SharedBucket {
+        Arc::new(Mutex::new(Self::new(rate)))
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    /// Time until `n` bytes are available.
+    fn wait_for(&self, n: usize) -> Duration {
+        let missing = n as f64 - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+}
+
+/// Buckets shared by every relay, one for each direction.
+#[derive(Debug, Clone)]
+pub(crate) struct Bandwidth {
+    /// From clients to targets.
+    pub(crate) upload: SharedBucket,
+    pub(crate) download: SharedBucket,
+}
+
+impl Bandwidth {
+    pub(crate) fn new(rate: u64) -> Self {
+        Self {
+            upload: ByteBucket::shared(rate),
+            download: ByteBucket::shared(rate),
+        }
+    }
+}
+
+/// Bandwidth limit of one direction of a relay, made of every bucket the
+/// bytes are taken from.
+pub(crate) struct Throttle {
+    buckets: Vec<SharedBucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(buckets: Vec<SharedBucket>) -> Self {
+        Self {
+            buckets,
+            sleep: None,
+        }
+    }
+
+    /// Wait until bytes can be moved, returns how many of the `wanted`.
+    fn poll_allowance(&mut self, cx: &mut TaskContext<'_>, wanted: usize) -> Poll<usize> {
+        if self.buckets.is_empty() || wanted == 0 {
+            return Poll::Ready(wanted);
+        }
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.sleep = None;
+            }
+
+            let now = Instant::now();
+            let mut allowed = wanted.min(MAX_CHUNK);
+            let mut wait = Duration::ZERO;
+            for bucket in self.buckets.iter() {
+                let mut bucket = bucket.lock().unwrap();
+                bucket.refill(now);
+                // a slow bucket still hands out small chunks instead of stalling
+                let chunk = allowed.min(bucket.rate as usize).max(1);
+                wait = wait.max(bucket.wait_for(chunk));
+                allowed = allowed.min(bucket.tokens.max(0.0) as usize);
+            }
+            if wait.is_zero() && allowed > 0 {
+                return Poll::Ready(allowed);
+            }
+            self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        for bucket in self.buckets.iter() {
+            bucket.lock().unwrap().tokens -= n as f64;
+        }
+    }
+}
+
+/// A stream with reads and writes throttled separately.
+pub(crate) struct ThrottledStream<S> {
+    inner: S,
+    read: Throttle,
+    write: Throttle,
+}
+
+impl<S> ThrottledStream<S> {
+    pub(crate) fn new(inner: S, read: Throttle, write: Throttle) -> Self {
+        Self { inner, read, write }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let allowed = match this.read.poll_allowance(cx, buf.remaining()) {
+            Poll::Ready(allowed) => allowed,
+            Poll::Pending => return Poll::Pending,
+        };
+        let mut limited = buf.take(allowed);
+        let res = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let n = limited.filled().len();
+        // the bytes were read into the unfilled part of `buf`
+        unsafe {
+            buf.assume_init(n);
+        }
+        buf.advance(n);
+        this.read.consume(n);
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let allowed = match this.write.poll_allowance(cx, buf.len()) {
+            Poll::Ready(allowed) => allowed,
+            Poll::Pending => return Poll::Pending,
+        };
+        let res = Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]);
+        if let Poll::Ready(Ok(n)) = res {
+            this.write.consume(n);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
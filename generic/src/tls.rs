@@ -0,0 +1,167 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Debug};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use rustls_pemfile::Item;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{self, OwnedTrustAnchor, RootCertStore, ServerName};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+use crate::config::Route;
+
+/// TLS client side settings for one peer.
+#[derive(Clone)]
+pub(crate) struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName,
+}
+
+impl Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl TlsClient {
+    /// Peer certificate is verified against `ca` or the bundled webpki roots.
+    pub(crate) fn new(ca: Option<&Path>, server_name: &str) -> anyhow::Result<Self> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|_| anyhow::anyhow!("invalid tls server name {}", server_name))?;
+        let config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store(ca)?)
+            .with_no_client_auth();
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+
+    pub(crate) async fn connect<S>(&self, stream: S) -> io::Result<client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.connector
+            .connect(self.server_name.clone(), stream)
+            .await
+    }
+}
+
+/// TLS server side settings of a listener.
+#[derive(Clone)]
+pub(crate) struct TlsServer {
+    acceptor: TlsAcceptor,
+    /// Keyed by ALPN protocol, offered to clients in order.
+    alpn_routes: Vec<Route>,
+}
+
+impl Debug for TlsServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsServer")
+            .field("alpn_routes", &self.alpn_routes)
+            .finish()
+    }
+}
+
+impl TlsServer {
+    /// `cert` is the certificate chain(pem) presented to clients, `key` its
+    /// private key(pem, pkcs8 or rsa). With `client_ca`, clients must present
+    /// a certificate issued by it.
+    pub(crate) fn new(
+        cert: &Path,
+        key: &Path,
+        client_ca: Option<&Path>,
+        alpn_routes: Vec<Route>,
+    ) -> anyhow::Result<Self> {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(ca) => builder
+                .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(root_store(Some(ca))?)),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .with_context(|| format!("invalid tls certificate {}", cert.display()))?;
+        config.alpn_protocols = alpn_routes
+            .iter()
+            .map(|route| route.key.as_bytes().to_vec())
+            .collect();
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            alpn_routes,
+        })
+    }
+
+    /// Target routed to by the negotiated ALPN protocol.
+    pub(crate) fn alpn_target(&self, protocol: &[u8]) -> Option<&str> {
+        self.alpn_routes
+            .iter()
+            .find(|route| route.key.as_bytes() == protocol)
+            .map(|route| route.target.as_str())
+    }
+
+    pub(crate) async fn accept<S>(&self, stream: S) -> io::Result<server::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.acceptor.accept(stream).await
+    }
+}
+
+fn root_store(ca: Option<&Path>) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(path) => {
+            for cert in load_certs(path)? {
+                roots
+                    .add(&cert)
+                    .with_context(|| format!("invalid ca certificate in {}", path.display()))?;
+            }
+        }
+        None => {
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+        }
+    }
+    Ok(roots)
+}
+
+pub(crate) fn load_certs(path: &Path) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let file = File::open(path)
+        .with_context(|| format!("unable to open certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("unable to parse certificate file {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificate found in {}", path.display());
+    }
+    Ok(certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_key(path: &Path) -> anyhow::Result<rustls::PrivateKey> {
+    let file =
+        File::open(path).with_context(|| format!("unable to open key file {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        let item = rustls_pemfile::read_one(&mut reader)
+            .with_context(|| format!("unable to parse key file {}", path.display()))?;
+        match item {
+            Some(Item::PKCS8Key(key)) | Some(Item::RSAKey(key)) => {
+                return Ok(rustls::PrivateKey(key))
+            }
+            Some(_) => {}
+            None => anyhow::bail!("no private key found in {}", path.display()),
+        }
+    }
+}
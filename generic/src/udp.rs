@@ -0,0 +1,443 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tracing::Instrument;
+
+use crate::config::{ProxyConfig, Rule};
+use crate::dns::MAX_MESSAGE_SIZE;
+use crate::proxy::{
+    self, encode_address, resolve_target, socks5_auth, with_handshake_timeout, SOCKS5_ATYP_DOMAIN,
+    SOCKS5_ATYP_IPV4, SOCKS5_ATYP_IPV6, SOCKS5_VERSION,
+};
+use crate::proxy_protocol::ProxiedAddrs;
+use crate::relay::connect_target;
+use crate::resolver;
+use crate::utils::unmap_addr;
+
+const MAX_DATAGRAM_SIZE: usize = 65536;
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_SESSION_BUFFER: usize = 64;
+/// Queries of a dns session waiting for their answer, more are dropped.
+const MAX_PENDING_QUERIES: usize = 1024;
+/// Times a query is sent, again when the resolver closed the connection
+/// before answering.
+const DNS_QUERY_ATTEMPTS: u32 = 2;
+
+const SOCKS5_CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+/// Relay datagrams received on `socket`. Every client address gets its own
+/// session with a dedicated upstream socket, which is dropped after being
+/// idle for `UDP_SESSION_TIMEOUT`. Dns clients share a session by ip, since
+/// stub resolvers query from a new port every time.
+pub(crate) async fn serve_udp(
+    socket: UdpSocket,
+    mut rules: watch::Receiver<Arc<Rule>>,
+) -> anyhow::Result<()> {
+    let listen = rules.borrow().listen.clone();
+    let socket = Arc::new(socket);
+    let mut sessions: HashMap<SocketAddr, mpsc::Sender<(SocketAddr, Vec<u8>)>> = HashMap::new();
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (n, client) = match res {
+                    Ok(r) => r,
+                    Err(e) => {
+                        tracing::error!("Receiving udp datagram in failure: {}", e);
+                        continue;
+                    }
+                };
+                let mut datagram = buf[..n].to_vec();
+                let rule = rules.borrow().clone();
+                let key = if rule.dns { SocketAddr::new(client.ip(), 0) } else { client };
+                if let Some(tx) = sessions.get(&key) {
+                    match tx.try_send((client, datagram)) {
+                        Ok(()) => continue,
+                        Err(TrySendError::Full(_)) => {
+                            tracing::warn!("Udp session of {} is busy, datagram dropped", client);
+                            continue;
+                        }
+                        Err(TrySendError::Closed((_, d))) => datagram = d,
+                    }
+                }
+
+                // ipv4 clients of dual stack sockets, as acl and routes know them
+                let source = unmap_addr(client);
+                let ip = source.ip();
+                if !rule.acl.allows(ip, rule.acl.country(ip).as_deref()) {
+                    tracing::warn!("Udp client {} is not allowed, dropped", client);
+                    continue;
+                }
+                tracing::info!("Receive new udp session from {}", client);
+                sessions.retain(|_, tx| !tx.is_closed());
+                let (tx, rx) = mpsc::channel(UDP_SESSION_BUFFER);
+                let _ = tx.try_send((client, datagram));
+                sessions.insert(key, tx);
+
+                let inbound = socket.clone();
+                let span = tracing::info_span!("udp_session", listener = %listen, client = %client);
+                tokio::spawn(async move {
+                    if let Err(e) = udp_session(inbound, client, source, rule, rx).await {
+                        tracing::error!("Udp relay failed: {}", e);
+                    }
+                }.instrument(span));
+            },
+            res = rules.changed() => {
+                if res.is_err() {
+                    tracing::info!("Rule for {} removed, udp listener closed", listen);
+                    return Ok(());
+                }
+            },
+        }
+    }
+}
+
+async fn udp_session(
+    inbound: Arc<UdpSocket>,
+    client: SocketAddr,
+    source: SocketAddr,
+    rule: Arc<Rule>,
+    mut rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+) -> anyhow::Result<()> {
+    if rule.dns {
+        return dns_session(inbound, client, source, rule, rx).await;
+    }
+    let target = rule
+        .target
+        .fixed()
+        .context("udp forwarding needs a fixed target")?;
+    let lease = match rule.proxy_for(source.ip()) {
+        Some(pool) => Some(
+            pool.pick()
+                .context("no proxy available, paused by circuit breaker or none discovered")?,
+        ),
+        None => None,
+    };
+    // control is the tcp connection keeping the udp association alive
+    let (outbound, mut control, header) = match lease.as_ref() {
+        Some(lease) => {
+            let header = encode_header(&resolve_target(lease, target).await?)?;
+            let res = with_handshake_timeout(lease, udp_associate(lease)).await;
+            lease.connected(res.is_ok());
+            let (control, relay_addr) = res?;
+            let outbound = lease.outbound.connect_udp(relay_addr).await?;
+            (outbound, Some(control), Some(header))
+        }
+        None => {
+            let target_addr = resolver::lookup(target)
+                .await?
+                .into_iter()
+                .next()
+                .with_context(|| format!("unable to resolve {}", target))?;
+            (rule.outbound.connect_udp(target_addr).await?, None, None)
+        }
+    };
+
+    tracing::info!("Start udp relay");
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            datagram = rx.recv() => match datagram {
+                Some((_, datagram)) => match header.as_ref() {
+                    Some(header) => {
+                        let mut packet = Vec::with_capacity(header.len() + datagram.len());
+                        packet.extend_from_slice(header);
+                        packet.extend_from_slice(&datagram);
+                        outbound.send(&packet).await?;
+                    }
+                    None => {
+                        outbound.send(&datagram).await?;
+                    }
+                },
+                None => return Ok(()),
+            },
+            res = outbound.recv(&mut buf) => {
+                let n = res?;
+                let payload = match header {
+                    Some(_) => match strip_header(&buf[..n]) {
+                        Some(payload) => payload,
+                        None => continue,
+                    },
+                    None => &buf[..n],
+                };
+                inbound.send_to(payload, client).await?;
+            },
+            _ = wait_closed(control.as_mut()) => {
+                tracing::info!("Udp association closed by proxy");
+                return Ok(());
+            },
+            _ = tokio::time::sleep(UDP_SESSION_TIMEOUT) => {
+                tracing::info!("Udp relay of {} finished", client);
+                return Ok(());
+            },
+        }
+    }
+}
+
+/// A query relayed on the connection of a dns session, by the id it was
+/// given there.
+struct PendingQuery {
+    client: SocketAddr,
+    /// Id of the query as sent by the client.
+    id: u16,
+    message: Vec<u8>,
+    attempts: u32,
+}
+
+/// Relay the dns queries of the clients of one ip to the resolver over
+/// tcp(RFC 7766), pipelined on a connection they share. Queries are given
+/// ids of their own on it, so those of different clients do not collide.
+/// The connection is opened again when the resolver closes it with queries
+/// pending, which are sent again, and the session ends once idle.
+async fn dns_session(
+    inbound: Arc<UdpSocket>,
+    client: SocketAddr,
+    source: SocketAddr,
+    rule: Arc<Rule>,
+    mut rx: mpsc::Receiver<(SocketAddr, Vec<u8>)>,
+) -> anyhow::Result<()> {
+    let target = rule
+        .target
+        .fixed()
+        .context("dns forwarding needs a fixed target")?;
+    let addrs = ProxiedAddrs {
+        source,
+        destination: unmap_addr(inbound.local_addr()?),
+    };
+    let mut pending: HashMap<u16, PendingQuery> = HashMap::new();
+    let mut next_id = rand::random::<u16>();
+    let mut queued = rx.recv().await;
+    loop {
+        let (_lease, outbound) = connect_target(addrs, &rule, target).await?;
+        tracing::info!("Start dns relay to {}", target);
+        let (reader, mut writer) = tokio::io::split(outbound);
+        // left unanswered by the previous connection
+        pending.retain(|_, query| query.attempts < DNS_QUERY_ATTEMPTS);
+        for (id, query) in pending.iter_mut() {
+            query.attempts += 1;
+            write_message(&mut writer, *id, &query.message).await?;
+        }
+        if let Some((client, query)) = queued.take() {
+            send_query(&mut writer, &mut pending, &mut next_id, client, query).await?;
+        }
+
+        let (answers, mut received) = mpsc::channel(UDP_SESSION_BUFFER);
+        let reading = read_answers(reader, answers);
+        tokio::pin!(reading);
+        let res = loop {
+            tokio::select! {
+                query = rx.recv() => match query {
+                    Some((client, query)) => {
+                        send_query(&mut writer, &mut pending, &mut next_id, client, query).await?;
+                    }
+                    None => return Ok(()),
+                },
+                Some(answer) = received.recv() => {
+                    send_answer(&inbound, &mut pending, answer).await?;
+                }
+                res = &mut reading => break res,
+                _ = tokio::time::sleep(UDP_SESSION_TIMEOUT) => {
+                    tracing::info!("Dns relay of {} finished", client.ip());
+                    return Ok(());
+                }
+            }
+        };
+        // the answers read before the connection closed
+        while let Some(answer) = received.recv().await {
+            send_answer(&inbound, &mut pending, answer).await?;
+        }
+        res?;
+        tracing::info!(
+            "Dns connection closed by resolver with {} queries pending",
+            pending.len()
+        );
+        // resolvers close idle connections, the next query opens another
+        if pending.is_empty() {
+            queued = match tokio::time::timeout(UDP_SESSION_TIMEOUT, rx.recv()).await {
+                Ok(Some(query)) => Some(query),
+                _ => return Ok(()),
+            };
+        }
+    }
+}
+
+/// Send the query of `client` with an id of the session.
+async fn send_query<W>(
+    writer: &mut W,
+    pending: &mut HashMap<u16, PendingQuery>,
+    next_id: &mut u16,
+    client: SocketAddr,
+    message: Vec<u8>,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if message.len() < 12 || message.len() > MAX_MESSAGE_SIZE {
+        return Ok(());
+    }
+    if pending.len() >= MAX_PENDING_QUERIES {
+        tracing::warn!("Too many dns queries pending, query of {} dropped", client);
+        return Ok(());
+    }
+    let id = loop {
+        *next_id = next_id.wrapping_add(1);
+        if !pending.contains_key(next_id) {
+            break *next_id;
+        }
+    };
+    write_message(writer, id, &message).await?;
+    pending.insert(
+        id,
+        PendingQuery {
+            client,
+            id: u16::from_be_bytes([message[0], message[1]]),
+            message,
+            attempts: 1,
+        },
+    );
+    Ok(())
+}
+
+async fn write_message<W>(writer: &mut W, id: u16, message: &[u8]) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    // messages over tcp are prefixed with their length
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    framed.extend_from_slice(&id.to_be_bytes());
+    framed.extend_from_slice(&message[2..]);
+    writer.write_all(&framed).await?;
+    Ok(())
+}
+
+/// Answers of the resolver until it closes the connection.
+async fn read_answers<R>(mut reader: R, answers: mpsc::Sender<Vec<u8>>) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let len = match reader.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut answer = vec![0u8; len];
+        reader.read_exact(&mut answer).await?;
+        if answers.send(answer).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Send the answer back to the client of its query, with the id it asked.
+async fn send_answer(
+    inbound: &UdpSocket,
+    pending: &mut HashMap<u16, PendingQuery>,
+    mut answer: Vec<u8>,
+) -> anyhow::Result<()> {
+    if answer.len() < 12 {
+        return Ok(());
+    }
+    let id = u16::from_be_bytes([answer[0], answer[1]]);
+    // late answers of queries sent again are dropped
+    let query = match pending.remove(&id) {
+        Some(query) => query,
+        None => return Ok(()),
+    };
+    answer[..2].copy_from_slice(&query.id.to_be_bytes());
+    inbound.send_to(&answer, query.client).await?;
+    Ok(())
+}
+
+async fn wait_closed(control: Option<&mut TcpStream>) {
+    match control {
+        // the proxy is not supposed to send anything on it
+        Some(control) => {
+            let mut buf = [0u8; 1];
+            let _ = control.read(&mut buf).await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Run the UDP ASSOCIATE command, returns the control connection and the
+/// relay address datagrams should be sent to.
+async fn udp_associate(proxy: &ProxyConfig) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    let mut control = proxy::connect(proxy).await?;
+
+    socks5_auth(&mut control, proxy).await?;
+
+    // client address is left unspecified since it is not known before sending
+    control
+        .write_all(&[
+            SOCKS5_VERSION,
+            SOCKS5_CMD_UDP_ASSOCIATE,
+            0x00,
+            SOCKS5_ATYP_IPV4,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .await?;
+    let mut head = [0u8; 4];
+    control.read_exact(&mut head).await?;
+    if head[1] != 0x00 {
+        anyhow::bail!("udp associate rejected by proxy, reply code {}", head[1]);
+    }
+    let ip: IpAddr = match head[3] {
+        SOCKS5_ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).into()
+        }
+        SOCKS5_ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).into()
+        }
+        atyp => anyhow::bail!("unsupported relay address type {}", atyp),
+    };
+    let port = control.read_u16().await?;
+
+    // some proxies reply 0.0.0.0 meaning the address we are talking to
+    let ip = if ip.is_unspecified() {
+        control.peer_addr()?.ip()
+    } else {
+        ip
+    };
+    Ok((control, SocketAddr::new(ip, port)))
+}
+
+/// Header prepended to every datagram sent to the relay, see RFC 1928 section 7.
+fn encode_header(target: &str) -> anyhow::Result<Vec<u8>> {
+    // RSV and FRAG
+    let mut header = vec![0x00, 0x00, 0x00];
+    header.extend_from_slice(&encode_address(target)?);
+    Ok(header)
+}
+
+/// Strip the header of a datagram from the relay, fragments are dropped.
+fn strip_header(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 4 || packet[2] != 0x00 {
+        return None;
+    }
+    let addr_len = match packet[3] {
+        SOCKS5_ATYP_IPV4 => 4,
+        SOCKS5_ATYP_IPV6 => 16,
+        SOCKS5_ATYP_DOMAIN => 1 + *packet.get(4)? as usize,
+        _ => return None,
+    };
+    packet.get(4 + addr_len + 2..)
+}
@@ -0,0 +1,558 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+use crate::metrics::metrics;
+
+/// Addresses with this prefix are unix socket paths.
+pub(crate) const UNIX_PREFIX: &str = "unix:";
+
+pub(crate) trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> AsyncStream for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+pub(crate) type BoxedStream = Box<dyn AsyncStream>;
+
+/// A stream with bytes already read from it put back in front, so peeking at
+/// the start of a connection does not lose what is relayed afterwards.
+pub(crate) struct PrefixedStream<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    pub(crate) fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let n = (this.prefix.len() - this.pos).min(buf.remaining());
+            buf.put_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// When bytes last went through a stream, and how many.
+pub(crate) struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`.
+    last: AtomicU64,
+    /// Bytes read from the stream.
+    upload: AtomicU64,
+    /// Bytes written to the stream.
+    download: AtomicU64,
+    /// Bytes are also counted into this, like the quota usage of the client.
+    counter: Option<Arc<AtomicU64>>,
+    /// The side seen closing first, as a `Side` plus one.
+    closed_first: AtomicU8,
+}
+
+/// An end of a relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Target,
+}
+
+impl Side {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Side::Client => "client",
+            Side::Target => "target",
+        }
+    }
+}
+
+impl Activity {
+    pub(crate) fn new(counter: Option<Arc<AtomicU64>>) -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            upload: AtomicU64::new(0),
+            download: AtomicU64::new(0),
+            counter,
+            closed_first: AtomicU8::new(0),
+        }
+    }
+
+    fn closed(&self, side: Side) {
+        let _ = self.closed_first.compare_exchange(
+            0,
+            side as u8 + 1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The side which ended its stream first, if any did.
+    pub(crate) fn closed_first(&self) -> Option<Side> {
+        match self.closed_first.load(Ordering::Relaxed) {
+            1 => Some(Side::Client),
+            2 => Some(Side::Target),
+            _ => None,
+        }
+    }
+
+    fn read(&self, bytes: usize) {
+        self.touch(bytes);
+        self.upload.fetch_add(bytes as u64, Ordering::Relaxed);
+        metrics().uploaded(bytes);
+    }
+
+    fn written(&self, bytes: usize) {
+        self.touch(bytes);
+        self.download.fetch_add(bytes as u64, Ordering::Relaxed);
+        metrics().downloaded(bytes);
+    }
+
+    fn touch(&self, bytes: usize) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+        if let Some(counter) = self.counter.as_ref() {
+            counter.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Bytes read from and written to the stream so far.
+    pub(crate) fn bytes(&self) -> (u64, u64) {
+        (
+            self.upload.load(Ordering::Relaxed),
+            self.download.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Time since the stream started.
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    pub(crate) fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+/// Whether a read into `buf`, which had `filled` bytes before and was
+/// ready, hit the end of stream.
+fn read_eof(buf: &ReadBuf<'_>, filled: usize) -> bool {
+    buf.filled().len() == filled && buf.remaining() > 0
+}
+
+/// A stream of the client recording its reads and writes in an `Activity`.
+pub(crate) struct ActivityStream<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> ActivityStream<S> {
+    pub(crate) fn new(inner: S, activity: Arc<Activity>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ActivityStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.activity.read(buf.filled().len() - filled);
+        } else if matches!(res, Poll::Ready(Ok(()))) && read_eof(buf, filled) {
+            this.activity.closed(Side::Client);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ActivityStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            if n > 0 {
+                this.activity.written(n);
+            }
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// A stream of the target recording in an `Activity` when it ends, its bytes
+/// are already counted on the client side.
+pub(crate) struct TargetStream<S> {
+    inner: S,
+    activity: Arc<Activity>,
+}
+
+impl<S> TargetStream<S> {
+    pub(crate) fn new(inner: S, activity: Arc<Activity>) -> Self {
+        Self { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TargetStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if matches!(res, Poll::Ready(Ok(()))) && read_eof(buf, filled) {
+            this.activity.closed(Side::Target);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TargetStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Split `host:port`, brackets around an IPv6 host are removed.
+pub(crate) fn split_host_port(addr: &str) -> anyhow::Result<(&str, u16)> {
+    let (host, port) = addr
+        .rsplit_once(':')
+        .with_context(|| format!("invalid address {}", addr))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port in address {}", addr))?;
+    Ok((host, port))
+}
+
+/// Match `host` against `example.com`, or `*.example.com` for its subdomains,
+/// ignoring case.
+pub(crate) fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => {
+            host.len() > domain.len() + 1
+                && host.ends_with(domain)
+                && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+        }
+        None => host == pattern,
+    }
+}
+
+/// Format `time` like `2021-12-01T08:30:00.000Z`.
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Date of days since 1970-01-01, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = (if z >= 0 { z } else { z - 146_096 }) / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// An IPv4 client of a dual-stack listener shows up as `::ffff:a.b.c.d`, turn
+/// it back into the IPv4 address.
+pub(crate) fn unmap_addr(addr: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = addr {
+        if let [0, 0, 0, 0, 0, 0xffff, ..] = v6.ip().segments() {
+            if let Some(ip) = v6.ip().to_ipv4() {
+                return (ip, v6.port()).into();
+            }
+        }
+    }
+    addr
+}
+
+/// TCP keepalive probing, so relays idle behind NAT are noticed when dropped.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Keepalive {
+    /// Idle time before the first probe.
+    pub(crate) time: Duration,
+    pub(crate) interval: Duration,
+    /// Unanswered probes before giving up, the system default if unset.
+    pub(crate) retries: Option<u32>,
+}
+
+#[cfg(unix)]
+pub(crate) fn set_tcp_keepalive(stream: &TcpStream, keepalive: &Keepalive) -> io::Result<()> {
+    use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+    let socket = unsafe { socket2::Socket::from_raw_fd(stream.as_raw_fd()) };
+    let mut params = socket2::TcpKeepalive::new().with_time(keepalive.time);
+    #[cfg(any(
+        target_os = "freebsd",
+        target_os = "fuchsia",
+        target_os = "linux",
+        target_os = "netbsd",
+        target_vendor = "apple",
+    ))]
+    {
+        params = params.with_interval(keepalive.interval);
+    }
+    let mut res = socket.set_tcp_keepalive(&params);
+    #[cfg(target_os = "linux")]
+    if let (Ok(()), Some(retries)) = (&res, keepalive.retries) {
+        let retries = retries as libc::c_int;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPCNT,
+                &retries as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            res = Err(io::Error::last_os_error());
+        }
+    }
+    let _ = socket.into_raw_fd();
+    res
+}
+
+/// Mark packets sent on `socket` with a DSCP class for QoS.
+#[cfg(unix)]
+pub(crate) fn set_dscp(socket: socket2::SockRef<'_>, dscp: u8) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let set = |level, name| {
+        // the DSCP field is the upper 6 bits of TOS and traffic class
+        let tos = libc::c_int::from(dscp << 2);
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &tos as *const _ as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    };
+    match socket.local_addr()?.as_socket() {
+        Some(SocketAddr::V6(_)) => {
+            set(libc::IPPROTO_IPV6, libc::IPV6_TCLASS)?;
+            // IPv4 clients of dual-stack sockets still go by IP_TOS
+            let _ = set(libc::IPPROTO_IP, libc::IP_TOS);
+            Ok(())
+        }
+        _ => set(libc::IPPROTO_IP, libc::IP_TOS),
+    }
+}
+
+/// macOS refuses a soft limit of open files above OPEN_MAX, even when the
+/// hard limit is unlimited.
+#[cfg(target_os = "macos")]
+const MACOS_OPEN_MAX: libc::rlim_t = 10240;
+
+/// Raise the soft limit of open files to the hard one, returns the limit in
+/// effect.
+#[cfg(unix)]
+pub(crate) fn raise_fd_limit() -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[cfg(target_os = "macos")]
+    let target = limit.rlim_max.min(MACOS_OPEN_MAX);
+    #[cfg(not(target_os = "macos"))]
+    let target = limit.rlim_max;
+    if target > limit.rlim_cur {
+        let raised = libc::rlimit {
+            rlim_cur: target,
+            rlim_max: limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        limit.rlim_cur = target;
+    }
+    Ok(limit.rlim_cur as u64)
+}
+
+/// Whether the process or the system ran out of file descriptors.
+#[cfg(unix)]
+pub(crate) fn is_fd_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_fd_exhausted(_e: &io::Error) -> bool {
+    false
+}
+
+/// Destination of a connection redirected by iptables REDIRECT/DNAT.
+#[cfg(target_os = "linux")]
+pub(crate) fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = match unmap_addr(stream.local_addr()?) {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+    let addr = unsafe {
+        let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+        let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &mut storage as *mut _ as *mut libc::c_void,
+            &mut len,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        socket2::SockAddr::new(storage, len)
+    };
+    addr.as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "unexpected address family"))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn original_dst(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "original destination is only available on linux",
+    ))
+}
+
+/// Stdin and stdout of the process as one stream.
+pub(crate) struct StdioStream {
+    stdin: tokio::io::Stdin,
+    stdout: tokio::io::Stdout,
+}
+
+impl StdioStream {
+    pub(crate) fn new() -> Self {
+        Self {
+            stdin: tokio::io::stdin(),
+            stdout: tokio::io::stdout(),
+        }
+    }
+}
+
+impl AsyncRead for StdioStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdin).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for StdioStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stdout).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdout).poll_shutdown(cx)
+    }
+}